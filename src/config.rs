@@ -1,4 +1,4 @@
-use crate::{login, vfs};
+use crate::{login, metrics, vfs};
 use anyhow::{Context as _, Result};
 use libc::{gid_t, mode_t, uid_t};
 use serde::{de::Deserializer, Deserialize};
@@ -11,7 +11,9 @@ pub struct Config {
     pub permission: PermissionConfig,
     pub vfs: vfs::Config,
     pub relogin: login::ReloginConfig,
+    pub drive: login::DriveConfig,
     pub net: NetConfig,
+    pub metrics: metrics::Config,
 }
 
 #[derive(Debug, Deserialize)]
@@ -20,6 +22,26 @@ pub struct NetConfig {
     pub connect_timeout: Duration,
     #[serde(deserialize_with = "de_duration_sec")]
     pub request_timeout: Duration,
+    proxy: String,
+    user_agent: String,
+}
+
+impl NetConfig {
+    /// Client builder with common settings applied, shared by all HTTP clients.
+    pub fn client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::ClientBuilder::new()
+            .https_only(true)
+            .connect_timeout(self.connect_timeout);
+        if !self.proxy.is_empty() {
+            let proxy = reqwest::Proxy::all(&self.proxy)
+                .with_context(|| format!("Invalid net.proxy: {:?}", self.proxy))?;
+            builder = builder.proxy(proxy);
+        }
+        if !self.user_agent.is_empty() {
+            builder = builder.user_agent(&self.user_agent);
+        }
+        Ok(builder)
+    }
 }
 
 impl Config {
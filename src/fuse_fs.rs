@@ -1,9 +1,14 @@
 use crate::{config::PermissionConfig, vfs};
 use fuser::{
     FileAttr, FileType, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+use std::{
+    convert::TryFrom as _,
+    ffi::OsStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
-use std::{convert::TryFrom as _, ffi::OsStr, sync::Arc, time::SystemTime};
 
 const GENERATION: u64 = 0;
 const NAME_LEN: u32 = 2048;
@@ -12,19 +17,43 @@ const FRAGMENT_SIZE: u32 = 512;
 
 const READDIR_CHUNK_SIZE: usize = 64;
 
+/// Inode numbers of drives under the virtual root are prefixed by their indices in high bits,
+/// leaving 2^48 inode numbers for each drive.
+const DRIVE_INO_SHIFT: u32 = 48;
+
+/// Drives under the virtual root never change. Use `i64::MAX` to avoid overflowing `libc::time_t`.
+const ROOT_TTL: Duration = Duration::from_secs(i64::MAX as u64);
+
+/// Root inode number of the `idx`-th drive under the virtual root.
+pub fn drive_root_ino(idx: usize) -> u64 {
+    (idx as u64 + 1) << DRIVE_INO_SHIFT
+}
+
 pub struct Filesystem {
     inner: Arc<FilesystemInner>,
 }
 
 struct FilesystemInner {
-    vfs: Arc<vfs::Vfs>,
+    drives: Vec<(String, Arc<vfs::Vfs>)>,
+    /// Whether drives are subdirectories of a virtual root, or the only drive is the root.
+    virtual_root: bool,
     perm_config: PermissionConfig,
+    mount_time: SystemTime,
 }
 
 impl Filesystem {
-    pub fn new(vfs: Arc<vfs::Vfs>, perm_config: PermissionConfig) -> Self {
+    /// Serve drives with their names. A single drive without name is served as the root,
+    /// otherwise they're subdirectories of a virtual root, with root inodes from `drive_root_ino`.
+    pub fn new(drives: Vec<(String, Arc<vfs::Vfs>)>, perm_config: PermissionConfig) -> Self {
+        assert!(!drives.is_empty());
+        let virtual_root = !(drives.len() == 1 && drives[0].0.is_empty());
         Self {
-            inner: Arc::new(FilesystemInner { vfs, perm_config }),
+            inner: Arc::new(FilesystemInner {
+                drives,
+                virtual_root,
+                perm_config,
+                mount_time: SystemTime::now(),
+            }),
         }
     }
 
@@ -39,6 +68,90 @@ impl Filesystem {
 }
 
 impl FilesystemInner {
+    fn is_virtual_root(&self, ino: u64) -> bool {
+        self.virtual_root && ino == fuser::FUSE_ROOT_ID
+    }
+
+    /// The drive serving an inode. Operations on the virtual root itself are not permitted.
+    fn vfs(&self, ino: u64) -> Result<&vfs::Vfs, libc::c_int> {
+        if !self.virtual_root {
+            return Ok(&self.drives[0].1);
+        }
+        if ino == fuser::FUSE_ROOT_ID {
+            return Err(libc::EPERM);
+        }
+        (ino >> DRIVE_INO_SHIFT)
+            .checked_sub(1)
+            .and_then(|idx| self.drives.get(idx as usize))
+            .map(|(_, vfs)| &**vfs)
+            .ok_or_else(|| vfs::Error::InvalidInode(ino).into_c_err())
+    }
+
+    fn root_attr(&self) -> vfs::InodeAttr {
+        vfs::InodeAttr {
+            size: 0,
+            mtime: self.mount_time,
+            crtime: self.mount_time,
+            is_directory: true,
+            c_tag: None,
+            dirty: false,
+            meta: Default::default(),
+        }
+    }
+
+    async fn statfs(&self, ino: u64) -> Result<vfs::StatfsData, libc::c_int> {
+        if !self.is_virtual_root(ino) {
+            return self
+                .vfs(ino)?
+                .statfs()
+                .await
+                .map_err(|err| err.into_c_err());
+        }
+        let mut sum = vfs::StatfsData { total: 0, free: 0 };
+        for (_, vfs) in &self.drives {
+            let data = vfs.statfs().await.map_err(|err| err.into_c_err())?;
+            sum.total += data.total;
+            sum.free += data.free;
+        }
+        Ok(sum)
+    }
+
+    async fn lookup(
+        &self,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<(u64, vfs::InodeAttr, Duration), libc::c_int> {
+        if !self.is_virtual_root(parent) {
+            return self
+                .vfs(parent)?
+                .lookup(parent, name)
+                .await
+                .map_err(|err| err.into_c_err());
+        }
+        let idx = self
+            .drives
+            .iter()
+            .position(|(drive_name, _)| name == drive_name.as_str())
+            .ok_or(libc::ENOENT)?;
+        let ino = drive_root_ino(idx);
+        let (attr, ttl) = self.drives[idx]
+            .1
+            .get_attr(ino)
+            .await
+            .map_err(|err| err.into_c_err())?;
+        Ok((ino, attr, ttl))
+    }
+
+    async fn get_xattrs(&self, ino: u64) -> Result<Vec<(&'static str, String)>, libc::c_int> {
+        if self.is_virtual_root(ino) {
+            return Ok(Vec::new());
+        }
+        self.vfs(ino)?
+            .get_xattrs(ino)
+            .await
+            .map_err(|err| err.into_c_err())
+    }
+
     fn cvt_attr(&self, ino: u64, attr: vfs::InodeAttr) -> FileAttr {
         FileAttr {
             ino,
@@ -83,10 +196,10 @@ impl fuser::Filesystem for Filesystem {
         log::info!("FUSE destroyed");
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
         self.spawn(|inner| async move {
-            match inner.vfs.statfs().await {
-                Err(err) => reply.error(err.into_c_err()),
+            match inner.statfs(ino).await {
+                Err(err) => reply.error(err),
                 Ok(vfs::StatfsData { total, free }) => reply.statfs(
                     to_blocks_ceil(total),
                     to_blocks_floor(free),
@@ -104,8 +217,8 @@ impl fuser::Filesystem for Filesystem {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let name = name.to_owned();
         self.spawn(|inner| async move {
-            match inner.vfs.lookup(parent, &name).await {
-                Err(err) => reply.error(err.into_c_err()),
+            match inner.lookup(parent, &name).await {
+                Err(err) => reply.error(err),
                 Ok((ino, attr, ttl)) => {
                     let attr = inner.cvt_attr(ino, attr);
                     reply.entry(&ttl, &attr, GENERATION);
@@ -115,14 +228,27 @@ impl fuser::Filesystem for Filesystem {
     }
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        // The virtual root and roots of drives under it are never freed.
+        let is_drive_root = ino & ((1 << DRIVE_INO_SHIFT) - 1) == 0;
+        if self.inner.virtual_root && (ino == fuser::FUSE_ROOT_ID || is_drive_root) {
+            return;
+        }
         self.spawn(|inner| async move {
-            inner.vfs.forget(ino, nlookup).await.unwrap();
+            inner.vfs(ino).unwrap().forget(ino, nlookup).await.unwrap();
         });
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         self.spawn(|inner| async move {
-            match inner.vfs.get_attr(ino).await {
+            if inner.is_virtual_root(ino) {
+                let attr = inner.cvt_attr(ino, inner.root_attr());
+                return reply.attr(&ROOT_TTL, &attr);
+            }
+            let vfs = match inner.vfs(ino) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.get_attr(ino).await {
                 Err(err) => reply.error(err.into_c_err()),
                 Ok((attr, ttl)) => {
                     let attr = inner.cvt_attr(ino, attr);
@@ -139,7 +265,14 @@ impl fuser::Filesystem for Filesystem {
     fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
         // FIXME: Check flags?
         self.spawn(|inner| async move {
-            match inner.vfs.open_dir(ino).await {
+            if inner.is_virtual_root(ino) {
+                return reply.opened(0, 0);
+            }
+            let vfs = match inner.vfs(ino) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.open_dir(ino).await {
                 Err(err) => reply.error(err.into_c_err()),
                 Ok(fh) => reply.opened(fh, 0),
             }
@@ -148,7 +281,9 @@ impl fuser::Filesystem for Filesystem {
 
     fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
         self.spawn(|inner| async move {
-            inner.vfs.close_dir(ino, fh).await.unwrap();
+            if !inner.is_virtual_root(ino) {
+                inner.vfs(ino).unwrap().close_dir(ino, fh).await.unwrap();
+            }
             reply.ok();
         });
     }
@@ -163,14 +298,32 @@ impl fuser::Filesystem for Filesystem {
     ) {
         let offset = u64::try_from(offset).unwrap();
         self.spawn(|inner| async move {
-            match inner
-                .vfs
-                .read_dir(ino, fh, offset, READDIR_CHUNK_SIZE)
-                .await
-            {
-                Err(err) => reply.error(err.into_c_err()),
+            let ret = if inner.is_virtual_root(ino) {
+                let entries = inner
+                    .drives
+                    .iter()
+                    .skip(offset as usize)
+                    .take(READDIR_CHUNK_SIZE)
+                    .map(|(name, _)| vfs::DirEntry {
+                        name: name.clone(),
+                        attr: inner.root_attr(),
+                    })
+                    .collect::<Vec<_>>();
+                Ok(entries)
+            } else {
+                match inner.vfs(ino) {
+                    Err(err) => Err(err),
+                    Ok(vfs) => vfs
+                        .read_dir(ino, fh, offset, READDIR_CHUNK_SIZE)
+                        .await
+                        .map(|entries| entries.as_ref().to_vec())
+                        .map_err(|err| err.into_c_err()),
+                }
+            };
+            match ret {
+                Err(err) => reply.error(err),
                 Ok(entries) => {
-                    for (idx, entry) in entries.as_ref().iter().enumerate() {
+                    for (idx, entry) in entries.iter().enumerate() {
                         let next_offset = offset
                             .checked_add(u64::try_from(idx).unwrap())
                             .unwrap()
@@ -198,12 +351,16 @@ impl fuser::Filesystem for Filesystem {
         static_assertions::const_assert_eq!(libc::O_RDONLY, 0);
         log::trace!("open flags: {:#x}", flags);
 
-        let write = (flags & libc::O_WRONLY) != 0;
+        let write = (flags & libc::O_ACCMODE) != libc::O_RDONLY;
         assert_eq!(flags & libc::O_TRUNC, 0);
         let ret_flags = flags & libc::O_WRONLY;
 
         self.spawn(|inner| async move {
-            match inner.vfs.open_file(ino, write).await {
+            let vfs = match inner.vfs(ino) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.open_file(ino, write).await {
                 Ok(fh) => reply.opened(fh, ret_flags as u32),
                 Err(err) => reply.error(err.into_c_err()),
             }
@@ -229,8 +386,11 @@ impl fuser::Filesystem for Filesystem {
 
         let name = name.to_owned();
         self.spawn(|inner| async move {
-            match inner
-                .vfs
+            let vfs = match inner.vfs(parent) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs
                 .open_create_file(parent, &name, truncate, exclusive)
                 .await
             {
@@ -254,7 +414,11 @@ impl fuser::Filesystem for Filesystem {
         reply: ReplyEmpty,
     ) {
         self.spawn(|inner| async move {
-            match inner.vfs.close_file(ino, fh).await {
+            let vfs = match inner.vfs(ino) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.close_file(ino, fh).await {
                 Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
             }
@@ -275,7 +439,11 @@ impl fuser::Filesystem for Filesystem {
         let offset = u64::try_from(offset).unwrap();
         let size = usize::try_from(size).unwrap();
         self.spawn(|inner| async move {
-            match inner.vfs.read_file(ino, fh, offset, size).await {
+            let vfs = match inner.vfs(ino) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.read_file(ino, fh, offset, size).await {
                 Ok(data) => {
                     let data = data.as_ref();
                     reply.data(data);
@@ -296,7 +464,11 @@ impl fuser::Filesystem for Filesystem {
     ) {
         let name = name.to_owned();
         self.spawn(|inner| async move {
-            match inner.vfs.create_dir(parent, &name).await {
+            let vfs = match inner.vfs(parent) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.create_dir(parent, &name).await {
                 Ok((ino, attr, ttl)) => {
                     let attr = inner.cvt_attr(ino, attr);
                     reply.entry(&ttl, &attr, GENERATION)
@@ -320,7 +492,14 @@ impl fuser::Filesystem for Filesystem {
         let name = name.to_owned();
         let newname = newname.to_owned();
         self.spawn(|inner| async move {
-            match inner.vfs.rename(parent, &name, newparent, &newname).await {
+            let (vfs, new_vfs) = match (inner.vfs(parent), inner.vfs(newparent)) {
+                (Ok(vfs), Ok(new_vfs)) => (vfs, new_vfs),
+                (Err(err), _) | (_, Err(err)) => return reply.error(err),
+            };
+            if !std::ptr::eq(vfs, new_vfs) {
+                return reply.error(libc::EXDEV);
+            }
+            match vfs.rename(parent, &name, newparent, &newname).await {
                 Ok(_) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
             }
@@ -330,7 +509,11 @@ impl fuser::Filesystem for Filesystem {
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = name.to_owned();
         self.spawn(|inner| async move {
-            match inner.vfs.remove_dir(parent, &name).await {
+            let vfs = match inner.vfs(parent) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.remove_dir(parent, &name).await {
                 Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
             }
@@ -340,7 +523,11 @@ impl fuser::Filesystem for Filesystem {
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = name.to_owned();
         self.spawn(|inner| async move {
-            match inner.vfs.remove_file(parent, &name).await {
+            let vfs = match inner.vfs(parent) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.remove_file(parent, &name).await {
                 Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
             }
@@ -361,7 +548,11 @@ impl fuser::Filesystem for Filesystem {
     ) {
         let data = data.to_owned();
         self.spawn(|inner| async move {
-            match inner.vfs.write_file(ino, fh, offset as u64, &data).await {
+            let vfs = match inner.vfs(ino) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.write_file(ino, fh, offset as u64, &data).await {
                 // > Write should return exactly the number of bytes requested except on error.
                 Ok(()) => reply.written(data.len() as u32),
                 Err(err) => reply.error(err.into_c_err()),
@@ -380,7 +571,7 @@ impl fuser::Filesystem for Filesystem {
         _atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
+        fh: Option<u64>,
         _crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
@@ -392,7 +583,11 @@ impl fuser::Filesystem for Filesystem {
                 TimeOrNow::SpecificTime(time) => time,
                 TimeOrNow::Now => SystemTime::now(),
             });
-            match inner.vfs.set_attr(ino, size, mtime).await {
+            let vfs = match inner.vfs(ino) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.set_attr(ino, fh, size, mtime).await {
                 Ok((attr, ttl)) => {
                     let attr = inner.cvt_attr(ino, attr);
                     reply.attr(&ttl, &attr)
@@ -414,18 +609,61 @@ impl fuser::Filesystem for Filesystem {
         reply.ok();
     }
 
-    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
         self.spawn(|inner| async move {
-            match inner.vfs.sync_file(ino).await {
+            let vfs = match inner.vfs(ino) {
+                Ok(vfs) => vfs,
+                Err(err) => return reply.error(err),
+            };
+            match vfs.sync_file(ino, fh).await {
                 Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
             }
         });
     }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = name.to_owned();
+        self.spawn(|inner| async move {
+            match inner.get_xattrs(ino).await {
+                Err(err) => reply.error(err),
+                Ok(xattrs) => match xattrs.into_iter().find(|(key, _)| name == *key) {
+                    Some((_, value)) => reply_xattr(reply, size, value.as_bytes()),
+                    None => reply.error(libc::ENODATA),
+                },
+            }
+        });
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.spawn(|inner| async move {
+            match inner.get_xattrs(ino).await {
+                Err(err) => reply.error(err),
+                Ok(xattrs) => {
+                    let names = xattrs
+                        .iter()
+                        .flat_map(|(key, _)| key.bytes().chain([0]))
+                        .collect::<Vec<u8>>();
+                    reply_xattr(reply, size, &names);
+                }
+            }
+        });
+    }
+}
+
+/// Reply the length if `size` is 0, or the data if it fits in `size`.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
 }
 
 fn to_blocks_ceil(bytes: u64) -> u64 {
-    (bytes + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64
+    bytes.div_ceil(BLOCK_SIZE as u64)
 }
 
 fn to_blocks_floor(bytes: u64) -> u64 {
@@ -1,6 +1,6 @@
 use crate::config::de_duration_sec;
 use anyhow::{ensure, Context as _, Result};
-use onedrive_api::{Auth, DriveLocation, OneDrive, Permission};
+use onedrive_api::{resource::DriveId, Auth, DriveLocation, OneDrive, Permission};
 use serde::{Deserialize, Serialize};
 use std::{
     fs,
@@ -10,7 +10,7 @@ use std::{
 };
 use tokio::{
     self,
-    sync::{RwLock, RwLockReadGuard},
+    sync::{watch, Notify, RwLock, RwLockReadGuard},
 };
 
 #[derive(Debug, Deserialize)]
@@ -22,11 +22,52 @@ pub struct ReloginConfig {
     time_before_expire: Duration,
     #[serde(deserialize_with = "de_duration_sec")]
     min_live_time: Duration,
+    #[serde(deserialize_with = "de_duration_sec")]
+    expired_wait_time: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DriveConfig {
+    id: String,
+    pub mounts: Vec<DriveMount>,
+}
+
+/// A drive mounted as a subdirectory of the root.
+#[derive(Debug, Deserialize)]
+pub struct DriveMount {
+    pub name: String,
+    #[serde(default)]
+    pub id: String,
+    /// Empty for the credential file of the mount command.
+    #[serde(default)]
+    pub credential: String,
+    /// Options overriding the global ones for this drive only.
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+impl DriveConfig {
+    fn location(&self) -> DriveLocation {
+        if self.id.is_empty() {
+            DriveLocation::me()
+        } else {
+            DriveLocation::from_id(DriveId(self.id.clone()))
+        }
+    }
 }
 
+#[derive(Debug, thiserror::Error)]
+#[error("Access token expired")]
+pub struct TokenExpired;
+
 #[derive(Clone)]
 pub struct ManagedOnedrive {
     onedrive: Arc<RwLock<OneDrive>>,
+    /// The expiration time of the current access token.
+    expire_rx: watch::Receiver<SystemTime>,
+    /// Wake up the relogin thread to relogin immediately.
+    relogin_notify: Arc<Notify>,
+    expired_wait_time: Duration,
 }
 
 impl ManagedOnedrive {
@@ -34,6 +75,7 @@ impl ManagedOnedrive {
         client: reqwest::Client,
         credential_file: PathBuf,
         config: ReloginConfig,
+        drive: DriveConfig,
         mount_readonly: bool,
     ) -> Result<Self> {
         log::info!("Logining...");
@@ -48,6 +90,7 @@ impl ManagedOnedrive {
             cred.client_id.clone(),
             Permission::new_read()
                 .write(!cred.readonly)
+                .access_shared(cred.access_shared)
                 .offline_access(true),
             cred.redirect_uri.clone(),
         );
@@ -63,31 +106,61 @@ impl ManagedOnedrive {
         cred.save(&credential_file)?;
         log::info!("New credential saved");
 
+        let drive = drive.location();
         let onedrive = Arc::new(RwLock::new(OneDrive::new_with_client(
             client,
             resp.access_token,
-            DriveLocation::me(),
+            drive.clone(),
         )));
+        let initial_expire_time = Duration::from_secs(resp.expires_in_secs);
+        let (expire_tx, expire_rx) = watch::channel(SystemTime::now() + initial_expire_time);
+        let relogin_notify = Arc::new(Notify::new());
+        let expired_wait_time = config.expired_wait_time;
 
         if config.enable {
             tokio::spawn(Self::relogin_thread(
                 Arc::downgrade(&onedrive),
+                expire_tx,
+                relogin_notify.clone(),
                 auth,
                 cred,
                 credential_file,
-                Duration::from_secs(resp.expires_in_secs),
+                drive,
+                initial_expire_time,
                 config,
             ));
         }
 
-        Ok(Self { onedrive })
+        Ok(Self {
+            onedrive,
+            expire_rx,
+            relogin_notify,
+            expired_wait_time,
+        })
+    }
+
+    /// An unexpiring session without relogin, for tests against a mock server.
+    #[cfg(test)]
+    pub fn new_for_test(client: reqwest::Client) -> Self {
+        let onedrive = OneDrive::new_with_client(client, "token".to_owned(), DriveLocation::me());
+        let (_, expire_rx) = watch::channel(SystemTime::now() + Duration::from_secs(86400));
+        Self {
+            onedrive: Arc::new(RwLock::new(onedrive)),
+            expire_rx,
+            relogin_notify: Arc::new(Notify::new()),
+            expired_wait_time: Duration::ZERO,
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn relogin_thread(
         weak: Weak<RwLock<OneDrive>>,
+        expire_tx: watch::Sender<SystemTime>,
+        relogin_notify: Arc<Notify>,
         auth: Auth,
         mut cred: Credential,
         credential_file: PathBuf,
+        drive: DriveLocation,
         initial_expire_time: Duration,
         config: ReloginConfig,
     ) {
@@ -102,9 +175,14 @@ impl ManagedOnedrive {
         );
 
         loop {
-            tokio::time::sleep(config.check_period).await;
-            if SystemTime::now() < relogin_inst {
-                continue;
+            tokio::select! {
+                _ = tokio::time::sleep(config.check_period) => {
+                    if SystemTime::now() < relogin_inst {
+                        continue;
+                    }
+                }
+                // Someone found the token expired. Relogin immediately.
+                _ = relogin_notify.notified() => {}
             }
 
             let onedrive = match weak.upgrade() {
@@ -129,7 +207,8 @@ impl ManagedOnedrive {
                 login_time + config.min_live_time,
             );
 
-            *onedrive.write().await = OneDrive::new(resp.access_token, DriveLocation::me());
+            *onedrive.write().await = OneDrive::new(resp.access_token, drive.clone());
+            let _ = expire_tx.send(login_time + Duration::from_secs(resp.expires_in_secs));
 
             log::info!(
                 "Relogined. Next relogin will happen after {}",
@@ -147,14 +226,52 @@ impl ManagedOnedrive {
         }
     }
 
-    pub async fn get(&self) -> RwLockReadGuard<'_, OneDrive> {
-        self.onedrive.read().await
+    /// Get the client with a valid access token.
+    ///
+    /// If the token is already expired (eg. the previous relogin failed), it triggers
+    /// a relogin and waits for at most `expired_wait_time` before failing.
+    pub async fn get(&self) -> Result<RwLockReadGuard<'_, OneDrive>, TokenExpired> {
+        let is_valid = |expire_time: &SystemTime| SystemTime::now() < *expire_time;
+        let mut expire_rx = self.expire_rx.clone();
+        if !is_valid(&expire_rx.borrow()) {
+            log::warn!("Access token expired, trying to relogin");
+            self.relogin_notify.notify_one();
+            let _ = tokio::time::timeout(self.expired_wait_time, async {
+                while expire_rx.changed().await.is_ok() && !is_valid(&expire_rx.borrow()) {}
+            })
+            .await;
+            if !is_valid(&expire_rx.borrow()) {
+                log::error!("Access token expired and relogin did not succeed in time");
+                return Err(TokenExpired);
+            }
+        }
+        Ok(self.onedrive.read().await)
+    }
+
+    /// Report that the access token is rejected before its expiration time, eg. revoked.
+    ///
+    /// It triggers a relogin like an expired token, and waits for at most `expired_wait_time`
+    /// for the new token. Relogins are done by a single thread, so concurrent reporters share it.
+    pub async fn report_rejected(&self) -> Result<(), TokenExpired> {
+        let mut expire_rx = self.expire_rx.clone();
+        expire_rx.borrow_and_update();
+        log::warn!("Access token rejected, trying to relogin");
+        self.relogin_notify.notify_one();
+        match tokio::time::timeout(self.expired_wait_time, expire_rx.changed()).await {
+            Ok(Ok(())) => Ok(()),
+            _ => {
+                log::error!("Access token rejected and relogin did not succeed in time");
+                Err(TokenExpired)
+            }
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Credential {
     pub readonly: bool,
+    #[serde(default)]
+    pub access_shared: bool,
     pub client_id: String,
     pub redirect_uri: String,
     pub refresh_token: String,
@@ -172,7 +289,7 @@ impl Credential {
         let parent = path.parent().context("Invalid credential path")?;
         fs::create_dir_all(parent)?;
 
-        let tmp_path = if path.extension().map_or(false, |ext| ext == "tmp") {
+        let tmp_path = if path.extension().is_some_and(|ext| ext == "tmp") {
             path.with_extension("_tmp")
         } else {
             path.with_extension("tmp")
@@ -1,13 +1,18 @@
-use crate::login::ManagedOnedrive;
-use anyhow::{Context as _, Result};
+use crate::login::{DriveMount, ManagedOnedrive};
+use anyhow::{ensure, Context as _, Result};
 use clap::{Args, Parser};
 use fuser::MountOption;
-use onedrive_api::{Auth, Permission};
-use std::{io, path::PathBuf};
+use onedrive_api::{Auth, FileName, Permission};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 mod config;
 mod fuse_fs;
 mod login;
+mod metrics;
 mod paths;
 mod vfs;
 
@@ -41,6 +46,7 @@ async fn main_login(opt: OptLogin) -> Result<()> {
         opt.client_id.clone(),
         Permission::new_read()
             .write(opt.read_write)
+            .access_shared(opt.access_shared)
             .offline_access(true),
         REDIRECT_URI.to_owned(),
     );
@@ -58,6 +64,7 @@ async fn main_login(opt: OptLogin) -> Result<()> {
 
     login::Credential {
         readonly: !opt.read_write,
+        access_shared: opt.access_shared,
         client_id: opt.client_id,
         redirect_uri: REDIRECT_URI.to_owned(),
         refresh_token,
@@ -106,29 +113,46 @@ async fn main_mount(opt: OptMount) -> Result<()> {
     let config = config::Config::merge_from_default(opt.config.as_deref(), &opt.option)?;
     let readonly = config.permission.readonly;
 
-    let client = reqwest::ClientBuilder::new()
+    let client = config
+        .net
+        .client_builder()?
         .redirect(reqwest::redirect::Policy::none())
         .gzip(true)
-        .https_only(true)
-        .connect_timeout(config.net.connect_timeout)
         .timeout(config.net.request_timeout)
         .build()?;
-    let unlimit_client = reqwest::ClientBuilder::new()
-        .https_only(true)
-        .connect_timeout(config.net.connect_timeout)
-        .build()?;
-
-    let onedrive =
-        ManagedOnedrive::login(client, credential_path, config.relogin, readonly).await?;
-    let vfs = vfs::Vfs::new(
-        fuser::FUSE_ROOT_ID,
-        readonly,
-        config.vfs,
-        onedrive.clone(),
-        unlimit_client,
-    )
-    .await
-    .context("Failed to initialize vfs")?;
+    let unlimit_client = config.net.client_builder()?.build()?;
+
+    let drives = if config.drive.mounts.is_empty() {
+        let onedrive = ManagedOnedrive::login(
+            client,
+            credential_path,
+            config.relogin,
+            config.drive,
+            readonly,
+        )
+        .await?;
+        let vfs = vfs::Vfs::new(
+            fuser::FUSE_ROOT_ID,
+            readonly,
+            config.vfs,
+            onedrive,
+            unlimit_client,
+        )
+        .await
+        .context("Failed to initialize vfs")?;
+        vec![(String::new(), vfs)]
+    } else {
+        mount_drives(
+            opt.config.as_deref(),
+            &opt.option,
+            &config.drive.mounts,
+            &credential_path,
+            client,
+            unlimit_client,
+        )
+        .await?
+    };
+    metrics::spawn(config.metrics, drives.clone())?;
 
     log::info!("Mounting...");
     let fuse_options = [
@@ -148,10 +172,82 @@ async fn main_mount(opt: OptMount) -> Result<()> {
             MountOption::RW
         },
     ];
-    let fs = fuse_fs::Filesystem::new(vfs, config.permission);
-    tokio::task::spawn_blocking(move || fuser::mount2(fs, &opt.mount_point, &fuse_options))
-        .await??;
-    Ok(())
+    let fs = fuse_fs::Filesystem::new(drives.clone(), config.permission);
+    let ret =
+        tokio::task::spawn_blocking(move || fuser::mount2(fs, &opt.mount_point, &fuse_options))
+            .await?;
+    log::info!("Unmounted");
+    for (_, vfs) in &drives {
+        vfs.flush_all().await;
+    }
+    Ok(ret?)
+}
+
+/// Login and initialize drives of `drive.mounts`, each with its own settings and inode numbers.
+async fn mount_drives(
+    config_path: Option<&Path>,
+    options: &[String],
+    mounts: &[DriveMount],
+    credential_path: &Path,
+    client: reqwest::Client,
+    unlimit_client: reqwest::Client,
+) -> Result<Vec<(String, Arc<vfs::Vfs>)>> {
+    let mut drives: Vec<(String, Arc<vfs::Vfs>)> = Vec::with_capacity(mounts.len());
+    for (idx, mount) in mounts.iter().enumerate() {
+        ensure!(
+            FileName::new(&mount.name).is_some() && !mount.name.is_empty(),
+            "Invalid name of drive.mounts: {:?}",
+            mount.name,
+        );
+        ensure!(
+            drives.iter().all(|(name, _)| *name != mount.name),
+            "Duplicated name of drive.mounts: {:?}",
+            mount.name,
+        );
+
+        // Settings of the drive take precedence over global ones.
+        let options = options
+            .iter()
+            .cloned()
+            .chain([format!("drive.id={:?}", mount.id)])
+            .chain(mount.options.iter().cloned())
+            .collect::<Vec<_>>();
+        let config = config::Config::merge_from_default(config_path, &options)
+            .with_context(|| format!("Invalid options of drive {:?}", mount.name))?;
+        let readonly = config.permission.readonly;
+        let credential_path = if mount.credential.is_empty() {
+            credential_path.to_owned()
+        } else {
+            PathBuf::from(&mount.credential)
+        };
+
+        log::info!("Mounting drive {:?}", mount.name);
+        let onedrive = ManagedOnedrive::login(
+            client.clone(),
+            credential_path,
+            config.relogin,
+            config.drive,
+            readonly,
+        )
+        .await
+        .with_context(|| format!("Failed to login for drive {:?}", mount.name))?;
+        // Drives mounted twice would share caches.
+        let drive_id = vfs::fetch_drive_id(&onedrive).await?;
+        if let Some((name, _)) = drives.iter().find(|(_, vfs)| *vfs.drive_id() == drive_id) {
+            anyhow::bail!("Drive {:?} is the same as {:?}", mount.name, name);
+        }
+        let vfs = vfs::Vfs::new(
+            fuse_fs::drive_root_ino(idx),
+            readonly,
+            config.vfs,
+            onedrive,
+            unlimit_client.clone(),
+        )
+        .await
+        .with_context(|| format!("Failed to initialize vfs for drive {:?}", mount.name))?;
+        drives.push((mount.name.clone(), vfs));
+    }
+    Ok(drives)
 }
 
 #[derive(Debug, Parser)]
@@ -191,6 +287,11 @@ struct OptLogin {
     #[clap(short = 'w', long)]
     read_write: bool,
 
+    /// Also request for the permission to files shared with you and drives other than your own,
+    /// eg. SharePoint document libraries.
+    #[clap(long)]
+    access_shared: bool,
+
     /// The login code for Code-Auth.
     /// If not provided, the program will interactively open your browser and
     /// ask for the redirected URL containing it.
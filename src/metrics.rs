@@ -0,0 +1,440 @@
+//! Prometheus exporter of transfer and cache metrics, available with the `metrics` feature.
+use crate::vfs::Vfs;
+use anyhow::Result;
+use serde::Deserialize;
+use std::sync::Arc;
+#[cfg(feature = "metrics")]
+use {
+    crate::vfs::CacheEvent,
+    std::sync::atomic::{AtomicU64, Ordering},
+    tokio::sync::broadcast,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    listen: String,
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn spawn(config: Config, _drives: Vec<(String, Arc<Vfs>)>) -> Result<()> {
+    anyhow::ensure!(
+        config.listen.is_empty(),
+        "metrics.listen is set, but onedrive-fuse is built without the `metrics` feature",
+    );
+    Ok(())
+}
+
+/// Start serving `/metrics` on `listen` in background, if it's not empty.
+/// Metrics of named drives are labeled with `drive`.
+#[cfg(feature = "metrics")]
+pub fn spawn(config: Config, drives: Vec<(String, Arc<Vfs>)>) -> Result<()> {
+    use anyhow::Context as _;
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, Server, StatusCode,
+    };
+    use std::{convert::Infallible, net::SocketAddr};
+
+    if config.listen.is_empty() {
+        return Ok(());
+    }
+    let addr: SocketAddr = config
+        .listen
+        .parse()
+        .context("Invalid metrics.listen address")?;
+
+    let drives = drives
+        .into_iter()
+        .map(|(name, vfs)| {
+            let events = Arc::new(CacheEventCounts::default());
+            tokio::spawn(count_cache_events(
+                vfs.subscribe_cache_events(),
+                events.clone(),
+            ));
+            Drive { name, vfs, events }
+        })
+        .collect::<Vec<_>>();
+    let drives = Arc::new(drives);
+    let make_svc = make_service_fn(move |_| {
+        let drives = drives.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let drives = drives.clone();
+                async move {
+                    let resp = if req.method() == Method::GET && req.uri().path() == "/metrics" {
+                        Response::builder()
+                            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                            .body(Body::from(render(&drives).await))
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                    };
+                    Ok::<_, Infallible>(resp.unwrap())
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("Failed to listen on {} for metrics", addr))?
+        .serve(make_svc);
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            log::error!("Metrics server failed: {}", err);
+        }
+    });
+    Ok(())
+}
+
+/// Name, type and help of a metric.
+#[cfg(feature = "metrics")]
+type MetricInfo = (&'static str, &'static str, &'static str);
+
+#[cfg(feature = "metrics")]
+struct Drive {
+    name: String,
+    vfs: Arc<Vfs>,
+    events: Arc<CacheEventCounts>,
+}
+
+/// Counters of cache lifecycle events of a drive, see `CacheEvent`.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+struct CacheEventCounts {
+    created: AtomicU64,
+    download_complete: AtomicU64,
+    invalidated: AtomicU64,
+    upload_started: AtomicU64,
+    upload_complete: AtomicU64,
+    upload_aborted: AtomicU64,
+    /// Events dropped since the counter lagged behind, which are not counted above.
+    missed: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+async fn count_cache_events(
+    mut events: broadcast::Receiver<CacheEvent>,
+    counts: Arc<CacheEventCounts>,
+) {
+    loop {
+        let counter = match events.recv().await {
+            Ok(CacheEvent::Created(_)) => &counts.created,
+            Ok(CacheEvent::DownloadComplete(_)) => &counts.download_complete,
+            Ok(CacheEvent::Invalidated(_)) => &counts.invalidated,
+            Ok(CacheEvent::UploadStarted(_)) => &counts.upload_started,
+            Ok(CacheEvent::UploadComplete(_)) => &counts.upload_complete,
+            Ok(CacheEvent::UploadAborted(_)) => &counts.upload_aborted,
+            // Evictions are counted process-wide in `transfer_metrics`.
+            Ok(
+                CacheEvent::Evicted(_)
+                | CacheEvent::DownloadProgress { .. }
+                | CacheEvent::UploadProgress { .. },
+            ) => continue,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::warn!("Metrics missed {} cache events", missed);
+                counts.missed.fetch_add(missed, Ordering::Relaxed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "metrics")]
+async fn render(drives: &[Drive]) -> String {
+    use std::fmt::Write as _;
+
+    // Transfer counters are process-wide, while caches are per drive.
+    let mut samples = transfer_metrics(&drives[0].vfs)
+        .into_iter()
+        .map(|metric| ("", metric))
+        .collect::<Vec<_>>();
+    for drive in drives {
+        let metrics = cache_metrics(&drive.vfs)
+            .await
+            .into_iter()
+            .chain(cache_event_metrics(&drive.events));
+        samples.extend(metrics.map(|metric| (&*drive.name, metric)));
+    }
+
+    // Samples of each metric in order of appearance, with HELP and TYPE only written once.
+    let mut metrics: Vec<(MetricInfo, Vec<(&str, u64)>)> = Vec::new();
+    for (drive, (name, kind, help, value)) in samples {
+        match metrics.iter_mut().find(|((prev, ..), _)| *prev == name) {
+            Some((_, samples)) => samples.push((drive, value)),
+            None => metrics.push(((name, kind, help), vec![(drive, value)])),
+        }
+    }
+
+    let mut out = String::new();
+    for ((name, kind, help), samples) in metrics {
+        let _ = write!(
+            out,
+            "# HELP onedrive_fuse_{name} {help}\n# TYPE onedrive_fuse_{name} {kind}\n",
+        );
+        for (drive, value) in samples {
+            if drive.is_empty() {
+                let _ = writeln!(out, "onedrive_fuse_{name} {value}");
+            } else {
+                let _ = writeln!(out, "onedrive_fuse_{name}{{drive={drive:?}}} {value}");
+            }
+        }
+    }
+    out
+}
+
+#[cfg(feature = "metrics")]
+fn transfer_metrics(vfs: &Vfs) -> Vec<(&'static str, &'static str, &'static str, u64)> {
+    let m = vfs.metrics();
+    vec![
+        (
+            "downloaded_bytes_total",
+            "counter",
+            "Bytes downloaded.",
+            m.downloaded_bytes,
+        ),
+        (
+            "uploaded_bytes_total",
+            "counter",
+            "Bytes uploaded.",
+            m.uploaded_bytes,
+        ),
+        (
+            "download_retries_total",
+            "counter",
+            "Retried download requests and streams.",
+            m.download_retries,
+        ),
+        (
+            "upload_retries_total",
+            "counter",
+            "Retried upload parts.",
+            m.upload_retries,
+        ),
+        (
+            "cache_evictions_total",
+            "counter",
+            "Cache entries evicted to make room.",
+            m.cache_evictions,
+        ),
+        (
+            "downloads_in_flight",
+            "gauge",
+            "Downloads in progress.",
+            m.downloads_in_flight,
+        ),
+        (
+            "uploads_in_flight",
+            "gauge",
+            "Uploads in progress or waiting for retry.",
+            m.uploads_in_flight,
+        ),
+        (
+            "open_handles",
+            "gauge",
+            "Opened file handles.",
+            m.open_handles,
+        ),
+        (
+            "download_rate_bytes",
+            "gauge",
+            "Download rate of active downloads in the last 10 seconds, in bytes per second.",
+            m.download_rate,
+        ),
+        (
+            "download_instant_rate_bytes",
+            "gauge",
+            "Download rate of active downloads in the last second, in bytes per second.",
+            m.download_instant_rate,
+        ),
+        (
+            "upload_rate_bytes",
+            "gauge",
+            "Upload rate of active uploads in the last 10 seconds, in bytes per second.",
+            m.upload_rate,
+        ),
+        (
+            "upload_instant_rate_bytes",
+            "gauge",
+            "Upload rate of active uploads in the last second, in bytes per second.",
+            m.upload_instant_rate,
+        ),
+    ]
+}
+
+#[cfg(feature = "metrics")]
+async fn cache_metrics(vfs: &Vfs) -> Vec<(&'static str, &'static str, &'static str, u64)> {
+    let mut metrics = Vec::new();
+    if let Some(stats) = vfs.cache_stats().await {
+        metrics.extend([
+            (
+                "cache_hits_total",
+                "counter",
+                "Lookups served by an existing cache entry.",
+                stats.hits,
+            ),
+            (
+                "cache_misses_total",
+                "counter",
+                "Lookups missing the cache.",
+                stats.misses,
+            ),
+            (
+                "cache_size_bytes",
+                "gauge",
+                "Total size of cached files.",
+                stats.total_size,
+            ),
+            (
+                "cache_max_size_bytes",
+                "gauge",
+                "Max total size of cached files.",
+                stats.max_total_size,
+            ),
+            (
+                "cache_files",
+                "gauge",
+                "Files in cache.",
+                stats.file_count as u64,
+            ),
+            (
+                "cache_max_files",
+                "gauge",
+                "Max files in cache.",
+                stats.max_files as u64,
+            ),
+            (
+                "cache_block_files",
+                "gauge",
+                "Block caches of files opened read-only.",
+                stats.block_file_count as u64,
+            ),
+            (
+                "cache_downloading_files",
+                "gauge",
+                "Alive cache files being downloaded.",
+                stats.downloading as u64,
+            ),
+            (
+                "cache_download_failed_files",
+                "gauge",
+                "Alive cache files failed to download.",
+                stats.download_failed as u64,
+            ),
+            (
+                "cache_available_files",
+                "gauge",
+                "Alive cache files completely downloaded and not modified.",
+                stats.available as u64,
+            ),
+            (
+                "cache_dirty_files",
+                "gauge",
+                "Alive cache files with changes not uploaded yet.",
+                stats.dirty as u64,
+            ),
+            (
+                "cache_invalidated_files",
+                "gauge",
+                "Alive cache files removed from cache but still opened.",
+                stats.invalidated as u64,
+            ),
+        ]);
+    }
+    metrics
+}
+
+#[cfg(feature = "metrics")]
+fn cache_event_metrics(
+    counts: &CacheEventCounts,
+) -> Vec<(&'static str, &'static str, &'static str, u64)> {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    vec![
+        (
+            "cache_created_total",
+            "counter",
+            "Cache entries created.",
+            load(&counts.created),
+        ),
+        (
+            "cache_downloads_completed_total",
+            "counter",
+            "Files completely downloaded into cache.",
+            load(&counts.download_complete),
+        ),
+        (
+            "cache_invalidations_total",
+            "counter",
+            "Cache entries invalidated by remote changes or deletions.",
+            load(&counts.invalidated),
+        ),
+        (
+            "cache_uploads_started_total",
+            "counter",
+            "Uploads of cached files started.",
+            load(&counts.upload_started),
+        ),
+        (
+            "cache_uploads_completed_total",
+            "counter",
+            "Uploads of cached files completed.",
+            load(&counts.upload_complete),
+        ),
+        (
+            "cache_uploads_aborted_total",
+            "counter",
+            "Uploads aborted since the file is gone in remote side.",
+            load(&counts.upload_aborted),
+        ),
+        (
+            "cache_events_missed_total",
+            "counter",
+            "Cache events not counted above since the exporter lagged behind.",
+            load(&counts.missed),
+        ),
+    ]
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use onedrive_api::ItemId;
+
+    #[tokio::test]
+    async fn count_cache_events_of_download_then_upload() {
+        let (tx, rx) = broadcast::channel(4);
+        let id = || ItemId("f".to_owned());
+        for event in [
+            CacheEvent::Created(id()),
+            CacheEvent::DownloadProgress {
+                item_id: id(),
+                downloaded: 1,
+                total: 2,
+            },
+            CacheEvent::DownloadComplete(id()),
+            CacheEvent::UploadStarted(id()),
+        ] {
+            tx.send(event).unwrap();
+        }
+        let counts = Arc::new(CacheEventCounts::default());
+        let task = tokio::spawn(count_cache_events(rx, counts.clone()));
+        tokio::task::yield_now().await;
+        // Overflow the buffer before the counter catches up.
+        for _ in 0..6 {
+            tx.send(CacheEvent::UploadComplete(id())).unwrap();
+        }
+        drop(tx);
+        task.await.unwrap();
+
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        assert_eq!(load(&counts.created), 1);
+        assert_eq!(load(&counts.download_complete), 1);
+        assert_eq!(load(&counts.upload_started), 1);
+        assert_eq!(load(&counts.upload_complete), 4);
+        assert_eq!(load(&counts.missed), 2);
+        assert_eq!(load(&counts.invalidated), 0);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::login::TokenExpired;
+use onedrive_api::ItemId;
 use reqwest::StatusCode;
 use std::ffi::OsString;
 
@@ -20,8 +22,22 @@ pub enum Error {
     FileExists,
     #[error("File changed in remote side, please re-open it")]
     Invalidated,
+    #[error("File is deleted in remote side")]
+    Deleted,
+    #[error("File is changed in remote side since the last sync")]
+    Conflict,
+    #[error("Handle {0} is not opened for write")]
+    NotOpenedForWrite(u64),
     #[error("File is uploading, you cannot move or remove it")]
     Uploading,
+    #[error("Too many open files")]
+    TooManyOpenFiles,
+    #[error("Access token expired or rejected, please check your network or re-login")]
+    AuthExpired,
+    #[error("Download from host {0:?} is not allowed")]
+    DownloadHostNotAllowed(String),
+    #[error("Not enough free space in OneDrive")]
+    NoSpace,
 
     // Api and network errors.
     #[error("Api error: {0}")]
@@ -30,8 +46,24 @@ pub enum Error {
     Deserialize(#[from] serde_json::Error),
     #[error("reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
+    #[error("Missing field in API response: {0}")]
+    MissingField(&'static str),
+    #[error("Unsupported item {item_id:?}: {reason}")]
+    UnsupportedItem { item_id: ItemId, reason: String },
     #[error("Download failed")]
     DownloadFailed,
+    #[error("Download exceeded max total duration")]
+    DownloadTimeout,
+    #[error("Download failed after {tries} tries, last error: {last_error}")]
+    DownloadRetryExhausted { tries: usize, last_error: String },
+    #[error("Download size {actual} disagrees with the expected size {expected}")]
+    DownloadSizeMismatch { expected: u64, actual: u64 },
+    #[error("Upload failed")]
+    UploadFailed,
+    #[error("Streaming upload is closed after writing {written} B of the truncated size {size} B")]
+    UploadIncomplete { written: u64, size: u64 },
+    #[error("Network is unreachable and the file is not cached")]
+    Offline,
 
     // IO error.
     #[error("IO error: {0}")]
@@ -44,10 +76,22 @@ pub enum Error {
         read_offset: u64,
         read_size: usize,
     },
+    #[error("Nonsequential write is not supported in streaming upload: current at {current_pos} but try to write {write_size} at {write_offset}")]
+    NonsequentialWrite {
+        current_pos: u64,
+        write_offset: u64,
+        write_size: usize,
+    },
+    #[error("Reading is not supported in streaming upload")]
+    ReadDuringUpload,
     #[error("File is too large to write")]
     FileTooLarge,
+    #[error("File of {size} B is larger than the download limit {limit} B")]
+    FileTooLargeToDownload { size: u64, limit: u64 },
     #[error("File writing is not supported without disk cache")]
     WriteWithoutCache,
+    #[error("Items shared with you are read-only")]
+    SharedReadOnly,
 
     // Fuse errors.
     // They are hard errors here, since `fuse` should guarantee that they are valid.
@@ -62,11 +106,19 @@ impl From<onedrive_api::Error> for Error {
         match err.status_code() {
             Some(StatusCode::NOT_FOUND) => Self::NotFound,
             Some(StatusCode::CONFLICT) => Self::FileExists,
+            Some(StatusCode::UNAUTHORIZED) => Self::AuthExpired,
+            Some(StatusCode::PRECONDITION_FAILED) => Self::Conflict,
             _ => Self::Api(err),
         }
     }
 }
 
+impl From<TokenExpired> for Error {
+    fn from(_: TokenExpired) -> Self {
+        Self::AuthExpired
+    }
+}
+
 impl Error {
     pub fn into_c_err(self) -> libc::c_int {
         match &self {
@@ -77,26 +129,74 @@ impl Error {
             Self::DirectoryNotEmpty => libc::ENOTEMPTY,
             Self::FileExists => libc::EEXIST,
             Self::Invalidated => libc::EPERM,
+            Self::Deleted | Self::Conflict => {
+                log::info!("{}", self);
+                libc::ESTALE
+            }
             Self::Uploading => libc::ETXTBSY,
+            Self::TooManyOpenFiles => libc::EMFILE,
+            Self::NoSpace => {
+                log::info!("{}", self);
+                libc::ENOSPC
+            }
+            Self::NotOpenedForWrite(_) => {
+                log::info!("{}", self);
+                libc::EBADF
+            }
             Self::InvalidFileName(_) => {
                 log::info!("{}", self);
                 libc::EINVAL
             }
+            Self::AuthExpired | Self::DownloadHostNotAllowed(_) => {
+                log::error!("{}", self);
+                libc::EACCES
+            }
+
+            Self::Io(err) if err.raw_os_error() == Some(libc::ENOSPC) => {
+                log::error!("{}", self);
+                libc::ENOSPC
+            }
 
             // Network errors.
-            Self::Api(_) | Self::Deserialize(_) | Self::Reqwest(_) | Self::Io(_) => {
+            Self::Api(_)
+            | Self::Deserialize(_)
+            | Self::Reqwest(_)
+            | Self::Io(_)
+            | Self::MissingField(_)
+            | Self::UnsupportedItem { .. }
+            | Self::UploadIncomplete { .. } => {
                 log::error!("{}", self);
                 log::debug!("{:?}", self);
                 libc::EIO
             }
             // Already reported.
-            Self::DownloadFailed => libc::EIO,
+            Self::DownloadFailed
+            | Self::DownloadRetryExhausted { .. }
+            | Self::DownloadSizeMismatch { .. }
+            | Self::UploadFailed => libc::EIO,
+            Self::DownloadTimeout => libc::ETIMEDOUT,
+            Self::Offline => {
+                log::info!("{}", self);
+                libc::ENETDOWN
+            }
+            Self::FileTooLargeToDownload { .. } => {
+                log::info!("{}", self);
+                libc::EFBIG
+            }
 
             // Not supported
-            Self::NonsequentialRead { .. } | Self::FileTooLarge | Self::WriteWithoutCache => {
+            Self::NonsequentialRead { .. }
+            | Self::NonsequentialWrite { .. }
+            | Self::ReadDuringUpload
+            | Self::FileTooLarge
+            | Self::WriteWithoutCache => {
                 log::info!("{}", self);
                 libc::EPERM
             }
+            Self::SharedReadOnly => {
+                log::info!("{}", self);
+                libc::EROFS
+            }
 
             // Fuse errors.
             Self::InvalidInode(_) | Self::InvalidHandle(_) => {
@@ -7,33 +7,43 @@ use crate::{
 use bytes::{Bytes, BytesMut};
 use lru_cache::LruCache;
 use onedrive_api::{
-    option::DriveItemPutOption,
-    resource::{DriveItem, DriveItemField},
-    ConflictBehavior, ItemId, ItemLocation, OneDrive, Tag,
+    option::{DriveItemPutOption, ObjectOption},
+    resource::{DriveId, DriveItem, DriveItemField},
+    ConflictBehavior, ExpectRange, FileName, ItemId, ItemLocation, OneDrive, Tag, UploadSession,
 };
 use reqwest::{header, StatusCode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sharded_slab::Slab;
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     convert::TryFrom as _,
+    future::Future,
     io::{self, SeekFrom},
-    path::PathBuf,
+    ops::Range,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc, Mutex as SyncMutex, Weak,
     },
     time::{Duration, Instant, SystemTime},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-    sync::{mpsc, oneshot, watch, Mutex, MutexGuard},
+    sync::{broadcast, mpsc, oneshot, watch, Mutex, MutexGuard, Semaphore},
+    task::{JoinHandle, JoinSet},
     time,
 };
 
-use super::InodeAttr;
+use super::{
+    hash::QuickXorHash,
+    inode::{content_tag, quick_xor_hash_of, ItemKind},
+    metrics::{InFlight, Metrics, Transfer, METRICS},
+    shared, InodeAttr,
+};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
+    max_open_handles: usize,
     disk_cache: DiskCacheConfig,
     download: DownloadConfig,
     upload: UploadConfig,
@@ -48,6 +58,70 @@ struct DownloadConfig {
     stream_ring_buffer_size: usize,
     #[serde(deserialize_with = "de_duration_sec")]
     chunk_timeout: Duration,
+    #[serde(deserialize_with = "de_duration_sec")]
+    max_total_duration: Duration,
+    exclude_throttle_waits: bool,
+    max_total_buffer_bytes: usize,
+    priority_first_read: bool,
+    allowed_hosts: Vec<String>,
+    max_file_size: u64,
+    read_window_size: usize,
+    size_mismatch: SizeMismatchPolicy,
+    segments: usize,
+    segment_size: u64,
+    verify_hash: bool,
+    #[serde(default)]
+    max_buffered_bytes: usize,
+    #[serde(deserialize_with = "de_duration_sec")]
+    progress_interval: Duration,
+}
+
+/// How to handle a download whose total size disagrees with the metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SizeMismatchPolicy {
+    /// Trust the size of the download, and correct the file size.
+    PreferDownload,
+    /// Fail the download.
+    Error,
+}
+
+impl DownloadConfig {
+    fn check_file_size(&self, size: u64) -> Result<()> {
+        if self.max_file_size != 0 && self.max_file_size < size {
+            return Err(Error::FileTooLargeToDownload {
+                size,
+                limit: self.max_file_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check the host of a download URL against `allowed_hosts`.
+    /// Returns the host on refusal.
+    fn check_host(&self, download_url: &str) -> std::result::Result<(), String> {
+        if self.allowed_hosts.is_empty() {
+            return Ok(());
+        }
+        let host = reqwest::Url::parse(download_url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_ascii_lowercase()))
+            .unwrap_or_default();
+        let allowed = self.allowed_hosts.iter().any(|pattern| {
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(suffix) => host
+                    .strip_suffix(suffix)
+                    .is_some_and(|prefix| prefix.ends_with('.')),
+                None => host == pattern,
+            }
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(host)
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -55,9 +129,87 @@ struct DiskCacheConfig {
     enable: bool,
     #[serde(default = "default_disk_cache_dir")]
     path: PathBuf,
+    #[serde(default)]
+    prestage_path: Option<PathBuf>,
+    persistent: bool,
+    purge_on_start: bool,
+    mode: CacheMode,
+    block_size: u64,
     max_cached_file_size: u64,
     max_files: usize,
     max_total_size: u64,
+    min_free_space: u64,
+    #[serde(deserialize_with = "de_duration_sec")]
+    reconcile_period: Duration,
+    on_invalidate_during_read: InvalidatedHandlePolicy,
+    on_invalidate_during_write: InvalidatedHandlePolicy,
+    on_invalidate_dirty: InvalidateDirtyPolicy,
+    open_unlinked: bool,
+    open_rules: Vec<OpenRule>,
+}
+
+/// How files opened in read-only mode are cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CacheMode {
+    /// Download the whole file in background once opened.
+    /// Files larger than `max_cached_file_size` are streamed instead.
+    Whole,
+    /// Download blocks covering each read on demand, regardless of the file size.
+    /// Files opened for write are still cached as a whole.
+    Blocks,
+}
+
+/// A rule to choose between streaming and caching when opening a file in read-only mode.
+#[derive(Debug, Deserialize, Clone)]
+struct OpenRule {
+    /// File extensions to match, case-insensitive. Empty to match all files.
+    #[serde(default)]
+    extensions: Vec<String>,
+    #[serde(default)]
+    min_size: u64,
+    #[serde(default)]
+    max_size: Option<u64>,
+    strategy: OpenStrategy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OpenStrategy {
+    Stream,
+    Cache,
+}
+
+impl OpenRule {
+    fn matches(&self, name: &str, size: u64) -> bool {
+        let ext_matched = self.extensions.is_empty()
+            || name.rsplit_once('.').is_some_and(|(_, ext)| {
+                self.extensions
+                    .iter()
+                    .any(|expect| expect.eq_ignore_ascii_case(ext))
+            });
+        ext_matched && self.min_size <= size && self.max_size.is_none_or(|max| size <= max)
+    }
+}
+
+/// How to handle reads or writes on a handle whose cache is invalidated by remote changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum InvalidatedHandlePolicy {
+    /// Fail the operation.
+    Error,
+    /// Re-open the file with the new content and continue on it.
+    Retry,
+}
+
+/// How to handle local changes not uploaded yet when the file is changed in remote side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum InvalidateDirtyPolicy {
+    /// Drop local changes, and invalidate the cache like other outdated files.
+    Discard,
+    /// Upload the local version as a conflict copy besides, like `UploadConflictPolicy::KeepBoth`.
+    KeepBoth,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -66,17 +218,129 @@ struct UploadConfig {
     #[serde(deserialize_with = "de_duration_sec")]
     flush_delay: Duration,
     #[serde(deserialize_with = "de_duration_sec")]
+    max_flush_delay: Duration,
+    #[serde(deserialize_with = "de_duration_sec")]
+    exit_flush_timeout: Duration,
+    #[serde(deserialize_with = "de_duration_sec")]
     retry_delay: Duration,
+    stream_upload: bool,
+    stream_max_retry: usize,
+    part_max_retry: usize,
+    memory_buffer_max: u64,
+    verify_after_upload: bool,
+    verify_max_retry: usize,
+    write_during_download: WriteDuringDownloadPolicy,
+    session_fragment_size: usize,
+    on_conflict: UploadConflictPolicy,
+}
+
+/// How to handle an upload whose target is changed in remote side since the last sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UploadConflictPolicy {
+    /// Replace the remote version unconditionally.
+    Overwrite,
+    /// Upload the local version as a new file besides, and invalidate the local cache.
+    KeepBoth,
+    /// Keep the local version dirty without uploading.
+    Fail,
+}
+
+/// How to handle writes to a cached file which is still downloading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum WriteDuringDownloadPolicy {
+    /// Block the write until the download completes.
+    Wait,
+    /// Write into the cache immediately. The download skips ranges already written.
+    Overwrite,
 }
 
+const UPLOAD_PART_SIZE: usize = 10 << 20;
+// Required by upload session for all parts except the last one.
+const UPLOAD_FRAGMENT_ALIGN: usize = 320 << 10;
+static_assertions::const_assert!(UPLOAD_PART_SIZE <= onedrive_api::UploadSession::MAX_PART_SIZE);
+static_assertions::const_assert_eq!(UPLOAD_PART_SIZE % UPLOAD_FRAGMENT_ALIGN, 0);
+
+/// Max delay to honor from `Retry-After` of throttling responses.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
 pub struct FilePool {
-    handles: Slab<File>,
+    handles: Slab<Handle>,
+    /// Number of `handles`, limited by `max_open_handles`.
+    open_handles: AtomicUsize,
     disk_cache: Option<DiskCache>,
     event_tx: mpsc::Sender<UpdateEvent>,
     config: Config,
     onedrive: ManagedOnedrive,
     /// The client without timeout limit, which is used for upload and download.
     client: reqwest::Client,
+    /// Bytes of chunks allowed to be buffered in all streaming downloads.
+    stream_buffer_budget: Arc<BufferBudget>,
+    cache_events: broadcast::Sender<CacheEvent>,
+    /// Whether the network is unreachable, see `Tracker::subscribe_offline`.
+    offline: watch::Receiver<bool>,
+    /// Opened streaming files with their c_tag when opened, to invalidate them on remote changes.
+    streams: SyncMutex<Vec<(ItemId, Tag, Weak<AtomicBool>)>>,
+}
+
+/// Max cache events buffered for each subscriber. A subscriber lagging behind by more than it
+/// misses the oldest events, and gets `RecvError::Lagged` once before continuing.
+const CACHE_EVENT_CAPACITY: usize = 1024;
+
+/// Lifecycle events of cache entries, for external observers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheEvent {
+    /// A cache entry is created, either downloading, pre-staged or empty.
+    Created(ItemId),
+    DownloadComplete(ItemId),
+    /// The cache entry is outdated or deleted, and removed from cache.
+    Invalidated(ItemId),
+    /// The cache entry is removed from cache to make room for others.
+    /// Its content is still alive until all handles of it are closed.
+    Evicted(ItemId),
+    /// Bytes downloaded into cache so far and the total bytes to download. It's emitted at most
+    /// once per `progress_interval`, or whenever another 5% is downloaded.
+    DownloadProgress {
+        item_id: ItemId,
+        downloaded: u64,
+        total: u64,
+    },
+    UploadStarted(ItemId),
+    /// A part is uploaded, with bytes uploaded so far and the total bytes to upload.
+    /// It's emitted during waiting uploads in `fsync` as well.
+    UploadProgress {
+        item_id: ItemId,
+        uploaded: u64,
+        total: u64,
+    },
+    UploadComplete(ItemId),
+    /// The upload is aborted since the file no longer exists in remote side.
+    /// Local changes are discarded and the cache entry is invalidated.
+    UploadAborted(ItemId),
+}
+
+/// Snapshot of disk cache statistics, see `FilePool::cache_stats`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    pub total_size: u64,
+    pub max_total_size: u64,
+    /// Entries in cache, excluding these evicted or invalidated but still opened.
+    pub file_count: usize,
+    pub max_files: usize,
+    /// Block caches of files opened read-only in `blocks` mode.
+    pub block_file_count: usize,
+    // Alive cache files by status, including these removed from cache but still opened.
+    pub downloading: usize,
+    pub download_failed: usize,
+    pub available: usize,
+    pub dirty: usize,
+    /// Invalidated, unlinked or timed out entries, which are not in cache anymore.
+    pub invalidated: usize,
+    /// Lookups served by an existing cache entry.
+    pub hits: u64,
+    /// Lookups allocating a new cache entry, or bypassing cache due to insufficient space.
+    pub misses: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -92,31 +356,108 @@ struct RemoteFileMeta {
     size: u64,
     c_tag: Tag,
     download_url: String,
+    /// Base64 encoded quickXorHash of the content, if provided.
+    quick_xor_hash: Option<String>,
+}
+
+impl RemoteFileMeta {
+    fn parse(item: DriveItem) -> Result<Self> {
+        let quick_xor_hash = quick_xor_hash_of(&item).map(|hash| hash.to_owned());
+        Ok(Self {
+            quick_xor_hash,
+            size: item.size.ok_or(Error::MissingField("size"))? as u64,
+            c_tag: content_tag(&item).ok_or(Error::MissingField("cTag"))?,
+            download_url: item
+                .download_url
+                .ok_or(Error::MissingField("@microsoft.graph.downloadUrl"))?,
+        })
+    }
 }
 
 impl FilePool {
-    pub const SYNC_SELECT_FIELDS: &'static [DriveItemField] = &[DriveItemField::c_tag];
+    pub const SYNC_SELECT_FIELDS: &'static [DriveItemField] =
+        &[DriveItemField::c_tag, DriveItemField::e_tag];
 
     pub fn new(
+        drive_id: &DriveId,
         event_tx: mpsc::Sender<UpdateEvent>,
         onedrive: ManagedOnedrive,
         unlimit_client: reqwest::Client,
+        offline: watch::Receiver<bool>,
         config: Config,
     ) -> anyhow::Result<Self> {
+        let fragment_size = config.upload.session_fragment_size;
+        anyhow::ensure!(
+            fragment_size != 0
+                && fragment_size.is_multiple_of(UPLOAD_FRAGMENT_ALIGN)
+                && fragment_size <= UploadSession::MAX_PART_SIZE,
+            "upload.session_fragment_size must be a positive multiple of 320 KiB, at most 60 MiB",
+        );
+        anyhow::ensure!(
+            config.disk_cache.mode != CacheMode::Blocks || config.disk_cache.block_size != 0,
+            "disk_cache.block_size must be positive in blocks mode",
+        );
+        let stream_buffer_budget =
+            Arc::new(BufferBudget::new(config.download.max_total_buffer_bytes));
+        let (cache_events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+        let disk_cache = if config.disk_cache.enable {
+            let (cache, dirty) = DiskCache::new(config.clone(), drive_id, cache_events.clone())?;
+            // Upload changes left by the previous run.
+            for (file, mtime, resume) in dirty {
+                log::info!("Pending upload for reloaded dirty file {:?}", file.item_id);
+                let mut guard = file.state.try_lock().expect("Not shared yet");
+                file.queue_upload(
+                    &mut guard,
+                    mtime,
+                    onedrive.clone(),
+                    unlimit_client.clone(),
+                    event_tx.clone(),
+                    config.upload.clone(),
+                );
+                // After `queue_upload` dropping sessions of previous contents.
+                if let Some(sidecar) = resume {
+                    file.has_upload_sidecar.store(true, Ordering::Relaxed);
+                    *file.upload_resume.lock().unwrap() = Some(sidecar);
+                }
+            }
+            Some(cache)
+        } else if config.upload.memory_buffer_max != 0 {
+            Some(DiskCache::new_in_memory(
+                config.clone(),
+                cache_events.clone(),
+            ))
+        } else {
+            None
+        };
         Ok(Self {
             handles: Slab::new(),
-            disk_cache: if config.disk_cache.enable {
-                Some(DiskCache::new(config.clone())?)
-            } else {
-                None
-            },
+            open_handles: AtomicUsize::new(0),
+            disk_cache,
             event_tx,
             config,
             onedrive,
             client: unlimit_client,
+            stream_buffer_budget,
+            cache_events,
+            offline,
+            streams: SyncMutex::new(Vec::new()),
         })
     }
 
+    /// Fail fast without touching the network if it's known to be unreachable.
+    fn check_online(&self) -> Result<()> {
+        match *self.offline.borrow() {
+            true => Err(Error::Offline),
+            false => Ok(()),
+        }
+    }
+
+    /// Subscribe lifecycle events of cache entries. See `CacheEvent`.
+    /// Nothing is received if neither disk cache nor memory buffer is enabled.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<CacheEvent> {
+        self.cache_events.subscribe()
+    }
+
     fn key_to_fh(key: usize) -> u64 {
         u64::try_from(key).unwrap()
     }
@@ -128,53 +469,181 @@ impl FilePool {
     // Fetch file size, CTag and download URL.
     async fn fetch_meta(item_id: &ItemId, onedrive: &OneDrive) -> Result<RemoteFileMeta> {
         // `download_url` is available without `$select`.
-        let item = onedrive.get_item(ItemLocation::from_id(item_id)).await?;
-        Ok(RemoteFileMeta {
-            size: item.size.unwrap() as u64,
-            c_tag: item.c_tag.unwrap(),
-            download_url: item.download_url.unwrap(),
-        })
+        let item = match shared::remote_of(item_id) {
+            Some((drive_id, remote_id)) => {
+                shared::remote_drive(onedrive, drive_id)
+                    .get_item(ItemLocation::from_id(&remote_id))
+                    .await?
+            }
+            None => onedrive.get_item(ItemLocation::from_id(item_id)).await?,
+        };
+        RemoteFileMeta::parse(item)
     }
 
-    async fn open_inner(&self, item_id: &ItemId, write_mode: bool) -> Result<File> {
+    /// `name` is used to match `open_rules`, or `None` to skip the matching.
+    async fn open_inner(
+        &self,
+        item_id: &ItemId,
+        name: Option<&str>,
+        write_mode: bool,
+    ) -> Result<File> {
         let meta = if let Some(cache) = &self.disk_cache {
             if let Some(state) = cache.get(item_id) {
                 log::debug!("File already cached: {:?}", item_id);
+                let failed = matches!(
+                    state.state.lock().await.status,
+                    FileCacheStatus::DownloadFailed
+                );
+                // Complete or partial content is still served while offline.
+                if failed && self.check_online().is_ok() {
+                    let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await?).await?;
+                    cache
+                        .try_resume(
+                            &state,
+                            &meta,
+                            self.onedrive.clone(),
+                            self.event_tx.clone(),
+                            self.client.clone(),
+                        )
+                        .await;
+                }
                 return Ok(File::Cached(state));
             }
 
-            let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await).await?;
-            if let Some(state) = cache.try_alloc_and_fetch(
-                item_id,
-                &meta,
-                None,
-                self.onedrive.clone(),
-                self.event_tx.clone(),
-                self.client.clone(),
-            )? {
-                log::debug!("Caching file {:?}, meta: {:?}", item_id, meta);
-                return Ok(File::Cached(state));
-            } else if write_mode {
-                return Err(Error::FileTooLarge);
+            self.check_online()?;
+            loop {
+                let sync_seq = cache.sync_seq();
+                let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await?).await?;
+                self.config.download.check_file_size(meta.size)?;
+                let strategy = name.and_then(|name| {
+                    let rules = &self.config.disk_cache.open_rules;
+                    let rule = rules.iter().find(|rule| rule.matches(name, meta.size))?;
+                    Some(rule.strategy)
+                });
+                if !write_mode && strategy == Some(OpenStrategy::Stream) {
+                    // Forced to stream by open rules.
+                    break meta;
+                } else if !write_mode && cache.dir.is_none() {
+                    // Only files opened for write are buffered in memory.
+                    break meta;
+                } else if !write_mode && self.config.disk_cache.mode == CacheMode::Blocks {
+                    match cache.open_blocks(item_id, &meta, sync_seq)? {
+                        Some(file) => {
+                            log::debug!("Caching blocks of {:?}, meta: {:?}", item_id, meta);
+                            return Ok(File::Blocks(file));
+                        }
+                        None => {
+                            log::debug!("Meta of {:?} may be outdated by sync, re-fetch", item_id);
+                            continue;
+                        }
+                    }
+                }
+                match cache.try_alloc_and_fetch(
+                    item_id,
+                    &meta,
+                    sync_seq,
+                    None,
+                    self.onedrive.clone(),
+                    self.event_tx.clone(),
+                    self.client.clone(),
+                )? {
+                    Alloc::Cached(state) => {
+                        log::debug!("Caching file {:?}, meta: {:?}", item_id, meta);
+                        return Ok(File::Cached(state));
+                    }
+                    Alloc::NoSpace if write_mode => return Err(Error::FileTooLarge),
+                    Alloc::NoSpace => break meta,
+                    Alloc::Outdated => {
+                        log::debug!("Meta of {:?} may be outdated by sync, re-fetch", item_id);
+                    }
+                }
             }
-
-            meta
         } else if write_mode {
             return Err(Error::WriteWithoutCache);
         } else {
-            Self::fetch_meta(item_id, &*self.onedrive.get().await).await?
+            self.check_online()?;
+            let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await?).await?;
+            self.config.download.check_file_size(meta.size)?;
+            meta
         };
 
         log::debug!("Streaming file {:?}, meta: {:?}", item_id, meta);
-        let state =
-            FileStreamState::fetch(&meta, self.client.clone(), self.config.download.clone());
-        Ok(File::Streaming(Arc::new(Mutex::new(state))))
+        let state = FileStreamState::fetch(
+            item_id,
+            &meta,
+            self.onedrive.clone(),
+            self.client.clone(),
+            self.stream_buffer_budget.clone(),
+            self.event_tx.clone(),
+            self.config.download.clone(),
+        );
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|(_, _, invalidated)| invalidated.strong_count() != 0);
+        streams.push((
+            item_id.clone(),
+            meta.c_tag,
+            Arc::downgrade(&state.invalidated),
+        ));
+        Ok(File::streaming(state))
+    }
+
+    pub async fn open(&self, item_id: &ItemId, name: &str, write_mode: bool) -> Result<u64> {
+        let file = self.open_inner(item_id, Some(name), write_mode).await?;
+        self.insert_handle(file, write_mode)
+    }
+
+    fn insert_handle(&self, file: File, write: bool) -> Result<u64> {
+        let max = self.config.max_open_handles;
+        let reserved =
+            self.open_handles
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cnt| {
+                    (max == 0 || cnt < max).then_some(cnt + 1)
+                });
+        if reserved.is_err() {
+            log::warn!("Too many open handles, max: {}", max);
+            return Err(Error::TooManyOpenFiles);
+        }
+        let handle = Handle {
+            file: SyncMutex::new(file),
+            write,
+            read_window: SyncMutex::new(None),
+            _open: METRICS.open_handle(),
+        };
+        match self.handles.insert(handle) {
+            Some(key) => Ok(Self::key_to_fh(key)),
+            None => {
+                self.open_handles.fetch_sub(1, Ordering::Relaxed);
+                log::warn!("Handle pool is full");
+                Err(Error::TooManyOpenFiles)
+            }
+        }
+    }
+
+    fn handle(&self, fh: u64) -> Result<sharded_slab::Entry<'_, Handle>> {
+        self.handles
+            .get(Self::fh_to_key(fh))
+            .ok_or(Error::InvalidHandle(fh))
+    }
+
+    fn get_handle(&self, fh: u64) -> Result<File> {
+        let file = self.handle(fh)?.file.lock().unwrap().clone();
+        Ok(file)
+    }
+
+    fn get_write_handle(&self, fh: u64) -> Result<File> {
+        let handle = self.handle(fh)?;
+        if !handle.write {
+            return Err(Error::NotOpenedForWrite(fh));
+        }
+        let file = handle.file.lock().unwrap().clone();
+        Ok(file)
     }
 
-    pub async fn open(&self, item_id: &ItemId, write_mode: bool) -> Result<u64> {
-        let file = self.open_inner(item_id, write_mode).await?;
-        let key = self.handles.insert(file).expect("Pool is full");
-        Ok(Self::key_to_fh(key))
+    fn set_handle(&self, fh: u64, file: File) -> Result<()> {
+        let handle = self.handle(fh)?;
+        *handle.file.lock().unwrap() = file;
+        *handle.read_window.lock().unwrap() = None;
+        Ok(())
     }
 
     pub async fn open_create_empty(
@@ -186,22 +655,92 @@ impl FilePool {
         let item = self
             .onedrive
             .get()
-            .await
+            .await?
             .upload_small(item_loc, Vec::new())
             .await?;
-        assert_eq!(item.size, Some(0));
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attrs");
-        let id = item.id.expect("Missing id");
+        let (id, attr) = InodeAttr::parse_response(&item)?;
+        if attr.size != 0 {
+            return Err(Error::UnsupportedItem {
+                item_id: id,
+                reason: format!("Uploaded empty file has size {}", attr.size),
+            });
+        }
         log::debug!("Truncated or created file {:?}", id);
 
-        let file = cache
-            .insert_empty(id.clone(), attr.c_tag.clone().unwrap())
-            .await?;
-        let key = self
-            .handles
-            .insert(File::Cached(file))
-            .expect("Pool is full");
-        Ok((Self::key_to_fh(key), id, attr))
+        let c_tag = attr.c_tag.clone().ok_or(Error::MissingField("cTag"))?;
+        let file = cache.insert_empty(id.clone(), c_tag).await?;
+        Ok((self.insert_handle(File::Cached(file), true)?, id, attr))
+    }
+
+    /// Try to switch a handle of a just created empty file into streaming upload mode,
+    /// using the truncated size as the hint of the final file size.
+    /// Return `false` if the handle is not applicable so the normal truncation should be done.
+    ///
+    /// In this mode, file content should be written sequentially, and is uploaded part by part
+    /// without going through the disk cache. A nonsequential write before the first part is
+    /// uploaded falls back to the normal way, see `fallback_to_cache`.
+    pub async fn try_start_stream_upload(
+        &self,
+        fh: u64,
+        file_size: u64,
+        mtime: SystemTime,
+    ) -> Result<bool> {
+        if !self.config.upload.stream_upload || file_size <= self.config.upload.max_size {
+            return Ok(false);
+        }
+        let cache_file = match self.get_handle(fh)? {
+            File::Cached(file) => file,
+            File::Streaming(..) | File::Blocks(_) | File::Uploading(_) => return Ok(false),
+        };
+        let is_empty = |state: &FileCacheState| {
+            state.file_size == 0 && matches!(state.status, FileCacheStatus::Available)
+        };
+        if !is_empty(&*cache_file.state.lock().await) {
+            return Ok(false);
+        }
+
+        // The cache is kept if the session cannot be created.
+        let c_tag = cache_file.c_tag.lock().unwrap().clone();
+        let state = FileUploadState::new(
+            cache_file.item_id.clone(),
+            file_size,
+            mtime,
+            c_tag,
+            &*self.onedrive.get().await?,
+        )
+        .await?;
+        {
+            let mut guard = cache_file.state.lock().await;
+            if !is_empty(&guard) {
+                log::debug!(
+                    "Cache of {:?} is changed while creating the upload session",
+                    cache_file.item_id,
+                );
+                state.cancel(&self.client).await;
+                return Ok(false);
+            }
+            // Cached empty content is going to be outdated.
+            guard.status = FileCacheStatus::Invalidated;
+            cache_file.bump_version();
+        }
+        if let Some(cache) = &self.disk_cache {
+            let mut cache = cache.cache.lock().unwrap();
+            if cache
+                .get_mut(&cache_file.item_id)
+                .is_some_and(|file| Arc::ptr_eq(file, &cache_file))
+            {
+                cache.remove(&cache_file.item_id);
+            }
+        }
+        cache_file.emit(CacheEvent::Invalidated(cache_file.item_id.clone()));
+
+        log::debug!(
+            "Streaming upload {:?} ({} B)",
+            cache_file.item_id,
+            file_size,
+        );
+        self.set_handle(fh, File::Uploading(Arc::new(Mutex::new(state))))?;
+        Ok(true)
     }
 
     pub async fn truncate_file(
@@ -210,23 +749,24 @@ impl FilePool {
         new_size: u64,
         mtime: SystemTime,
     ) -> Result<()> {
-        if new_size > self.config.disk_cache.max_cached_file_size {
+        let cache = self.disk_cache.as_ref().ok_or(Error::WriteWithoutCache)?;
+        if new_size > cache.config.disk_cache.max_cached_file_size {
             return Err(Error::FileTooLarge);
         }
 
-        let cache = self.disk_cache.as_ref().ok_or(Error::WriteWithoutCache)?;
-
         let file = cache.cache.lock().unwrap().get_mut(item_id).cloned();
         if let Some(file) = file {
             let mut guard = file.state.lock().await;
             match guard.status {
                 FileCacheStatus::Downloading { truncate } => {
                     let download_size = truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size);
+                    guard.cache_file.set_len(new_size).await?;
                     guard.status = FileCacheStatus::Downloading {
                         truncate: Some((download_size.min(new_size), mtime)),
                     };
+                    file.account_resize(guard.file_size, new_size);
                     guard.file_size = new_size;
-                    guard.cache_file.set_len(new_size).await.unwrap();
+                    file.bump_version();
                     log::debug!(
                         "Pending another truncate for still downloading file {:?}",
                         item_id,
@@ -240,8 +780,10 @@ impl FilePool {
                         guard.file_size,
                         new_size,
                     );
+                    guard.cache_file.set_len(new_size).await?;
+                    file.account_resize(guard.file_size, new_size);
                     guard.file_size = new_size;
-                    guard.cache_file.set_len(new_size).await.unwrap();
+                    file.bump_version();
                     file.queue_upload(
                         &mut guard,
                         mtime,
@@ -252,216 +794,676 @@ impl FilePool {
                     );
                     return Ok(());
                 }
-                FileCacheStatus::DownloadFailed | FileCacheStatus::Invalidated => {}
+                FileCacheStatus::DownloadFailed
+                | FileCacheStatus::DownloadTimeout
+                | FileCacheStatus::Unlinked
+                | FileCacheStatus::Invalidated => {}
             }
         }
 
-        let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await).await?;
-        log::debug!(
-            "Download with truncate {:?}: new size: {}, remote meta: {:?}",
-            item_id,
-            new_size,
-            meta,
-        );
+        loop {
+            let sync_seq = cache.sync_seq();
+            let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await?).await?;
+            log::debug!(
+                "Download with truncate {:?}: new size: {}, remote meta: {:?}",
+                item_id,
+                new_size,
+                meta,
+            );
 
-        match cache.try_alloc_and_fetch(
-            item_id,
-            &meta,
-            Some((new_size, mtime)),
+            match cache.try_alloc_and_fetch(
+                item_id,
+                &meta,
+                sync_seq,
+                Some((new_size, mtime)),
+                self.onedrive.clone(),
+                self.event_tx.clone(),
+                self.client.clone(),
+            )? {
+                Alloc::Cached(_) => return Ok(()),
+                Alloc::NoSpace => return Err(Error::FileTooLarge),
+                Alloc::Outdated => {
+                    log::debug!("Meta of {:?} may be outdated by sync, re-fetch", item_id);
+                }
+            }
+        }
+    }
+
+    /// Set the mtime to upload with if the file has pending changes, restarting the delay of its
+    /// upload. Return `false` if it's not dirty, where the mtime should be set in remote directly.
+    pub async fn set_pending_mtime(&self, item_id: &ItemId, mtime: SystemTime) -> bool {
+        // Not a content lookup, so it's not counted in cache hits.
+        let file = match self
+            .disk_cache
+            .as_ref()
+            .and_then(|cache| cache.cache.lock().unwrap().get_mut(item_id).cloned())
+        {
+            Some(file) => file,
+            None => return false,
+        };
+        let mut guard = file.state.lock().await;
+        if !matches!(guard.status, FileCacheStatus::Dirty { .. }) {
+            return false;
+        }
+        // An upload already in progress is superseded, or the mtime would be overwritten by it.
+        file.queue_upload(
+            &mut guard,
+            mtime,
             self.onedrive.clone(),
-            self.event_tx.clone(),
             self.client.clone(),
-        )? {
-            Some(_) => Ok(()),
-            None => Err(Error::FileTooLarge),
-        }
+            self.event_tx.clone(),
+            self.config.upload.clone(),
+        );
+        true
     }
 
     pub async fn close(&self, fh: u64) -> Result<()> {
-        if self.handles.remove(Self::fh_to_key(fh)) {
-            Ok(())
-        } else {
-            Err(Error::InvalidHandle(fh))
+        match self.handles.take(Self::fh_to_key(fh)) {
+            Some(handle) => {
+                self.open_handles.fetch_sub(1, Ordering::Relaxed);
+                if let File::Uploading(state) = handle.file.into_inner().unwrap() {
+                    state.lock().await.abort(&self.client).await?;
+                }
+                Ok(())
+            }
+            None => Err(Error::InvalidHandle(fh)),
         }
     }
 
+    /// Read from a handle. Reads smaller than `read_window_size` fetch a whole window, and
+    /// following reads inside it are served without touching the file state.
     pub async fn read(&self, fh: u64, offset: u64, size: usize) -> Result<impl AsRef<[u8]>> {
-        let file = self
-            .handles
-            .get(Self::fh_to_key(fh))
-            .ok_or(Error::InvalidHandle(fh))?
-            .clone();
-        match file {
-            File::Streaming(state) => state.lock().await.read(offset, size).await,
-            File::Cached(state) => FileCache::read(&state, offset, size).await,
+        let file = self.get_handle(fh)?;
+        let window_size = match &file {
+            // The chunk buffer should be able to hold the whole window.
+            File::Streaming(..) => self
+                .config
+                .download
+                .read_window_size
+                .min(self.config.download.stream_ring_buffer_size),
+            File::Cached(_) | File::Blocks(_) => self.config.download.read_window_size,
+            File::Uploading(_) => return Err(Error::ReadDuringUpload),
+        };
+        let version = file.read_version();
+        if window_size <= size {
+            return self.read_file(fh, file, offset, size).await;
         }
+
+        let cached = self
+            .handle(fh)?
+            .read_window
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|window| window.get(offset, size, version));
+        if let Some(data) = cached {
+            return Ok(data);
+        }
+        let data = self.read_file(fh, file, offset, window_size).await?;
+        let ret = data.slice(..size.min(data.len()));
+        *self.handle(fh)?.read_window.lock().unwrap() = Some(ReadWindow {
+            offset,
+            eof: data.len() < window_size,
+            data,
+            version,
+        });
+        Ok(ret)
     }
 
-    /// Write to cached file. Returns item id and file size after the write.
-    pub async fn write(&self, fh: u64, offset: u64, data: &[u8]) -> Result<UpdatedFileAttr> {
-        let file = self
-            .handles
-            .get(Self::fh_to_key(fh))
-            .ok_or(Error::InvalidHandle(fh))?
-            .clone();
+    async fn read_file(&self, fh: u64, file: File, offset: u64, size: usize) -> Result<Bytes> {
         match file {
-            File::Streaming { .. } => panic!("Cannot stream in write mode"),
+            File::Streaming(state, _) => state.lock().await.read(offset, size).await,
             File::Cached(state) => {
-                FileCache::write(
+                if let Some(data) = FileCache::priority_read(
                     &state,
                     offset,
-                    data,
-                    self.event_tx.clone(),
-                    self.onedrive.clone(),
-                    self.client.clone(),
-                    self.config.upload.clone(),
+                    size,
+                    &self.client,
+                    &self.config.download,
                 )
                 .await
+                {
+                    return Ok(data);
+                }
+                self.read_cached(fh, File::Cached(state), offset, size)
+                    .await
             }
+            File::Blocks(_) => self.read_cached(fh, file, offset, size).await,
+            File::Uploading(_) => Err(Error::ReadDuringUpload),
         }
     }
 
-    pub async fn flush_file(&self, item_id: &ItemId) -> Result<()> {
-        if let Some(cache) = &self.disk_cache {
-            if let Some(file) = cache.get(item_id) {
-                let mut guard = file.state.lock().await;
-                match guard.status {
-                    FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
-                    FileCacheStatus::Available | FileCacheStatus::Invalidated => return Ok(()),
-                    FileCacheStatus::Downloading { .. } => {
-                        let mut rx = guard.available_size.clone();
-                        drop(guard);
-                        while rx.changed().await.is_ok() {}
-                        guard = file.state.lock().await;
-                    }
-                    FileCacheStatus::Dirty { .. } => {}
-                }
-                loop {
-                    let (flush_tx, mut done_rx) = match &mut guard.status {
-                        FileCacheStatus::Downloading { .. } => unreachable!(),
-                        FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
-                        FileCacheStatus::Invalidated | FileCacheStatus::Available => return Ok(()),
-                        FileCacheStatus::Dirty {
-                            flush_tx, done_rx, ..
-                        } => (flush_tx.take(), done_rx.clone()),
-                    };
-                    drop(guard);
-                    if let Some(flush_tx) = flush_tx {
-                        let _ = flush_tx.send(());
-                    }
-                    while done_rx.changed().await.is_ok() {}
-                    // May be canceled by another modification during the upload.
-                    if *done_rx.borrow() {
-                        return Ok(());
-                    }
-                    guard = file.state.lock().await;
-                }
+    async fn read_cached(&self, fh: u64, file: File, offset: u64, size: usize) -> Result<Bytes> {
+        match self.read_once(&file, offset, size).await {
+            Err(Error::Invalidated)
+                if self.config.disk_cache.on_invalidate_during_read
+                    == InvalidatedHandlePolicy::Retry =>
+            {
+                let item_id = match &file {
+                    File::Cached(state) => state.item_id.clone(),
+                    File::Blocks(state) => state.item_id.clone(),
+                    File::Streaming(..) | File::Uploading(_) => unreachable!(),
+                };
+                log::info!(
+                    "Cache of {:?} is invalidated during read, re-open it",
+                    item_id,
+                );
+                let file = self.open_inner(&item_id, None, false).await?;
+                self.set_handle(fh, file.clone())?;
+                self.read_once(&file, offset, size).await
             }
+            ret => ret,
         }
-        Ok(())
     }
 
-    pub async fn sync_items(&self, items: &[DriveItem]) {
-        if let Some(cache) = &self.disk_cache {
-            cache.sync_items(items).await;
+    async fn read_once(&self, file: &File, offset: u64, size: usize) -> Result<Bytes> {
+        match file {
+            File::Streaming(state, _) => state.lock().await.read(offset, size).await,
+            File::Cached(state) => FileCache::read(state, offset, size).await,
+            File::Blocks(state) => {
+                BlockFile::read(
+                    state,
+                    offset,
+                    size,
+                    &self.onedrive,
+                    &self.client,
+                    &self.config.download,
+                )
+                .await
+            }
+            File::Uploading(_) => unreachable!(),
         }
     }
-}
 
-#[derive(Debug, Clone)]
-enum File {
-    Streaming(Arc<Mutex<FileStreamState>>),
-    Cached(Arc<FileCache>),
-}
+    /// Write to cached file. Returns item id and file size after the write.
+    /// Handles not opened for write fail with `Error::NotOpenedForWrite`.
+    pub async fn write(&self, fh: u64, offset: u64, data: &[u8]) -> Result<UpdatedFileAttr> {
+        let file = self.get_write_handle(fh)?;
+        match (self.write_once(fh, &file, offset, data).await, &file) {
+            (Err(Error::Invalidated), File::Cached(state))
+                if self.config.disk_cache.on_invalidate_during_write
+                    == InvalidatedHandlePolicy::Retry =>
+            {
+                log::info!(
+                    "Cache of {:?} is invalidated during write, re-open it",
+                    state.item_id,
+                );
+                let file = self.open_inner(&state.item_id, None, true).await?;
+                self.set_handle(fh, file.clone())?;
+                self.write_once(fh, &file, offset, data).await
+            }
+            (ret, _) => ret,
+        }
+    }
 
-#[derive(Debug)]
-struct FileStreamState {
+    async fn write_once(
+        &self,
+        fh: u64,
+        file: &File,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<UpdatedFileAttr> {
+        match file {
+            // A write handle may fall back to streaming after re-opened due to invalidation.
+            File::Streaming { .. } | File::Blocks(_) => Err(Error::NotOpenedForWrite(fh)),
+            File::Uploading(state) => {
+                let mut guard = state.lock().await;
+                let err = match guard
+                    .write(
+                        offset,
+                        data,
+                        &self.client,
+                        &self.event_tx,
+                        &self.config.upload,
+                    )
+                    .await
+                {
+                    Err(err @ Error::NonsequentialWrite { .. }) => err,
+                    ret => return ret,
+                };
+                let file = match self.fallback_to_cache(fh, &mut guard).await? {
+                    Some(file) => file,
+                    None => return Err(err),
+                };
+                drop(guard);
+                FileCache::write(
+                    &file,
+                    offset,
+                    data,
+                    self.event_tx.clone(),
+                    self.onedrive.clone(),
+                    self.client.clone(),
+                    self.config.upload.clone(),
+                )
+                .await
+            }
+            File::Cached(state) => {
+                FileCache::write(
+                    state,
+                    offset,
+                    data,
+                    self.event_tx.clone(),
+                    self.onedrive.clone(),
+                    self.client.clone(),
+                    self.config.upload.clone(),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Switch a streaming upload handle back to a cache file of the same size, with the content
+    /// written so far. Return `None` if it's not possible since some parts are already uploaded,
+    /// or the file cannot fit in cache.
+    async fn fallback_to_cache(
+        &self,
+        fh: u64,
+        state: &mut FileUploadState,
+    ) -> Result<Option<Arc<FileCache>>> {
+        let cache = match &self.disk_cache {
+            Some(cache) => cache,
+            None => return Ok(None),
+        };
+        if state.pos != 0
+            || state.sess.is_none()
+            || cache.config.disk_cache.max_cached_file_size < state.file_size
+        {
+            return Ok(None);
+        }
+        log::info!(
+            "Nonsequential write to streaming upload {:?}, fall back to cache",
+            state.item_id,
+        );
+        let sess = state.sess.take().unwrap();
+        if let Err(err) = sess.delete(&self.client).await {
+            log::error!(
+                "Failed to delete upload session of {:?}: {}",
+                state.item_id,
+                err,
+            );
+        }
+        let written = std::mem::take(&mut state.buf);
+
+        let file = cache
+            .insert_empty(state.item_id.clone(), state.c_tag.clone())
+            .await?;
+        self.truncate_file(&state.item_id, state.file_size, state.mtime)
+            .await?;
+        if !written.is_empty() {
+            FileCache::write(
+                &file,
+                0,
+                &written,
+                self.event_tx.clone(),
+                self.onedrive.clone(),
+                self.client.clone(),
+                self.config.upload.clone(),
+            )
+            .await?;
+        }
+        self.set_handle(fh, File::Cached(file.clone()))?;
+        Ok(Some(file))
+    }
+
+    /// Force the pending upload of a handle to start immediately and wait for its completion.
+    /// It's a no-op for streaming handles.
+    pub async fn fsync(&self, fh: u64) -> Result<()> {
+        match self.get_handle(fh)? {
+            File::Cached(file) => FileCache::flush(&file).await,
+            File::Streaming(..) | File::Blocks(_) | File::Uploading(_) => Ok(()),
+        }
+    }
+
+    /// Start all pending uploads immediately and wait for them, for at most `exit_flush_timeout`.
+    pub async fn flush_all(&self) {
+        let cache = match &self.disk_cache {
+            Some(cache) => cache,
+            None => return,
+        };
+        // Dirty files may be evicted from `cache` but still alive.
+        let files = cache
+            .live_files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        let mut uploads = JoinSet::new();
+        for file in files {
+            if matches!(
+                file.state.lock().await.status,
+                FileCacheStatus::Dirty { .. }
+            ) {
+                uploads.spawn(async move { (file.item_id.clone(), FileCache::flush(&file).await) });
+            }
+        }
+        if uploads.is_empty() {
+            return;
+        }
+
+        log::info!("Flushing {} files with pending uploads", uploads.len());
+        let wait_all = async {
+            while let Some(ret) = uploads.join_next().await {
+                if let (item_id, Err(err)) = ret.expect("Flush panicked") {
+                    log::error!("Failed to flush {:?}: {}", item_id, err);
+                }
+            }
+        };
+        let timeout = self.config.upload.exit_flush_timeout;
+        if timeout.is_zero() {
+            wait_all.await;
+        } else if time::timeout(timeout, wait_all).await.is_err() {
+            log::error!(
+                "{} uploads are not finished in {:?}, their changes are lost",
+                uploads.len(),
+                timeout,
+            );
+        }
+    }
+
+    /// Statistics of the disk cache, or the in-memory write cache if disk cache is disabled.
+    /// Return `None` if neither is enabled.
+    pub async fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.disk_cache.as_ref()?.stats().await)
+    }
+
+    pub async fn sync_items(&self, items: &[DriveItem]) {
+        self.sync_streams(items);
+        if let Some(cache) = &self.disk_cache {
+            cache.sync_items(items).await;
+        }
+    }
+
+    /// Invalidate opened streaming files changed or deleted in remote side. Their download URL
+    /// may still serve the old content, so following reads fail instead.
+    fn sync_streams(&self, items: &[DriveItem]) {
+        let mut streams = self.streams.lock().unwrap();
+        for item in items {
+            if ItemKind::of(item) != Some(ItemKind::File) {
+                continue;
+            }
+            let id = match &item.id {
+                Some(id) => id,
+                None => {
+                    log::warn!("Skip streaming sync of item without id: {:?}", item);
+                    continue;
+                }
+            };
+            let c_tag = content_tag(item).filter(|_| item.deleted.is_none());
+            for (item_id, old_c_tag, invalidated) in streams.iter() {
+                if item_id != id || c_tag.as_ref() == Some(old_c_tag) {
+                    continue;
+                }
+                if let Some(invalidated) = invalidated.upgrade() {
+                    log::debug!("Streaming file {:?} is outdated or deleted", id);
+                    invalidated.store(true, Ordering::Release);
+                }
+            }
+        }
+        streams.retain(|(_, _, invalidated)| {
+            invalidated
+                .upgrade()
+                .is_some_and(|invalidated| !invalidated.load(Ordering::Acquire))
+        });
+    }
+}
+
+struct Handle {
+    file: SyncMutex<File>,
+    /// Whether it's opened for write. Read is always allowed.
+    write: bool,
+    read_window: SyncMutex<Option<ReadWindow>>,
+    _open: InFlight,
+}
+
+/// Recently read data of a handle, serving following small reads.
+struct ReadWindow {
+    offset: u64,
+    data: Bytes,
+    /// Whether `data` reaches the end of file.
+    eof: bool,
+    /// `File::read_version` when it's read.
+    version: u64,
+}
+
+impl ReadWindow {
+    fn get(&self, offset: u64, size: usize, version: u64) -> Option<Bytes> {
+        if version != self.version
+            || offset < self.offset
+            || self.offset + (self.data.len() as u64) < offset
+        {
+            return None;
+        }
+        let start = (offset - self.offset) as usize;
+        let end = start.saturating_add(size);
+        if end <= self.data.len() {
+            Some(self.data.slice(start..end))
+        } else if self.eof {
+            Some(self.data.slice(start..))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum File {
+    /// With `FileStreamState::invalidated`, which is checked without locking the state.
+    Streaming(Arc<Mutex<FileStreamState>>, Arc<AtomicBool>),
+    Cached(Arc<FileCache>),
+    Blocks(Arc<BlockFile>),
+    Uploading(Arc<Mutex<FileUploadState>>),
+}
+
+impl File {
+    fn streaming(state: FileStreamState) -> Self {
+        let invalidated = state.invalidated.clone();
+        File::Streaming(Arc::new(Mutex::new(state)), invalidated)
+    }
+
+    /// Changes once data read before is no longer valid, so read windows are dropped.
+    fn read_version(&self) -> u64 {
+        match self {
+            File::Streaming(_, invalidated) => u64::from(invalidated.load(Ordering::Acquire)),
+            File::Cached(state) => state.version.load(Ordering::Acquire),
+            File::Blocks(state) => u64::from(state.invalidated.load(Ordering::Acquire)),
+            // Never read.
+            File::Uploading(_) => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FileStreamState {
+    item_id: ItemId,
+    c_tag: Tag,
     file_size: u64,
+    /// Receives the corrected size if the download disagrees with the metadata.
+    size_rx: Option<oneshot::Receiver<u64>>,
+    event_tx: mpsc::Sender<UpdateEvent>,
     buf_start_pos: u64,
-    buf: RingBuf,
+    buf: ChunkBuf,
     rx: mpsc::Receiver<Bytes>,
+    /// Chunks in `rx` hold budget of their length, which is released after being consumed.
+    buffer_budgets: Vec<Arc<BufferBudget>>,
+    /// Taken to retrieve the reason once `rx` is closed unexpectedly.
+    download_task: Option<JoinHandle<DownloadResult>>,
+    failure: Option<DownloadFailure>,
+    /// Set by `FilePool::sync_items` once the file is changed or deleted in remote side.
+    invalidated: Arc<AtomicBool>,
 }
 
+/// Byte budget of buffered chunks of streaming downloads, either shared by all or per download.
 #[derive(Debug)]
-struct RingBuf {
-    v: Vec<u8>,
-    l: usize,
-    r: usize,
+struct BufferBudget {
+    sem: Semaphore,
+    capacity: usize,
 }
 
-impl RingBuf {
+impl BufferBudget {
     fn new(capacity: usize) -> Self {
-        let v = vec![0u8; capacity.checked_add(1).unwrap()];
-        Self { v, l: 0, r: 0 }
+        // The max permits `Semaphore` supports.
+        let capacity = capacity.min(usize::MAX >> 3);
+        Self {
+            sem: Semaphore::new(capacity),
+            capacity,
+        }
     }
 
-    fn capacity(&self) -> usize {
-        self.v.len() - 1
+    // Capped to the capacity, or a large chunk would block forever.
+    fn permits(&self, len: usize) -> u32 {
+        len.min(self.capacity).try_into().unwrap_or(u32::MAX)
     }
 
-    fn len(&self) -> usize {
-        if self.l <= self.r {
-            self.r - self.l
-        } else {
-            self.r + self.v.len() - self.l
+    async fn acquire(&self, len: usize) {
+        self.sem
+            .acquire_many(self.permits(len))
+            .await
+            .expect("Never closed")
+            .forget();
+    }
+
+    fn release(&self, len: usize) {
+        self.sem.add_permits(self.permits(len) as usize);
+    }
+}
+
+/// Chunks received by a streaming download, kept without copying.
+#[derive(Debug)]
+struct ChunkBuf {
+    chunks: VecDeque<Bytes>,
+    len: usize,
+    /// At least these bytes are kept, besides the latest chunk.
+    capacity: usize,
+}
+
+impl ChunkBuf {
+    fn new(capacity: usize) -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            len: 0,
+            capacity,
         }
     }
 
-    fn slice(&self, range: std::ops::Range<usize>) -> (&[u8], &[u8]) {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Get bytes in `range`, which is a slice of the chunk if it's inside a single one.
+    /// Otherwise, bytes are copied from all chunks it covers.
+    fn slice(&self, range: Range<usize>) -> Bytes {
         assert!(range.start <= range.end && range.end <= self.len());
-        let (start, end, l, wrap) = (range.start, range.end, self.l, self.v.len());
-        if l + end <= wrap {
-            (&self.v[(l + start)..(l + end)], &[])
-        } else if wrap < l + start {
-            (&self.v[(l + start - wrap)..(l + end - wrap)], &[])
-        } else {
-            (&self.v[(l + start)..], &self.v[..(l + end - wrap)])
+        let mut ret: Option<BytesMut> = None;
+        let mut start = 0;
+        for chunk in &self.chunks {
+            let end = start + chunk.len();
+            if range.start < end && start < range.end {
+                let part = chunk.slice(range.start.max(start) - start..range.end.min(end) - start);
+                if ret.is_none() && range.end <= end {
+                    return part;
+                }
+                ret.get_or_insert_with(|| BytesMut::with_capacity(range.len()))
+                    .extend_from_slice(&part);
+            }
+            start = end;
         }
+        ret.map_or_else(Bytes::new, BytesMut::freeze)
     }
 
     /// Return truncated bytes from left.
-    fn feed(&mut self, data: &[u8]) -> usize {
-        assert!(data.len() <= self.capacity());
-        let truncate = (self.len() + data.len()).saturating_sub(self.capacity());
-        let wrap = self.v.len();
-        if self.l + truncate < wrap {
-            self.l += truncate;
-        } else {
-            self.l = self.l + truncate - wrap;
-        }
-        if self.r + data.len() < wrap {
-            self.v[self.r..(self.r + data.len())].copy_from_slice(data);
-            self.r += data.len();
-        } else {
-            let rest = wrap - self.r;
-            self.v[self.r..].copy_from_slice(&data[..rest]);
-            self.v[..(data.len() - rest)].copy_from_slice(&data[rest..]);
-            self.r = data.len() - rest;
+    fn feed(&mut self, chunk: Bytes) -> usize {
+        self.len += chunk.len();
+        self.chunks.push_back(chunk);
+        let mut truncate = 0;
+        while 1 < self.chunks.len() && self.capacity <= self.len - self.chunks[0].len() {
+            let front = self.chunks.pop_front().unwrap();
+            self.len -= front.len();
+            truncate += front.len();
         }
         truncate
     }
 }
 
 impl FileStreamState {
-    fn fetch(meta: &RemoteFileMeta, client: reqwest::Client, config: DownloadConfig) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn fetch(
+        item_id: &ItemId,
+        meta: &RemoteFileMeta,
+        onedrive: ManagedOnedrive,
+        client: reqwest::Client,
+        shared_budget: Arc<BufferBudget>,
+        event_tx: mpsc::Sender<UpdateEvent>,
+        config: DownloadConfig,
+    ) -> Self {
+        let mut buffer_budgets = vec![shared_budget];
+        if config.max_buffered_bytes != 0 {
+            buffer_budgets.insert(0, Arc::new(BufferBudget::new(config.max_buffered_bytes)));
+        }
+        let (size_tx, size_rx) = oneshot::channel();
         let (tx, rx) = mpsc::channel(config.stream_buffer_chunks);
-        let buf = RingBuf::new(config.stream_ring_buffer_size);
-        tokio::spawn(download_thread(
+        let buf = ChunkBuf::new(config.stream_ring_buffer_size);
+        let download_task = tokio::spawn(download_thread(
             meta.size,
+            0,
             meta.download_url.clone(),
+            UrlRefresher::new(onedrive, item_id, meta),
+            size_tx,
             tx,
+            buffer_budgets.clone(),
             client,
             config,
         ));
         Self {
+            item_id: item_id.clone(),
+            c_tag: meta.c_tag.clone(),
             file_size: meta.size,
+            size_rx: Some(size_rx),
+            event_tx,
             buf_start_pos: 0,
             buf,
             rx,
+            buffer_budgets,
+            download_task: Some(download_task),
+            failure: None,
+            invalidated: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn release_budgets(&self, len: usize) {
+        for budget in &self.buffer_budgets {
+            budget.release(len);
+        }
+    }
+
+    async fn download_error(&mut self) -> Error {
+        if let Some(task) = self.download_task.take() {
+            self.failure = task.await.ok().and_then(|ret| ret.err());
+        }
+        match self.failure.clone() {
+            Some(failure) => failure.into(),
+            None => Error::DownloadFailed,
         }
     }
 
     async fn read(&mut self, offset: u64, size: usize) -> Result<Bytes> {
+        if self.invalidated.load(Ordering::Acquire) {
+            return Err(Error::Invalidated);
+        }
+        if let Some(size_rx) = self.size_rx.take() {
+            // Closed without value if the size is confirmed, or the download failed.
+            if let Ok(file_size) = size_rx.await {
+                self.file_size = file_size;
+                let _ = self
+                    .event_tx
+                    .send(UpdateEvent::CorrectSize {
+                        item_id: self.item_id.clone(),
+                        c_tag: self.c_tag.clone(),
+                        size: file_size,
+                    })
+                    .await;
+            }
+        }
         let size = (self.file_size.saturating_sub(offset)).min(size as u64) as usize;
         if size == 0 {
             return Ok(Bytes::new());
@@ -471,9 +1473,10 @@ impl FileStreamState {
         while self.buf_start_pos + (self.buf.len() as u64) < end {
             let chunk = match self.rx.recv().await {
                 Some(chunk) => chunk,
-                None => return Err(Error::DownloadFailed),
+                None => return Err(self.download_error().await),
             };
-            let advance = self.buf.feed(&*chunk);
+            self.release_budgets(chunk.len());
+            let advance = self.buf.feed(chunk);
             self.buf_start_pos += advance as u64;
         }
 
@@ -486,748 +1489,4818 @@ impl FileStreamState {
         }
 
         let start = (offset - self.buf_start_pos) as usize;
-        let (lhs, rhs) = self.buf.slice(start..(start + size));
-        let mut ret = BytesMut::with_capacity(size);
-        ret.extend_from_slice(lhs);
-        ret.extend_from_slice(rhs);
-        Ok(ret.freeze())
+        Ok(self.buf.slice(start..(start + size)))
+    }
+}
+
+impl Drop for FileStreamState {
+    fn drop(&mut self) {
+        // Release permits of chunks never consumed.
+        self.rx.close();
+        while let Ok(chunk) = self.rx.try_recv() {
+            self.release_budgets(chunk.len());
+        }
     }
 }
 
+/// Download the file from `download_url` starting at `start_pos`, and send chunks to `tx`.
+///
+/// Each chunk acquires budget of its length from all `buffer_budgets` before being sent.
+/// The receiver is responsible to release them after consuming the chunk.
+///
+/// The download is aborted with `DownloadFailure::Timeout` if it takes longer than
+/// `max_total_duration`, or with `DownloadFailure::RetryExhausted` if retries are exhausted.
+///
+/// If the content size disagrees with `file_size` and `download.size_mismatch` trusts the download,
+/// the actual size is sent to `size_tx` before any chunk. Otherwise `size_tx` is dropped.
+#[allow(clippy::too_many_arguments)]
 async fn download_thread(
     file_size: u64,
+    start_pos: u64,
+    download_url: String,
+    refresher: UrlRefresher,
+    size_tx: oneshot::Sender<u64>,
+    tx: mpsc::Sender<Bytes>,
+    buffer_budgets: Vec<Arc<BufferBudget>>,
+    client: reqwest::Client,
+    config: DownloadConfig,
+) -> DownloadResult {
+    if let Err(host) = config.check_host(&download_url) {
+        log::error!("Refused to download from disallowed host {:?}", host);
+        return Err(DownloadFailure::HostNotAllowed(host));
+    }
+    let max_total_duration = config.max_total_duration;
+    let waits = ThrottleWaits::default();
+    let excluded = config.exclude_throttle_waits.then_some(&waits);
+    let download = download_file(
+        file_size,
+        start_pos,
+        None,
+        download_url,
+        refresher,
+        Some(size_tx),
+        tx,
+        buffer_budgets,
+        client,
+        config.clone(),
+        waits.clone(),
+    );
+    limit_duration(file_size, max_total_duration, excluded, download).await
+}
+
+/// Like `download_thread`, but for filling the cache. Chunks are sent with their offsets.
+///
+/// If `download.segments` is more than 1, the file is split into segments of
+/// `download.segment_size`, which are downloaded concurrently and may complete out of order.
+/// Segmented downloads cannot be resized on size mismatch.
+#[allow(clippy::too_many_arguments)]
+async fn download_segments_thread(
+    file_size: u64,
+    start_pos: u64,
+    download_url: String,
+    refresher: UrlRefresher,
+    size_tx: oneshot::Sender<u64>,
+    tx: mpsc::Sender<(u64, Bytes)>,
+    wanted: Option<Arc<AtomicU64>>,
+    client: reqwest::Client,
+    config: DownloadConfig,
+) -> DownloadResult {
+    if let Err(host) = config.check_host(&download_url) {
+        log::error!("Refused to download from disallowed host {:?}", host);
+        return Err(DownloadFailure::HostNotAllowed(host));
+    }
+    let max_total_duration = config.max_total_duration;
+    let waits = ThrottleWaits::default();
+    let excluded = config.exclude_throttle_waits.then_some(&waits);
+    let segment_size = config.segment_size.max(1);
+    let segment_cnt = (file_size.saturating_sub(start_pos)).div_ceil(segment_size);
+    if config.segments <= 1 || segment_cnt <= 1 {
+        let download = download_segment(
+            file_size,
+            start_pos,
+            None,
+            download_url,
+            refresher,
+            Some(size_tx),
+            tx,
+            client,
+            config.clone(),
+            waits.clone(),
+        );
+        return limit_duration(file_size, max_total_duration, excluded, download).await;
+    }
+
+    log::debug!(
+        "Start downloading from {} ({} bytes) in {} segments",
+        start_pos,
+        file_size,
+        segment_cnt,
+    );
+    let worker_waits = waits.clone();
+    let download = async move {
+        let queue = Arc::new(SyncMutex::new(SegmentQueue {
+            taken: vec![false; segment_cnt as usize],
+            next: 0,
+        }));
+        let mut workers = JoinSet::new();
+        for _ in 0..(config.segments as u64).min(segment_cnt) {
+            let (queue, wanted, download_url, tx) = (
+                queue.clone(),
+                wanted.clone(),
+                download_url.clone(),
+                tx.clone(),
+            );
+            let (refresher, client, config, waits) = (
+                refresher.clone(),
+                client.clone(),
+                config.clone(),
+                worker_waits.clone(),
+            );
+            workers.spawn(async move {
+                loop {
+                    let wanted = wanted
+                        .as_ref()
+                        .map(|wanted| wanted.swap(u64::MAX, Ordering::Relaxed))
+                        .filter(|&offset| start_pos <= offset && offset < file_size)
+                        .map(|offset| ((offset - start_pos) / segment_size) as usize);
+                    let idx = match queue.lock().unwrap().take(wanted) {
+                        Some(idx) if !tx.is_closed() => idx as u64,
+                        _ => return Ok(()),
+                    };
+                    if wanted == Some(idx as usize) {
+                        log::debug!("Download segment {} first for a waiting read", idx);
+                    }
+                    let start = start_pos + idx * segment_size;
+                    let end = (start + segment_size).min(file_size);
+                    download_segment(
+                        file_size,
+                        start,
+                        Some(end),
+                        download_url.clone(),
+                        refresher.clone(),
+                        None,
+                        tx.clone(),
+                        client.clone(),
+                        config.clone(),
+                        waits.clone(),
+                    )
+                    .await?;
+                }
+            });
+        }
+        // Other workers are aborted when dropped on failure.
+        while let Some(ret) = workers.join_next().await {
+            ret.expect("Download worker panicked")?;
+        }
+        Ok(())
+    };
+    limit_duration(file_size, max_total_duration, excluded, download).await
+}
+
+/// Segments of a segmented download, taken by workers in order, except that the one wanted by
+/// a waiting read goes first.
+struct SegmentQueue {
+    taken: Vec<bool>,
+    next: usize,
+}
+
+impl SegmentQueue {
+    fn take(&mut self, wanted: Option<usize>) -> Option<usize> {
+        let idx = match wanted.filter(|&idx| !self.taken[idx]) {
+            Some(idx) => idx,
+            None => {
+                while self.next < self.taken.len() && self.taken[self.next] {
+                    self.next += 1;
+                }
+                self.next
+            }
+        };
+        *self.taken.get_mut(idx)? = true;
+        Some(idx)
+    }
+}
+
+/// Download a range of the file, and send chunks with their offsets to `tx`.
+#[allow(clippy::too_many_arguments)]
+async fn download_segment(
+    file_size: u64,
+    start_pos: u64,
+    end_pos: Option<u64>,
     download_url: String,
+    refresher: UrlRefresher,
+    size_tx: Option<oneshot::Sender<u64>>,
+    tx: mpsc::Sender<(u64, Bytes)>,
+    client: reqwest::Client,
+    config: DownloadConfig,
+    waits: ThrottleWaits,
+) -> DownloadResult {
+    let (chunk_tx, mut chunk_rx) = mpsc::channel(1);
+    let download = download_file(
+        file_size,
+        start_pos,
+        end_pos,
+        download_url,
+        refresher,
+        size_tx,
+        chunk_tx,
+        Vec::new(),
+        client,
+        config,
+        waits,
+    );
+    let forward = async {
+        let mut pos = start_pos;
+        while let Some(chunk) = chunk_rx.recv().await {
+            let len = chunk.len() as u64;
+            if tx.send((pos, chunk)).await.is_err() {
+                // Stops the download.
+                break;
+            }
+            pos += len;
+        }
+        drop(chunk_rx);
+    };
+    tokio::join!(download, forward).0
+}
+
+/// Abort `download` after `max_total_duration`, not counting `excluded` waits if any.
+async fn limit_duration(
+    file_size: u64,
+    max_total_duration: Duration,
+    excluded: Option<&ThrottleWaits>,
+    download: impl Future<Output = DownloadResult>,
+) -> DownloadResult {
+    if max_total_duration.is_zero() {
+        return download.await;
+    }
+    let start = Instant::now();
+    tokio::pin!(download);
+    loop {
+        let waited = excluded.map_or(Duration::ZERO, ThrottleWaits::total);
+        match time::timeout_at((start + max_total_duration + waited).into(), &mut download).await {
+            Ok(ret) => return ret,
+            // Throttled in the meantime.
+            Err(_) if excluded.map_or(Duration::ZERO, ThrottleWaits::total) != waited => {}
+            Err(_) => {
+                log::error!(
+                    "Download ({} bytes) aborted after max total duration {:?}, excluding {:?} throttled",
+                    file_size,
+                    max_total_duration,
+                    waited,
+                );
+                return Err(DownloadFailure::Timeout);
+            }
+        }
+    }
+}
+
+/// Time a download spent waiting for throttled requests. Overlapping waits of concurrent
+/// segments are counted once.
+#[derive(Debug, Clone, Default)]
+struct ThrottleWaits(Arc<SyncMutex<ThrottleWaitsState>>);
+
+#[derive(Debug, Default)]
+struct ThrottleWaitsState {
+    total: Duration,
+    until: Option<Instant>,
+}
+
+impl ThrottleWaits {
+    async fn sleep(&self, delay: Duration) {
+        {
+            let now = Instant::now();
+            let mut state = self.0.lock().unwrap();
+            // Counted up to here already.
+            let counted = state.until.map_or(now, |until| until.max(now));
+            state.total += (now + delay).saturating_duration_since(counted);
+            state.until = Some(counted.max(now + delay));
+        }
+        time::sleep(delay).await;
+    }
+
+    fn total(&self) -> Duration {
+        self.0.lock().unwrap().total
+    }
+}
+
+type DownloadResult = std::result::Result<(), DownloadFailure>;
+
+/// Why a download is terminated before completion.
+#[derive(Debug, Clone)]
+enum DownloadFailure {
+    HostNotAllowed(String),
+    /// The download URL is no longer valid, usually because the file is deleted.
+    Gone,
+    Timeout,
+    RetryExhausted {
+        tries: usize,
+        last_error: String,
+    },
+    /// The content is changed in remote side during downloading.
+    Changed,
+    /// The size of the content disagrees with the expected one.
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl From<DownloadFailure> for Error {
+    fn from(failure: DownloadFailure) -> Self {
+        match failure {
+            DownloadFailure::HostNotAllowed(host) => Error::DownloadHostNotAllowed(host),
+            DownloadFailure::Gone => Error::Deleted,
+            DownloadFailure::Changed => Error::Invalidated,
+            DownloadFailure::Timeout => Error::DownloadTimeout,
+            DownloadFailure::RetryExhausted { tries, last_error } => {
+                Error::DownloadRetryExhausted { tries, last_error }
+            }
+            DownloadFailure::SizeMismatch { expected, actual } => {
+                Error::DownloadSizeMismatch { expected, actual }
+            }
+        }
+    }
+}
+
+/// Download `start_pos..end_pos`, or until the end of file if `end_pos` is `None`.
+#[allow(clippy::too_many_arguments)]
+async fn download_file(
+    mut file_size: u64,
+    start_pos: u64,
+    end_pos: Option<u64>,
+    mut download_url: String,
+    refresher: UrlRefresher,
+    mut size_tx: Option<oneshot::Sender<u64>>,
     tx: mpsc::Sender<Bytes>,
+    buffer_budgets: Vec<Arc<BufferBudget>>,
     client: reqwest::Client,
     config: DownloadConfig,
-) {
-    let mut pos = 0u64;
+    waits: ThrottleWaits,
+) -> DownloadResult {
+    let mut pos = start_pos;
+    let mut end = end_pos.unwrap_or(file_size);
+    let start_time = Instant::now();
+    let transfer = METRICS.start_download(&refresher.item_id);
 
-    log::debug!("Start downloading ({} bytes)", file_size);
+    log::debug!(
+        "Start downloading from {} to {} ({} bytes)",
+        pos,
+        end,
+        file_size,
+    );
 
-    while pos < file_size {
-        let mut tries = 0;
+    // Failed requests and broken streams in total.
+    let mut tries = 0;
+    while pos < end {
+        let range = match end_pos {
+            Some(end) => format!("bytes={}-{}", pos, end - 1),
+            None => format!("bytes={}-", pos),
+        };
         let mut resp = loop {
             let ret: anyhow::Result<_> = client
                 .get(&download_url)
                 // We already have timeout for each chunk.
                 // FIXME: Use `Duration::MAX`.
                 .timeout(Duration::from_secs(u64::MAX))
-                .header(header::RANGE, format!("bytes={}-", pos))
+                .header(header::RANGE, &range)
                 .send()
                 .await
                 .map_err(|err| err.into())
                 .and_then(|resp| {
-                    if resp.status() != StatusCode::PARTIAL_CONTENT {
-                        anyhow::bail!("Not Partial Content response: {}", resp.status());
+                    let throttled =
+                        throttle_delay(resp.status(), Some(resp.headers()), config.retry_delay);
+                    if let Some(delay) = throttled {
+                        return Err(Throttled(delay).into());
+                    }
+                    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                        return Err(RangeNotSatisfiable(content_range_total(&resp)).into());
+                    }
+                    if !matches!(resp.status(), StatusCode::PARTIAL_CONTENT | StatusCode::OK) {
+                        return Err(UnexpectedStatus(resp.status()).into());
                     }
                     Ok(resp)
                 });
             match ret {
                 Ok(resp) => break resp,
-                Err(err) => {
-                    tries += 1;
+                // Throttling is not a failure of the download, and costs no retry.
+                Err(err) if err.is::<Throttled>() => {
+                    let Throttled(delay) = *err.downcast_ref().unwrap();
+                    log::warn!("Download throttled at {}, retry after {:?}", pos, delay);
+                    waits.sleep(delay).await;
+                }
+                Err(err) if is_gone(&err) => {
+                    log::error!("Download URL is gone, the file may be deleted: {}", err);
+                    return Err(DownloadFailure::Gone);
+                }
+                // The content is shorter than expected, and retrying the same range never helps.
+                Err(err) if err.is::<RangeNotSatisfiable>() => {
+                    let RangeNotSatisfiable(total) = *err.downcast_ref().unwrap();
                     log::error!(
-                        "Error downloading file (try {}/{}): {}",
-                        tries,
+                        "Range from {} is not satisfiable, download size: {:?}, expected size: {}",
+                        pos,
+                        total,
+                        file_size,
+                    );
+                    return Err(DownloadFailure::SizeMismatch {
+                        expected: file_size,
+                        // It ends before `pos` at least.
+                        actual: total.unwrap_or(pos),
+                    });
+                }
+                Err(err) => {
+                    // Pre-authenticated URLs expire after a while.
+                    if is_expired(&err) {
+                        log::info!("Download URL expired at {}, refresh it", pos);
+                        match refresher.refresh().await {
+                            Ok(url) => {
+                                if let Err(host) = config.check_host(&url) {
+                                    log::error!(
+                                        "Refused to download from disallowed host {:?}",
+                                        host
+                                    );
+                                    return Err(DownloadFailure::HostNotAllowed(host));
+                                }
+                                download_url = url;
+                            }
+                            Err(Error::Invalidated) => return Err(DownloadFailure::Changed),
+                            Err(err) => log::error!("Failed to refresh download URL: {}", err),
+                        }
+                    }
+                    tries += 1;
+                    Metrics::add(&METRICS.download_retries, 1);
+                    log::error!(
+                        "Error downloading file (try {}/{}): {}",
+                        tries,
                         config.max_retry,
                         err,
                     );
                     if config.max_retry < tries {
-                        return;
+                        log::error!(
+                            "Download retries exhausted after {} tries, last error: {}",
+                            tries,
+                            err,
+                        );
+                        return Err(DownloadFailure::RetryExhausted {
+                            tries,
+                            last_error: err.to_string(),
+                        });
                     }
                     tokio::time::sleep(config.retry_delay).await;
                 }
             }
         };
 
-        loop {
-            let chunk = match time::timeout(config.chunk_timeout, resp.chunk()).await {
-                Err(_) => {
-                    log::error!("Download stream timeout");
-                    break;
-                }
-                Ok(Err(err)) => {
-                    log::error!("Download stream error: {}", err);
-                    break;
-                }
-                Ok(Ok(None)) => {
-                    if pos != file_size {
-                        log::error!("Download stream ends too early");
-                    }
-                    break;
-                }
-                Ok(Ok(Some(chunk))) => chunk,
-            };
+        // Some CDN edges ignore `Range` and return the whole content, which we need to skip to `pos`.
+        let mut skip = 0;
+        let total = if resp.status() == StatusCode::OK {
+            log::debug!("Range is ignored by server, skip {} bytes", pos);
+            skip = pos;
+            resp.content_length()
+        } else {
+            content_range_total(&resp)
+        };
 
-            pos += chunk.len() as u64;
-            assert!(pos <= file_size);
-            if tx.send(chunk).await.is_err() {
-                log::debug!(
-                    "Download stopped at {} bytes ({} bytes in total)",
-                    pos,
+        // Metadata may lag behind the content.
+        if let Some(total) = total.filter(|&total| total != file_size) {
+            // Only a fresh download can be resized, since downloaded bytes may belong to the old content.
+            if start_pos != 0
+                || size_tx.is_none()
+                || config.size_mismatch == SizeMismatchPolicy::Error
+            {
+                log::error!(
+                    "Download size {} disagrees with the expected size {}",
+                    total,
                     file_size,
                 );
-                return;
+                return Err(DownloadFailure::SizeMismatch {
+                    expected: file_size,
+                    actual: total,
+                });
             }
+            log::warn!(
+                "Download size {} disagrees with the metadata size {}, trust the download",
+                total,
+                file_size,
+            );
+            file_size = total;
+            end = total;
+            // Always before any chunk is sent.
+            let _ = size_tx.take().unwrap().send(total);
         }
-    }
+        // Dropped to indicate the size is confirmed.
+        size_tx = None;
 
-    assert_eq!(pos, file_size);
-    log::debug!("Download finished ({} bytes)", file_size);
-}
+        let stream_error = loop {
+            let mut chunk = match time::timeout(config.chunk_timeout, resp.chunk()).await {
+                Err(_) => break Some("Download stream timeout".to_owned()),
+                Ok(Err(err)) => break Some(format!("Download stream error: {}", err)),
+                Ok(Ok(None)) if pos != end => {
+                    break Some("Download stream ends too early".to_owned())
+                }
+                Ok(Ok(None)) => break None,
+                Ok(Ok(Some(chunk))) => chunk,
+            };
 
-#[derive(Debug)]
-struct DiskCache {
-    dir: PathBuf,
-    total_size: Arc<AtomicU64>,
-    cache: SyncMutex<LruCache<ItemId, Arc<FileCache>>>,
-    config: Config,
-}
+            if skip != 0 {
+                let len = skip.min(chunk.len() as u64);
+                chunk = chunk.slice(len as usize..);
+                skip -= len;
+                if chunk.is_empty() {
+                    continue;
+                }
+            }
+            // The end of `Range` is ignored as well.
+            if end - pos < chunk.len() as u64 {
+                chunk.truncate((end - pos) as usize);
+            }
 
-impl DiskCache {
-    fn new(config: Config) -> io::Result<Self> {
-        let disk_config = &config.disk_cache;
-        assert!(disk_config.enable);
-        assert!(disk_config.max_cached_file_size <= disk_config.max_total_size);
+            pos += chunk.len() as u64;
+            // Reserve the slot first, so that acquired budget is never lost on abortion.
+            let permit = match tx.reserve().await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    log::debug!(
+                        "Download stopped at {} bytes ({} bytes in total)",
+                        pos,
+                        file_size,
+                    );
+                    return Ok(());
+                }
+            };
+            // The per-download budget goes first, so that a blocked download never holds
+            // the shared budget.
+            for budget in &buffer_budgets {
+                budget.acquire(chunk.len()).await;
+            }
+            transfer.add(chunk.len() as u64);
+            permit.send(chunk);
+            if pos == end {
+                break None;
+            }
+        };
 
-        let dir = disk_config.path.clone();
-        std::fs::create_dir_all(&dir)?;
-        log::info!("Disk file cache enabled at: {}", dir.display());
-        Ok(Self {
-            dir,
-            total_size: Arc::new(0.into()),
-            cache: SyncMutex::new(LruCache::new(disk_config.max_files)),
-            config,
-        })
+        // Resumed from `pos` by the next request.
+        if let Some(err) = stream_error {
+            tries += 1;
+            Metrics::add(&METRICS.download_retries, 1);
+            log::error!(
+                "Error downloading file at {} (try {}/{}): {}",
+                pos,
+                tries,
+                config.max_retry,
+                err,
+            );
+            if config.max_retry < tries {
+                log::error!(
+                    "Download retries exhausted after {} tries, last error: {}",
+                    tries,
+                    err,
+                );
+                return Err(DownloadFailure::RetryExhausted {
+                    tries,
+                    last_error: err,
+                });
+            }
+        }
     }
 
-    fn get(&self, item_id: &ItemId) -> Option<Arc<FileCache>> {
-        self.cache.lock().unwrap().get_mut(item_id).cloned()
+    // Only if the range starts beyond the end.
+    if pos != end {
+        log::error!("Download stopped at {} instead of {}", pos, end);
+        return Err(DownloadFailure::SizeMismatch {
+            expected: end,
+            actual: pos,
+        });
     }
+    log::debug!(
+        "Download finished ({} bytes) at {}",
+        end - start_pos,
+        format_rate(end - start_pos, start_time.elapsed()),
+    );
+    Ok(())
+}
 
-    fn try_alloc_and_fetch(
-        &self,
-        item_id: &ItemId,
-        meta: &RemoteFileMeta,
-        truncate_to: Option<(u64, SystemTime)>,
-        onedrive: ManagedOnedrive,
-        event_tx: mpsc::Sender<UpdateEvent>,
-        client: reqwest::Client,
-    ) -> io::Result<Option<Arc<FileCache>>> {
-        let (file_size, download_truncate) = match truncate_to {
-            None => (meta.size, None),
-            Some((new_size, mtime)) => (new_size, Some((meta.size.min(new_size), mtime))),
-        };
+#[derive(Debug, thiserror::Error)]
+#[error("Not Partial Content response: {0}")]
+struct UnexpectedStatus(StatusCode);
 
-        if self.config.disk_cache.max_cached_file_size < file_size {
-            return Ok(None);
-        }
+/// 416 response, with the total size in `Content-Range: bytes */<total>` if provided.
+#[derive(Debug, thiserror::Error)]
+#[error("Range Not Satisfiable response, total size: {0:?}")]
+struct RangeNotSatisfiable(Option<u64>);
 
-        let mut cache = self.cache.lock().unwrap();
-        if let Some(state) = cache.get_mut(item_id) {
-            return Ok(Some(state.clone()));
-        }
+/// The total size in `Content-Range: bytes <start>-<end>/<total>` or `bytes */<total>`.
+fn content_range_total(resp: &reqwest::Response) -> Option<u64> {
+    let range = resp.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+    range
+        .strip_prefix("bytes ")?
+        .rsplit_once('/')?
+        .1
+        .parse()
+        .ok()
+}
 
-        // Drop LRU until we have enough space.
-        while self.config.disk_cache.max_cached_file_size
-            < self.total_size.load(Ordering::Relaxed) + file_size
-        {
-            if cache.remove_lru().is_none() {
-                // Cache is already empty.
-                return Ok(None);
-            }
-        }
+fn is_expired(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<UnexpectedStatus>(),
+        Some(UnexpectedStatus(
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        )),
+    )
+}
 
-        let cache_file = tempfile::tempfile_in(&self.dir)?;
-        cache_file.set_len(file_size)?;
+/// Fetches a fresh download URL of a file, when the old one expires during a long download.
+#[derive(Clone)]
+struct UrlRefresher {
+    onedrive: ManagedOnedrive,
+    item_id: ItemId,
+    c_tag: Tag,
+}
 
-        // The channel size doesn't really matter, since it's just for synchronization
-        // between downloading and writing.
-        let (chunk_tx, chunk_rx) = mpsc::channel(64);
-        let (file, pos_tx) = FileCache::new(
-            item_id.clone(),
-            file_size,
-            meta.c_tag.clone(),
-            FileCacheStatus::Downloading {
-                truncate: download_truncate,
-            },
-            cache_file.into(),
-            &self.total_size,
-        );
-        cache.insert(item_id.clone(), file.clone());
-        tokio::spawn(FileCache::write_to_cache_thread(
-            file.clone(),
-            chunk_rx,
-            pos_tx,
+impl UrlRefresher {
+    fn new(onedrive: ManagedOnedrive, item_id: &ItemId, meta: &RemoteFileMeta) -> Self {
+        Self {
             onedrive,
-            client.clone(),
-            event_tx,
-            self.config.upload.clone(),
-        ));
-        tokio::spawn(download_thread(
-            meta.size,
-            meta.download_url.clone(),
-            chunk_tx,
-            client,
-            self.config.download.clone(),
-        ));
-        Ok(Some(file))
+            item_id: item_id.clone(),
+            c_tag: meta.c_tag.clone(),
+        }
     }
 
-    async fn insert_empty(&self, item_id: ItemId, c_tag: Tag) -> Result<Arc<FileCache>> {
-        let cache_file = tempfile::tempfile_in(&self.dir)?;
-        let (file, old) = {
-            let mut cache = self.cache.lock().unwrap();
-            let (file, _) = FileCache::new(
-                item_id.clone(),
-                0,
-                c_tag,
-                FileCacheStatus::Available,
-                cache_file.into(),
-                &self.total_size,
-            );
-            let old = cache.insert(item_id, file.clone());
-            (file, old)
+    /// Fails with `Error::Invalidated` if the content is changed.
+    /// If the access token is rejected, it waits for a relogin and tries once more.
+    async fn refresh(&self) -> Result<String> {
+        let meta = match FilePool::fetch_meta(&self.item_id, &*self.onedrive.get().await?).await {
+            Err(Error::AuthExpired) => {
+                self.onedrive.report_rejected().await?;
+                FilePool::fetch_meta(&self.item_id, &*self.onedrive.get().await?).await?
+            }
+            ret => ret?,
         };
-        if let Some(old) = old {
-            old.state.lock().await.status = FileCacheStatus::Invalidated;
+        if meta.c_tag != self.c_tag {
+            log::warn!(
+                "File {:?} is changed during downloading, c_tag: {:?} -> {:?}",
+                self.item_id,
+                self.c_tag,
+                meta.c_tag,
+            );
+            return Err(Error::Invalidated);
         }
-        Ok(file)
+        Ok(meta.download_url)
     }
+}
 
-    async fn sync_items(&self, items: &[DriveItem]) {
-        let mut outdated = Vec::new();
-        {
-            let mut cache = self.cache.lock().unwrap();
-            for item in items {
-                if item.folder.is_some() {
-                    continue;
-                }
-                if item.file.is_none() {
-                    continue;
-                }
-
-                let id = item.id.clone().expect("Missing id");
-                let file = match cache.get_mut(&id) {
-                    Some(file) => file,
-                    None => continue,
-                };
-                if item.deleted.is_some() {
-                    log::debug!("Cached file {:?} is deleted", file.item_id);
-                    outdated.push(cache.remove(&id).unwrap());
-                    continue;
-                }
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("Throttled by server, retry after {0:?}")]
+struct Throttled(Duration);
 
-                let c_tag = item.c_tag.clone().expect("Missing c_tag");
-                let old_c_tag = file.c_tag.lock().unwrap();
-                if *old_c_tag == c_tag {
-                    log::debug!("Cached file {:?} is still up-to-date", *old_c_tag);
-                } else {
-                    log::debug!(
-                        "Cached file {:?} is outdated, ctag: {:?} -> {:?}",
-                        file.item_id,
-                        *old_c_tag,
-                        c_tag,
-                    );
-                    drop(old_c_tag);
-                    outdated.push(cache.remove(&id).unwrap());
-                }
-            }
-        }
-        for file in outdated {
-            file.state.lock().await.status = FileCacheStatus::Invalidated;
-        }
+/// The delay before retrying if a response of `status` is throttling, ie. 429, or 503 with
+/// `Retry-After`. `default` is used if `Retry-After` is missing or `headers` are unknown.
+fn throttle_delay(
+    status: StatusCode,
+    headers: Option<&header::HeaderMap>,
+    default: Duration,
+) -> Option<Duration> {
+    // Only the delay-seconds form is used by Graph.
+    let retry_after = headers
+        .and_then(|headers| headers.get(header::RETRY_AFTER))
+        .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+        .map(Duration::from_secs);
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => {}
+        StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => {}
+        _ => return None,
     }
+    Some(retry_after.unwrap_or(default).min(MAX_RETRY_AFTER))
 }
 
-#[derive(Debug)]
-struct FileCache {
-    state: Mutex<FileCacheState>,
-    item_id: ItemId,
-    c_tag: SyncMutex<Tag>,
-    cache_total_size: Weak<AtomicU64>,
-}
+/// Non-success response of uploading a part, with the error body for diagnostics.
+#[derive(Debug, thiserror::Error)]
+#[error("Upload part failed with {0}: {1}")]
+struct UploadPartFailed(StatusCode, String);
 
-#[derive(Debug)]
-struct FileCacheState {
-    status: FileCacheStatus,
+/// Upload a part like `UploadSession::upload_part`, but fail with `Throttled` carrying the delay
+/// from `Retry-After`, which is not exposed by the API. Returns the item on completion.
+async fn upload_part(
+    sess: &UploadSession,
+    data: impl Into<reqwest::Body>,
+    range: Range<u64>,
     file_size: u64,
-    available_size: watch::Receiver<u64>,
-    cache_file: tokio::fs::File,
+    client: &reqwest::Client,
+    retry_delay: Duration,
+) -> anyhow::Result<Option<DriveItem>> {
+    let resp = client
+        .put(sess.upload_url())
+        .header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", range.start, range.end - 1, file_size),
+        )
+        .body(data)
+        .send()
+        .await?;
+    let status = resp.status();
+    if let Some(delay) = throttle_delay(status, Some(resp.headers()), retry_delay) {
+        return Err(Throttled(delay).into());
+    }
+    match status {
+        StatusCode::ACCEPTED => Ok(None),
+        StatusCode::OK | StatusCode::CREATED => Ok(Some(resp.json().await?)),
+        _ => Err(UploadPartFailed(status, resp.text().await.unwrap_or_default()).into()),
+    }
+}
+
+fn is_gone(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<UnexpectedStatus>(),
+        Some(UnexpectedStatus(StatusCode::NOT_FOUND | StatusCode::GONE)),
+    )
 }
 
 #[derive(Debug)]
-enum FileCacheStatus {
-    /// File is downloading.
-    ///
-    /// `truncate` is `Some(download_size, truncate_mtime)` if there is a pending truncation.
-    Downloading { truncate: Option<(u64, SystemTime)> },
-    /// Download failed.
-    DownloadFailed,
-    /// File is downloaded or created, and is synchronized with remote side.
-    Available,
-    /// File is downloaded or created, and is uploading or waiting for uploading.
-    /// The parameter is used for mark-up of delayed flush.
-    Dirty {
-        lock_mtime: Instant,
-        flush_tx: Option<oneshot::Sender<()>>,
-        /// When closed, `true` indicates a successful upload, while `false` indicates still dirty.
-        done_rx: watch::Receiver<bool>,
-    },
-    /// File is changed in remote side, local cache is invalidated.
-    Invalidated,
+struct FileUploadState {
+    item_id: ItemId,
+    file_size: u64,
+    mtime: SystemTime,
+    /// CTag of the empty file being replaced, for `FilePool::fallback_to_cache`.
+    c_tag: Tag,
+    sess: Option<UploadSession>,
+    /// Bytes already uploaded.
+    pos: u64,
+    /// Pending bytes of the current part.
+    buf: Vec<u8>,
+    transfer: Transfer,
 }
 
-impl FileCache {
-    fn new(
+impl FileUploadState {
+    async fn new(
         item_id: ItemId,
         file_size: u64,
+        mtime: SystemTime,
         c_tag: Tag,
-        status: FileCacheStatus,
-        cache_file: tokio::fs::File,
-        cache_total_size: &Arc<AtomicU64>,
-    ) -> (Arc<Self>, watch::Sender<u64>) {
-        let (pos_tx, pos_rx) = watch::channel(0);
-        cache_total_size.fetch_add(file_size, Ordering::Relaxed);
-        let this = Arc::new(Self {
-            state: Mutex::new(FileCacheState {
-                status,
-                file_size,
-                available_size: pos_rx,
-                cache_file,
-            }),
+        onedrive: &OneDrive,
+    ) -> Result<Self> {
+        let mut initial = DriveItem::default();
+        initial.file_system_info = Some(Box::new(serde_json::json!({
+            "lastModifiedDateTime": humantime::format_rfc3339_seconds(mtime).to_string(),
+        })));
+        let (sess, _) = onedrive
+            .new_upload_session_with_initial_option(
+                ItemLocation::from_id(&item_id),
+                &initial,
+                DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Replace),
+            )
+            .await?;
+        Ok(Self {
+            transfer: METRICS.start_upload(&item_id),
             item_id,
-            c_tag: SyncMutex::new(c_tag),
-            cache_total_size: Arc::downgrade(cache_total_size),
-        });
-        (this, pos_tx)
+            file_size,
+            mtime,
+            c_tag,
+            sess: Some(sess),
+            pos: 0,
+            buf: Vec::with_capacity(UPLOAD_PART_SIZE),
+        })
     }
 
-    async fn write_to_cache_thread(
-        this: Arc<FileCache>,
-        mut chunk_rx: mpsc::Receiver<Bytes>,
-        pos_tx: watch::Sender<u64>,
-        onedrive: ManagedOnedrive,
-        client: reqwest::Client,
-        event_tx: mpsc::Sender<UpdateEvent>,
-        upload_config: UploadConfig,
-    ) {
-        let mut pos = 0u64;
+    async fn write(
+        &mut self,
+        offset: u64,
+        mut data: &[u8],
+        client: &reqwest::Client,
+        event_tx: &mpsc::Sender<UpdateEvent>,
+        config: &UploadConfig,
+    ) -> Result<UpdatedFileAttr> {
+        let current_pos = self.pos + self.buf.len() as u64;
+        if offset != current_pos {
+            return Err(Error::NonsequentialWrite {
+                current_pos,
+                write_offset: offset,
+                write_size: data.len(),
+            });
+        }
+        if self.file_size < current_pos + data.len() as u64 {
+            return Err(Error::FileTooLarge);
+        }
+        // Put back if not completed yet, or leave it `None` on completion or failure.
+        let sess = self.sess.take().ok_or(Error::UploadFailed)?;
 
-        let complete = |mut guard: MutexGuard<'_, FileCacheState>, download_size: u64| {
-            log::debug!(
-                "Cache {:?} is fully available (downloaded {} bytes, total {} bytes)",
-                this.item_id,
-                download_size,
-                guard.file_size,
-            );
+        while !data.is_empty() {
+            let len = data.len().min(UPLOAD_PART_SIZE - self.buf.len());
+            self.buf.extend_from_slice(&data[..len]);
+            data = &data[len..];
 
-            match guard.status {
-                FileCacheStatus::Downloading {
-                    truncate: Some((_, mtime)),
-                } => {
-                    log::debug!(
-                        "Pending upload for truncated file {:?}, size: {}, mtime: {}",
-                        this.item_id,
-                        guard.file_size,
-                        humantime::format_rfc3339_seconds(mtime),
-                    );
-                    this.queue_upload(
-                        &mut guard,
-                        mtime,
-                        onedrive.clone(),
-                        client.clone(),
-                        event_tx,
-                        upload_config,
-                    );
-                }
-                FileCacheStatus::Downloading { truncate: None } => {
-                    guard.status = FileCacheStatus::Available;
-                }
-                _ => unreachable!(),
+            let end = self.pos + self.buf.len() as u64;
+            if self.buf.len() < UPLOAD_PART_SIZE && end < self.file_size {
+                break;
             }
-        };
 
-        while let Some(mut chunk) = chunk_rx.recv().await {
-            let mut guard = this.state.lock().await;
-            let download_size = match guard.status {
-                FileCacheStatus::Downloading {
-                    truncate: Some((download_size, _)),
-                } => download_size,
-                // If there is no pending set_len, download should be aborted when removed from cache.
-                FileCacheStatus::Downloading { truncate: None }
-                    if Arc::strong_count(&this) != 1 =>
-                {
-                    guard.file_size
+            let part = Bytes::from(std::mem::take(&mut self.buf));
+            let mut tries = 0;
+            let ret = loop {
+                let ret = upload_part(
+                    &sess,
+                    part.clone(),
+                    self.pos..end,
+                    self.file_size,
+                    client,
+                    config.retry_delay,
+                )
+                .await;
+                match ret {
+                    Ok(ret) => {
+                        self.transfer.add(part.len() as u64);
+                        break ret;
+                    }
+                    // Throttling costs no retry.
+                    Err(err) if err.is::<Throttled>() => {
+                        let Throttled(delay) = *err.downcast_ref().unwrap();
+                        log::warn!(
+                            "Streaming upload of {:?} throttled at {}, retry after {:?}",
+                            self.item_id,
+                            self.pos,
+                            delay,
+                        );
+                        time::sleep(delay).await;
+                    }
+                    Err(err) => {
+                        tries += 1;
+                        Metrics::add(&METRICS.upload_retries, 1);
+                        log::error!(
+                            "Failed to upload part {}..{}/{} of file {:?} (try {}/{}): {}",
+                            self.pos,
+                            end,
+                            self.file_size,
+                            self.item_id,
+                            tries,
+                            config.stream_max_retry,
+                            err,
+                        );
+                        if config.stream_max_retry < tries {
+                            // Do not leave partial content on the remote side.
+                            if let Err(err) = sess.delete(client).await {
+                                log::error!(
+                                    "Failed to delete failed upload session of {:?}: {}",
+                                    self.item_id,
+                                    err,
+                                );
+                            }
+                            return Err(Error::UploadFailed);
+                        }
+                        time::sleep(config.retry_delay).await;
+                    }
                 }
-                FileCacheStatus::Downloading { .. } | FileCacheStatus::Invalidated => return,
-                FileCacheStatus::DownloadFailed { .. }
-                | FileCacheStatus::Available
-                | FileCacheStatus::Dirty { .. } => unreachable!(),
             };
-            assert!(download_size <= guard.file_size);
-
-            // Truncate extra data if `set_len` is called.
-            let rest_len = download_size.saturating_sub(pos);
-            if rest_len < chunk.len() as u64 {
-                chunk.truncate(rest_len as usize);
-            }
-
-            if !chunk.is_empty() {
-                guard.cache_file.seek(SeekFrom::Start(pos)).await.unwrap();
-                guard.cache_file.write_all(&chunk).await.unwrap();
-                pos += chunk.len() as u64;
-            }
-            log::trace!(
-                "Write {} bytes to cache {:?}, current pos: {}, total need download: {}, file size: {}",
-                chunk.len(),
-                this.item_id,
-                pos,
-                download_size,
-                guard.file_size,
+            log::debug!(
+                "Uploaded part {}..{}/{} of file {:?}",
+                self.pos,
+                end,
+                self.file_size,
+                self.item_id,
             );
+            self.pos = end;
+            self.buf.reserve(UPLOAD_PART_SIZE);
 
-            if pos < download_size {
-                // We are holding `state`.
-                pos_tx.send(pos).unwrap();
-            } else {
-                // We are holding `state`.
-                // The file size may be larger then download size due to set_len.
-                // Space after data written is already zero as expected.
-                pos_tx.send(guard.file_size).unwrap();
-
-                complete(guard, download_size);
-                return;
+            if let Some(item) = ret {
+                if end != self.file_size {
+                    log::error!(
+                        "Streaming upload session of {:?} is completed early at {}/{}",
+                        self.item_id,
+                        end,
+                        self.file_size,
+                    );
+                    return Err(Error::UploadFailed);
+                }
+                let (_, attr) = InodeAttr::parse_response(&item)?;
+                let c_tag = attr.c_tag.clone().ok_or(Error::MissingField("cTag"))?;
+                log::info!(
+                    "Uploaded {:?} ({} B), new c_tag: {:?}",
+                    self.item_id,
+                    self.file_size,
+                    c_tag,
+                );
+                let _ = event_tx
+                    .send(UpdateEvent::UpdateFile(UpdatedFileAttr {
+                        item_id: self.item_id.clone(),
+                        size: attr.size,
+                        mtime: attr.mtime,
+                        c_tag,
+                    }))
+                    .await;
             }
         }
+        if self.pos < self.file_size {
+            self.sess = Some(sess);
+        }
 
-        let mut guard = this.state.lock().await;
-        let download_size = match guard.status {
-            FileCacheStatus::Downloading { truncate } => {
-                truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size)
-            }
-            FileCacheStatus::Invalidated => return,
-            FileCacheStatus::DownloadFailed { .. }
-            | FileCacheStatus::Available
-            | FileCacheStatus::Dirty { .. } => unreachable!(),
-        };
+        Ok(UpdatedFileAttr {
+            item_id: self.item_id.clone(),
+            size: self.file_size,
+            mtime: self.mtime,
+            // CTag is currently unknown and will be filled after a successful upload.
+            c_tag: Tag(String::new()),
+        })
+    }
 
-        if pos < download_size {
+    /// Cancel the upload session if the file is not completely written, which fails since the
+    /// content written is lost.
+    async fn abort(&mut self, client: &reqwest::Client) -> Result<()> {
+        let sess = match self.sess.take() {
+            Some(sess) => sess,
+            None => return Ok(()),
+        };
+        let written = self.pos + self.buf.len() as u64;
+        log::warn!(
+            "Streaming upload of {:?} is closed before completion ({}/{} B), cancelling",
+            self.item_id,
+            written,
+            self.file_size,
+        );
+        if let Err(err) = sess.delete(client).await {
             log::error!(
-                "Download failed of {:?}, got {}/{}",
-                this.item_id,
-                pos,
-                download_size,
+                "Failed to delete upload session of {:?}: {}",
+                self.item_id,
+                err,
             );
-            guard.status = FileCacheStatus::DownloadFailed;
-        } else {
-            // File is set to a larger length than remote side.
-            complete(guard, download_size);
         }
+        Err(Error::UploadIncomplete {
+            written,
+            size: self.file_size,
+        })
     }
 
-    async fn read(this: &Arc<Self>, offset: u64, size: usize) -> Result<Bytes> {
-        let mut guard = this.state.lock().await;
-        let file_size = guard.file_size;
-        if file_size <= offset || size == 0 {
-            return Ok(Bytes::new());
+    /// Cancel the upload session of a state not used yet.
+    async fn cancel(mut self, client: &reqwest::Client) {
+        if let Some(sess) = self.sess.take() {
+            if let Err(err) = sess.delete(client).await {
+                log::error!(
+                    "Failed to delete upload session of {:?}: {}",
+                    self.item_id,
+                    err,
+                );
+            }
         }
-        let end = offset + size as u64;
+    }
+}
 
-        match guard.status {
-            FileCacheStatus::Available | FileCacheStatus::Dirty { .. } => {}
-            FileCacheStatus::Invalidated => return Err(Error::Invalidated),
-            FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
-            FileCacheStatus::Downloading { .. } if end <= *guard.available_size.borrow() => {}
-            FileCacheStatus::Downloading { .. } => {
-                let mut rx = guard.available_size.clone();
-                drop(guard);
-                // Wait until finished or enough bytes are available.
-                while rx.changed().await.is_ok() && *rx.borrow() < end {}
+#[derive(Debug)]
+struct DiskCache {
+    /// `None` if cache files are kept in memory, see `new_in_memory`.
+    dir: Option<PathBuf>,
+    /// Holding an exclusive `flock` on `LOCK_FILE_NAME` in `dir`, released on drop.
+    _dir_lock: Option<std::fs::File>,
+    total_size: Arc<AtomicU64>,
+    cache: Arc<CacheMap>,
+    /// All alive cache files, including these removed from `cache` but still opened.
+    /// It should sum up to `total_size`.
+    live_files: Arc<SyncMutex<Vec<Weak<FileCache>>>>,
+    /// Block caches of files opened read-only in `blocks` mode, not counted in `max_files` of
+    /// `cache`.
+    blocks: Arc<BlockMap>,
+    /// Like `live_files`, but for block caches. Their downloaded blocks sum up to `total_size` too.
+    live_blocks: Arc<SyncMutex<Vec<Weak<BlockFile>>>>,
+    events: broadcast::Sender<CacheEvent>,
+    /// Bumped by each `sync_items`, under the lock of `cache`.
+    sync_seq: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    config: Config,
+}
 
-                guard = this.state.lock().await;
-                match guard.status {
-                    FileCacheStatus::Invalidated => return Err(Error::Invalidated),
-                    FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
-                    FileCacheStatus::Available
-                    | FileCacheStatus::Dirty { .. }
-                    | FileCacheStatus::Downloading { .. } => {}
-                }
-            }
+type CacheMap = SyncMutex<LruCache<ItemId, Arc<FileCache>>>;
+
+type BlockMap = SyncMutex<LruCache<ItemId, Arc<BlockFile>>>;
+
+/// Dirty cache files reloaded from the previous run, with the mtimes to upload and the upload
+/// sessions to resume.
+type ReloadedDirty = Vec<(Arc<FileCache>, SystemTime, Option<UploadSidecar>)>;
+
+const LOCK_FILE_NAME: &str = ".lock";
+
+/// Suffix of named cache files when `persistent` is enabled.
+const CACHE_FILE_SUFFIX: &str = ".cache";
+
+/// Suffix of an index being written, appended to `<cache file>.json`.
+const INDEX_TEMP_SUFFIX: &str = ".tmp";
+
+/// Suffix of `UploadSidecar` files, appended to the item id.
+const UPLOAD_SIDECAR_SUFFIX: &str = ".upload.json";
+
+/// Sidecar `<cache file>.json` of a named cache file, present only if its content is complete.
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    item_id: ItemId,
+    size: u64,
+    /// CTag of the remote version the content is based on.
+    c_tag: Tag,
+    #[serde(default)]
+    status: IndexStatus,
+    /// The mtime to upload with, for `Dirty` entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<SystemTime>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum IndexStatus {
+    /// The content is synchronized with remote side.
+    #[default]
+    Available,
+    /// The content has local changes not uploaded yet, which are uploaded after restart.
+    /// `size` may be outdated by later writes, and the length of the cache file is used instead.
+    Dirty,
+}
+
+/// Sidecar `<item id>.upload.json` of a dirty named cache file, recording the upload session in
+/// progress, so that the upload is resumed after restart instead of starting over.
+/// It's removed before the content or mtime is changed, which outdates the session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct UploadSidecar {
+    upload_url: String,
+    file_size: u64,
+    /// Bytes uploaded in order. The server may have received more.
+    uploaded: u64,
+}
+
+/// Where to resume a persisted upload session of a file of `file_size`, given the ranges the
+/// server still expects. Returns `None` if a new session should be started instead.
+fn resume_pos(
+    sidecar: &UploadSidecar,
+    file_size: u64,
+    next_expected: &[ExpectRange],
+) -> Option<u64> {
+    if sidecar.file_size != file_size {
+        return None;
+    }
+    // None left means the last part is received, but the completed item is lost.
+    // Only the first range is used since parts are uploaded in order.
+    next_expected
+        .first()
+        .map(|range| range.start)
+        .filter(|&start| start < file_size)
+}
+
+/// Remove files, returning the number of removed ones and the disk space reclaimed.
+fn remove_files(paths: impl IntoIterator<Item = PathBuf>) -> (usize, u64) {
+    let (mut count, mut size) = (0, 0);
+    for path in paths {
+        let len = disk_usage(&path);
+        if std::fs::remove_file(&path).is_ok() {
+            count += 1;
+            size += len;
         }
+    }
+    (count, size)
+}
 
-        // File size should be retrieved after waiting since it may change.
-        let end = end.min(guard.file_size);
+/// Disk space used by a file, or 0 if it's gone. Cache files of `blocks` mode are sparse.
+fn disk_usage(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt as _;
 
-        let mut buf = vec![0u8; (end - offset) as usize];
-        guard
-            .cache_file
-            .seek(SeekFrom::Start(offset))
-            .await
-            .unwrap();
-        guard.cache_file.read_exact(&mut buf).await.unwrap();
-        Ok(buf.into())
+    std::fs::metadata(path).map_or(0, |meta| meta.blocks() * 512)
+}
+
+/// Whether `path` is a valid index of a cache file with changes not uploaded.
+fn is_dirty_index(path: &Path) -> bool {
+    std::fs::read(path)
+        .ok()
+        .and_then(|buf| serde_json::from_slice::<IndexEntry>(&buf).ok())
+        .is_some_and(|entry| entry.status == IndexStatus::Dirty)
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".json");
+    path.into()
+}
+
+fn upload_sidecar_path(dir: &Path, item_id: &ItemId) -> PathBuf {
+    dir.join(format!("{}{}", item_id.as_str(), UPLOAD_SIDECAR_SUFFIX))
+}
+
+/// Replace a file atomically, since a torn index or sidecar loses local changes.
+fn write_atomic(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(INDEX_TEMP_SUFFIX);
+    let ret = std::fs::write(&temp_path, buf).and_then(|()| std::fs::rename(&temp_path, path));
+    if ret.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
     }
+    ret
+}
 
-    async fn write(
-        this: &Arc<Self>,
-        offset: u64,
-        data: &[u8],
-        event_tx: mpsc::Sender<UpdateEvent>,
-        onedrive: ManagedOnedrive,
-        unlimit_client: reqwest::Client,
-        config: UploadConfig,
-    ) -> Result<UpdatedFileAttr> {
-        let mut guard = this.state.lock().await;
-        if config.max_size < offset + data.len() as u64 {
-            return Err(Error::FileTooLarge);
+/// Result of `DiskCache::try_alloc_and_fetch`.
+enum Alloc {
+    Cached(Arc<FileCache>),
+    /// There is not enough space in cache.
+    NoSpace,
+    /// Changes are synced after the meta is fetched, which are not applied to the item since it
+    /// was not in cache. The meta should be fetched again.
+    Outdated,
+}
+
+impl DiskCache {
+    /// Also return dirty files reloaded from the previous run, see `load_index`.
+    fn new(
+        config: Config,
+        drive_id: &DriveId,
+        events: broadcast::Sender<CacheEvent>,
+    ) -> io::Result<(Self, ReloadedDirty)> {
+        let disk_config = &config.disk_cache;
+        assert!(disk_config.enable);
+        assert!(disk_config.max_cached_file_size <= disk_config.max_total_size);
+
+        // Item ids are only unique in a drive.
+        let dir = disk_config.path.join(drive_id.as_str());
+        std::fs::create_dir_all(&dir)?;
+        let lock = Self::lock_dir(&dir)?;
+        log::info!("Disk file cache enabled at: {}", dir.display());
+        let persistent = disk_config.persistent;
+        let mut this = Self::with_dir(Some(dir), config, events);
+        this._dir_lock = Some(lock);
+        let dirty = if persistent {
+            this.load_index()?
+        } else {
+            this.remove_leftovers()?;
+            Vec::new()
+        };
+        Ok((this, dirty))
+    }
+
+    /// Remove named cache files and indexes left by previous runs with `persistent` enabled,
+    /// which are otherwise never reclaimed, unless `purge_on_start` is disabled.
+    ///
+    /// Files with changes not uploaded cannot be uploaded without `persistent`. Instead of
+    /// removing them, it fails if `purge_on_start` is enabled, or keeps them otherwise.
+    fn remove_leftovers(&self) -> io::Result<()> {
+        let dir = self.dir.as_ref().unwrap();
+        let mut paths = Vec::new();
+        let mut dirty = Vec::new();
+        for dirent in std::fs::read_dir(dir)? {
+            let path = dirent?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            let is_sidecar = name.ends_with(UPLOAD_SIDECAR_SUFFIX);
+            if name.ends_with(&format!("{}.json", CACHE_FILE_SUFFIX)) && is_dirty_index(&path) {
+                dirty.push(path.with_extension(""));
+            }
+            let name = name.strip_suffix(INDEX_TEMP_SUFFIX).unwrap_or(name);
+            let name = name.strip_suffix(".json").unwrap_or(name);
+            if is_sidecar || name.ends_with(CACHE_FILE_SUFFIX) {
+                paths.push(path);
+            }
         }
-        match guard.status {
-            FileCacheStatus::Available | FileCacheStatus::Dirty { .. } => {}
-            FileCacheStatus::Invalidated => return Err(Error::Invalidated),
-            FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
-            FileCacheStatus::Downloading { .. } => {
-                let mut rx = guard.available_size.clone();
-                drop(guard);
-                // Wait until finished.
-                while rx.changed().await.is_ok() {}
-                guard = this.state.lock().await;
+
+        let purge = self.config.disk_cache.purge_on_start;
+        if !dirty.is_empty() {
+            dirty.sort();
+            let msg = format!(
+                "{} has {} cache files with changes not uploaded, left by a previous run with \
+                 `disk_cache.persistent` enabled: {:?}. Enable it again to upload them, or remove \
+                 them manually to discard the changes",
+                dir.display(),
+                dirty.len(),
+                dirty,
+            );
+            if purge {
+                return Err(io::Error::other(msg));
             }
+            log::warn!(
+                "{}. They are kept since `disk_cache.purge_on_start` is disabled",
+                msg
+            );
         }
 
-        let mtime = SystemTime::now();
-        match guard.status {
-            FileCacheStatus::Invalidated => return Err(Error::Invalidated),
-            FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
-            FileCacheStatus::Downloading { .. } => unreachable!(),
-            FileCacheStatus::Dirty { .. } | FileCacheStatus::Available => {
-                this.queue_upload(
-                    &mut guard,
-                    mtime,
-                    onedrive,
-                    unlimit_client.clone(),
-                    event_tx.clone(),
-                    config,
+        if !purge {
+            if !paths.is_empty() {
+                log::info!(
+                    "Kept {} files ({} B) left by persistent disk cache",
+                    paths.len(),
+                    paths.iter().map(|path| disk_usage(path)).sum::<u64>(),
                 );
             }
+            return Ok(());
         }
-
-        guard
+        let (count, size) = remove_files(paths);
+        if count != 0 {
+            log::info!(
+                "Removed {} files ({} B) left by persistent disk cache",
+                count,
+                size,
+            );
+        }
+        Ok(())
+    }
+
+    /// Reload complete cache files left by the previous run, and remove all other cache files
+    /// unless `purge_on_start` is disabled. Outdated ones are invalidated by the initial
+    /// synchronization as usual.
+    ///
+    /// Dirty ones are always reloaded regardless of limits, and returned with their mtimes to be
+    /// uploaded again.
+    fn load_index(&self) -> io::Result<ReloadedDirty> {
+        let dir = self.dir.as_ref().unwrap();
+        let mut entries = Vec::new();
+        let mut cache_files = Vec::new();
+        let mut sidecars = HashMap::new();
+        // Indexes and sidecars not used.
+        let mut leftovers = Vec::new();
+        for dirent in std::fs::read_dir(dir)? {
+            let path = dirent?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            if name.ends_with(CACHE_FILE_SUFFIX) {
+                cache_files.push(path);
+                continue;
+            }
+            if let Some(item_id) = name.strip_suffix(UPLOAD_SIDECAR_SUFFIX) {
+                sidecars.insert(ItemId(item_id.to_owned()), path);
+                continue;
+            }
+            // Interrupted `FileCache::write_index`.
+            if name.ends_with(INDEX_TEMP_SUFFIX) {
+                leftovers.push(path);
+                continue;
+            }
+            let cache_path = match name.strip_suffix(".json") {
+                Some(name) if name.ends_with(CACHE_FILE_SUFFIX) => dir.join(name),
+                _ => continue,
+            };
+            match Self::open_indexed(&path, &cache_path) {
+                Ok(Some((mtime, entry, file))) => entries.push((mtime, entry, cache_path, file)),
+                Ok(None) => {
+                    log::warn!("Drop invalid cache index {}", path.display());
+                    leftovers.push(path);
+                }
+                Err(err) => {
+                    log::warn!("Failed to load cache index {}: {}", path.display(), err);
+                    leftovers.push(path);
+                }
+            }
+        }
+
+        // Least recently completed ones are inserted first, and thus evicted first.
+        // Dirty ones go before all others, so that they are never dropped for limits.
+        entries.sort_by_key(|(mtime, entry, ..)| (entry.status == IndexStatus::Available, *mtime));
+        let mut kept = HashSet::new();
+        let mut dirty = ReloadedDirty::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for (_, entry, cache_path, file) in entries {
+                let is_dirty = entry.status == IndexStatus::Dirty;
+                // An evicted file may outlive its successor. The later one wins, unless only the
+                // earlier one has local changes.
+                if let Some(old) = cache.get_mut(&entry.item_id) {
+                    let old_dirty = dirty.iter().position(|(file, ..)| Arc::ptr_eq(file, old));
+                    if old_dirty.is_some() && !is_dirty {
+                        leftovers.push(index_path(&cache_path));
+                        continue;
+                    }
+                    if let Some(i) = old_dirty {
+                        dirty.swap_remove(i);
+                    }
+                    let old = cache.remove(&entry.item_id).unwrap();
+                    let old_path = old.path.as_ref().unwrap();
+                    kept.remove(old_path);
+                    leftovers.push(index_path(old_path));
+                }
+                let disk_config = &self.config.disk_cache;
+                if !is_dirty
+                    && (cache.len() >= disk_config.max_files
+                        || disk_config.max_total_size
+                            < self.total_size.load(Ordering::Relaxed) + entry.size)
+                {
+                    leftovers.push(index_path(&cache_path));
+                    continue;
+                }
+                let (file, pos_tx) = FileCache::new(
+                    entry.item_id.clone(),
+                    entry.size,
+                    entry.c_tag,
+                    FileCacheStatus::Available,
+                    file.into(),
+                    Some(cache_path.clone()),
+                    self,
+                );
+                pos_tx.send(entry.size).unwrap();
+                cache.insert(entry.item_id, file.clone());
+                self.register_live(&file);
+                kept.insert(cache_path);
+                if is_dirty {
+                    let mtime = entry.mtime.unwrap_or_else(SystemTime::now);
+                    dirty.push((file, mtime, None));
+                }
+            }
+        }
+        // Uploads of dirty ones may be resumed.
+        for (file, _, resume) in &mut dirty {
+            let path = match sidecars.remove(&file.item_id) {
+                Some(path) => path,
+                None => continue,
+            };
+            let sidecar = std::fs::read(&path)
+                .ok()
+                .and_then(|buf| serde_json::from_slice::<UploadSidecar>(&buf).ok());
+            let file_size = file.state.try_lock().expect("Not shared yet").file_size;
+            match sidecar {
+                Some(sidecar) if sidecar.file_size == file_size => {
+                    log::info!(
+                        "Found upload session of {:?} at {}/{}",
+                        file.item_id,
+                        sidecar.uploaded,
+                        file_size,
+                    );
+                    *resume = Some(sidecar);
+                }
+                _ => {
+                    log::warn!("Drop invalid upload sidecar {}", path.display());
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        leftovers.extend(sidecars.into_values());
+
+        let unused = cache_files.into_iter().filter(|path| !kept.contains(path));
+        let (action, count, size) = if self.config.disk_cache.purge_on_start {
+            remove_files(leftovers);
+            let (count, size) = remove_files(unused);
+            ("removed", count, size)
+        } else {
+            let unused = unused.collect::<Vec<_>>();
+            let size = unused.iter().map(|path| disk_usage(path)).sum();
+            ("kept", unused.len(), size)
+        };
+        log::info!(
+            "Loaded {} cached files ({} B, {} dirty) from disk cache, {} {} unused ones ({} B)",
+            kept.len(),
+            self.total_size.load(Ordering::Relaxed),
+            dirty.len(),
+            action,
+            count,
+            size,
+        );
+        Ok(dirty)
+    }
+
+    /// Open a cache file by its index. Returns `None` if the index is invalid or doesn't match the
+    /// cache file.
+    fn open_indexed(
+        index_path: &Path,
+        cache_path: &Path,
+    ) -> io::Result<Option<(SystemTime, IndexEntry, std::fs::File)>> {
+        let buf = std::fs::read(index_path)?;
+        let mtime = std::fs::metadata(index_path)?.modified()?;
+        let mut entry: IndexEntry = match serde_json::from_slice(&buf) {
+            Ok(entry) => entry,
+            Err(_) => return Ok(None),
+        };
+        let file = match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(cache_path)
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let len = file.metadata()?.len();
+        match entry.status {
+            IndexStatus::Available if len != entry.size => return Ok(None),
+            IndexStatus::Available => {}
+            IndexStatus::Dirty => entry.size = len,
+        }
+        Ok(Some((mtime, entry, file)))
+    }
+
+    /// Lock the cache directory, so that it's not used by multiple instances, whose total size
+    /// accounting would not be aware of each other.
+    fn lock_dir(dir: &std::path::Path) -> io::Result<std::fs::File> {
+        use nix::fcntl::{flock, FlockArg};
+        use std::os::unix::io::AsRawFd as _;
+
+        let path = dir.join(LOCK_FILE_NAME);
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(file),
+            Err(nix::errno::Errno::EWOULDBLOCK) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "Disk cache directory {} is in use by another instance",
+                    dir.display(),
+                ),
+            )),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Cache files in memory for writing when disk cache is disabled. Only files opened for write
+    /// are cached, and the total size of them is limited by `upload.memory_buffer_max`.
+    fn new_in_memory(mut config: Config, events: broadcast::Sender<CacheEvent>) -> Self {
+        let disk_config = &mut config.disk_cache;
+        assert!(!disk_config.enable);
+        disk_config.max_cached_file_size = config.upload.memory_buffer_max;
+        disk_config.max_total_size = config.upload.memory_buffer_max;
+        disk_config.prestage_path = None;
+        disk_config.persistent = false;
+        disk_config.mode = CacheMode::Whole;
+        log::info!(
+            "In-memory file cache for writing enabled, max size: {} B",
+            config.upload.memory_buffer_max,
+        );
+        Self::with_dir(None, config, events)
+    }
+
+    fn with_dir(
+        dir: Option<PathBuf>,
+        config: Config,
+        events: broadcast::Sender<CacheEvent>,
+    ) -> Self {
+        let disk_config = &config.disk_cache;
+        let total_size = Arc::new(AtomicU64::new(0));
+        let live_files = Arc::new(SyncMutex::new(Vec::new()));
+        let live_blocks = Arc::new(SyncMutex::new(Vec::new()));
+        if !disk_config.reconcile_period.is_zero() {
+            tokio::spawn(Self::reconcile_thread(
+                Arc::downgrade(&total_size),
+                Arc::downgrade(&live_files),
+                Arc::downgrade(&live_blocks),
+                disk_config.reconcile_period,
+            ));
+        }
+        Self {
+            dir,
+            _dir_lock: None,
+            total_size,
+            cache: Arc::new(SyncMutex::new(LruCache::new(disk_config.max_files))),
+            live_files,
+            blocks: Arc::new(SyncMutex::new(LruCache::new(disk_config.max_files))),
+            live_blocks,
+            events,
+            sync_seq: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            config,
+        }
+    }
+
+    /// Whether the filesystem of the cache directory can hold `len` more bytes, while keeping
+    /// `min_free_space` free.
+    fn has_free_space(&self, len: u64) -> io::Result<bool> {
+        let min_free_space = self.config.disk_cache.min_free_space;
+        let dir = match &self.dir {
+            Some(dir) if min_free_space != 0 => dir,
+            _ => return Ok(true),
+        };
+        let stat = nix::sys::statvfs::statvfs(dir)?;
+        let free = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+        Ok(len.saturating_add(min_free_space) <= free)
+    }
+
+    /// Create a file for caching, which is removed after closed.
+    /// It's anonymous, unless `persistent` is enabled, where its path is also returned.
+    fn new_cache_file(&self, item_id: &ItemId) -> io::Result<(std::fs::File, Option<PathBuf>)> {
+        match &self.dir {
+            Some(dir) if self.config.disk_cache.persistent => {
+                let (file, path) = tempfile::Builder::new()
+                    .prefix(&format!("{}.", item_id.as_str()))
+                    .suffix(CACHE_FILE_SUFFIX)
+                    .tempfile_in(dir)?
+                    .keep()
+                    .map_err(|err| err.error)?;
+                Ok((file, Some(path)))
+            }
+            Some(dir) => Ok((tempfile::tempfile_in(dir)?, None)),
+            None => {
+                use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+                use std::os::unix::io::FromRawFd as _;
+
+                let fd = memfd_create(c"onedrive-fuse", MemFdCreateFlag::MFD_CLOEXEC)?;
+                // SAFETY: `fd` is just created and owned by nobody else.
+                Ok((unsafe { std::fs::File::from_raw_fd(fd) }, None))
+            }
+        }
+    }
+
+    /// Periodically recompute the total size from alive cache files, in case of accounting drift.
+    async fn reconcile_thread(
+        total_size: Weak<AtomicU64>,
+        live_files: Weak<SyncMutex<Vec<Weak<FileCache>>>>,
+        live_blocks: Weak<SyncMutex<Vec<Weak<BlockFile>>>>,
+        period: Duration,
+    ) {
+        loop {
+            time::sleep(period).await;
+
+            let (total_size, live_files, live_blocks) = match (
+                total_size.upgrade(),
+                live_files.upgrade(),
+                live_blocks.upgrade(),
+            ) {
+                (Some(total_size), Some(live_files), Some(live_blocks)) => {
+                    (total_size, live_files, live_blocks)
+                }
+                _ => return,
+            };
+            let files = {
+                let mut live_files = live_files.lock().unwrap();
+                live_files.retain(|file| file.strong_count() != 0);
+                live_files
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .collect::<Vec<_>>()
+            };
+            let blocks = {
+                let mut live_blocks = live_blocks.lock().unwrap();
+                live_blocks.retain(|file| file.strong_count() != 0);
+                live_blocks
+                    .iter()
+                    .filter_map(Weak::upgrade)
+                    .collect::<Vec<_>>()
+            };
+            let mut actual = 0u64;
+            for file in &files {
+                actual += file.state.lock().await.file_size;
+            }
+            for file in &blocks {
+                actual += file.state.lock().await.downloaded_size;
+            }
+            // Sizes may still change during the summation above. It's best-effort and would be
+            // corrected in the next round.
+            let recorded = total_size.swap(actual, Ordering::Relaxed);
+            if recorded != actual {
+                log::warn!(
+                    "Disk cache total size drifted: recorded {} B, actual {} B in {} files",
+                    recorded,
+                    actual,
+                    files.len() + blocks.len(),
+                );
+            }
+        }
+    }
+
+    /// Track a newly created cache file.
+    fn register_live(&self, file: &Arc<FileCache>) {
+        self.live_files.lock().unwrap().push(Arc::downgrade(file));
+        file.emit(CacheEvent::Created(file.item_id.clone()));
+    }
+
+    /// Get a cache entry for reading or writing, counted as a hit if found.
+    fn get(&self, item_id: &ItemId) -> Option<Arc<FileCache>> {
+        let file = self.cache.lock().unwrap().get_mut(item_id).cloned();
+        if file.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        file
+    }
+
+    fn sync_seq(&self) -> u64 {
+        let _guard = self.cache.lock().unwrap();
+        self.sync_seq.load(Ordering::Relaxed)
+    }
+
+    /// Allocate a cache entry and start downloading, or get the existing one.
+    /// `sync_seq` should be taken by `sync_seq()` before fetching `meta`.
+    #[allow(clippy::too_many_arguments)]
+    fn try_alloc_and_fetch(
+        &self,
+        item_id: &ItemId,
+        meta: &RemoteFileMeta,
+        sync_seq: u64,
+        truncate_to: Option<(u64, SystemTime)>,
+        onedrive: ManagedOnedrive,
+        event_tx: mpsc::Sender<UpdateEvent>,
+        client: reqwest::Client,
+    ) -> io::Result<Alloc> {
+        let (file_size, download_truncate) = match truncate_to {
+            None => (meta.size, None),
+            Some((new_size, mtime)) => (new_size, Some((meta.size.min(new_size), mtime))),
+        };
+
+        if self.config.disk_cache.max_cached_file_size < file_size {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(Alloc::NoSpace);
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(state) = cache.get_mut(item_id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Alloc::Cached(state.clone()));
+        }
+        if self.sync_seq.load(Ordering::Relaxed) != sync_seq {
+            return Ok(Alloc::Outdated);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Drop LRU until we have enough space.
+        while self.config.disk_cache.max_total_size
+            < self.total_size.load(Ordering::Relaxed) + file_size
+        {
+            match cache.remove_lru() {
+                Some((id, _)) => {
+                    log::debug!("Evicted cache {:?}", id);
+                    Metrics::add(&METRICS.cache_evictions, 1);
+                    let _ = self.events.send(CacheEvent::Evicted(id));
+                }
+                // Cache is already empty.
+                None => return Ok(Alloc::NoSpace),
+            }
+        }
+        if !self.has_free_space(file_size)? {
+            log::warn!(
+                "Free space of the cache directory is low, not caching {:?} ({} B)",
+                item_id,
+                file_size,
+            );
+            return Ok(Alloc::NoSpace);
+        }
+
+        let (mut cache_file, cache_path) = self.new_cache_file(item_id)?;
+        if truncate_to.is_none() && self.load_prestaged(item_id, meta, &mut cache_file)? {
+            let (file, pos_tx) = FileCache::new(
+                item_id.clone(),
+                file_size,
+                meta.c_tag.clone(),
+                FileCacheStatus::Available,
+                cache_file.into(),
+                cache_path,
+                self,
+            );
+            pos_tx.send(file_size).unwrap();
+            file.save_index(file_size, &meta.c_tag);
+            cache.insert(item_id.clone(), file.clone());
+            self.register_live(&file);
+            return Ok(Alloc::Cached(file));
+        }
+        cache_file.set_len(file_size)?;
+
+        let (mut file, pos_tx) = FileCache::new(
+            item_id.clone(),
+            file_size,
+            meta.c_tag.clone(),
+            FileCacheStatus::Downloading {
+                truncate: download_truncate,
+            },
+            cache_file.into(),
+            cache_path,
+            self,
+        );
+        if self.config.download.priority_first_read {
+            Arc::get_mut(&mut file)
+                .unwrap()
+                .state
+                .get_mut()
+                .priority_read_url = Some(meta.download_url.clone());
+        }
+        cache.insert(item_id.clone(), file.clone());
+        self.register_live(&file);
+        self.spawn_download(&file, meta, 0, pos_tx, onedrive, event_tx, client);
+        Ok(Alloc::Cached(file))
+    }
+
+    /// Get or create the block cache of an item, whose blocks are downloaded when read.
+    /// Return `None` if the meta may be outdated by sync, like `Alloc::Outdated`.
+    fn open_blocks(
+        &self,
+        item_id: &ItemId,
+        meta: &RemoteFileMeta,
+        sync_seq: u64,
+    ) -> io::Result<Option<Arc<BlockFile>>> {
+        // `sync_items` checks `blocks` under the lock of `cache` as well.
+        let _cache = self.cache.lock().unwrap();
+        if self.sync_seq.load(Ordering::Relaxed) != sync_seq {
+            return Ok(None);
+        }
+        let mut blocks = self.blocks.lock().unwrap();
+        if let Some(file) = blocks.get_mut(item_id) {
+            if file.c_tag == meta.c_tag {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(file.clone()));
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let (cache_file, path) = self.new_cache_file(item_id)?;
+        // Sparse until blocks are written.
+        cache_file.set_len(meta.size)?;
+        let block_size = self.config.disk_cache.block_size;
+        let file = Arc::new(BlockFile {
+            item_id: item_id.clone(),
+            c_tag: meta.c_tag.clone(),
+            file_size: meta.size,
+            block_size,
+            state: Mutex::new(BlockFileState {
+                cache_file: cache_file.into(),
+                download_url: meta.download_url.clone(),
+                present: vec![false; meta.size.div_ceil(block_size) as usize],
+                downloaded_size: 0,
+            }),
+            cache_total_size: Arc::downgrade(&self.total_size),
+            blocks: Arc::downgrade(&self.blocks),
+            max_total_size: self.config.disk_cache.max_total_size,
+            events: self.events.clone(),
+            invalidated: AtomicBool::new(false),
+            path,
+        });
+        if blocks.len() >= self.config.disk_cache.max_files && !blocks.contains_key(item_id) {
+            if let Some((id, _)) = blocks.remove_lru() {
+                log::debug!("Evicted block cache {:?}", id);
+                Metrics::add(&METRICS.cache_evictions, 1);
+                file.emit(CacheEvent::Evicted(id));
+            }
+        }
+        if let Some(old) = blocks.insert(item_id.clone(), file.clone()) {
+            old.invalidated.store(true, Ordering::Release);
+            old.emit(CacheEvent::Invalidated(old.item_id.clone()));
+        }
+        self.live_blocks.lock().unwrap().push(Arc::downgrade(&file));
+        file.emit(CacheEvent::Created(item_id.clone()));
+        Ok(Some(file))
+    }
+
+    /// Copy the pre-staged content of an item into `cache_file` if it's up-to-date.
+    ///
+    /// A pre-staged item consists of the content file named by the item id,
+    /// and a sidecar `<item id>.json` like `{ "size": 42, "c_tag": "..." }`.
+    fn load_prestaged(
+        &self,
+        item_id: &ItemId,
+        meta: &RemoteFileMeta,
+        cache_file: &mut std::fs::File,
+    ) -> io::Result<bool> {
+        #[derive(Deserialize)]
+        struct Sidecar {
+            size: u64,
+            c_tag: Tag,
+        }
+
+        let dir = match &self.config.disk_cache.prestage_path {
+            Some(dir) => dir,
+            None => return Ok(false),
+        };
+        let sidecar_path = dir.join(format!("{}.json", item_id.as_str()));
+        let sidecar = match std::fs::read(&sidecar_path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let sidecar: Sidecar = match serde_json::from_slice(&sidecar) {
+            Ok(sidecar) => sidecar,
+            Err(err) => {
+                log::warn!("Invalid sidecar {}: {}", sidecar_path.display(), err);
+                return Ok(false);
+            }
+        };
+        if sidecar.size != meta.size || sidecar.c_tag != meta.c_tag {
+            log::debug!("Pre-staged file {:?} is outdated", item_id);
+            return Ok(false);
+        }
+
+        let mut src = std::fs::File::open(dir.join(item_id.as_str()))?;
+        if src.metadata()?.len() != meta.size {
+            log::warn!("Pre-staged file {:?} has a wrong size", item_id);
+            return Ok(false);
+        }
+        io::copy(&mut src, cache_file)?;
+        log::debug!("Loaded pre-staged file {:?} ({} B)", item_id, meta.size);
+        Ok(true)
+    }
+
+    /// Resume the download of a cached file from where it failed, if the remote side is not changed.
+    async fn try_resume(
+        &self,
+        file: &Arc<FileCache>,
+        meta: &RemoteFileMeta,
+        onedrive: ManagedOnedrive,
+        event_tx: mpsc::Sender<UpdateEvent>,
+        client: reqwest::Client,
+    ) {
+        let mut guard = file.state.lock().await;
+        if !matches!(guard.status, FileCacheStatus::DownloadFailed)
+            || guard.file_size != meta.size
+            || !guard.overwritten.is_empty()
+            || *file.c_tag.lock().unwrap() != meta.c_tag
+        {
+            return;
+        }
+        let pos = *guard.available_size.borrow();
+        log::info!(
+            "Resume download of {:?} from {}/{}",
+            file.item_id,
+            pos,
+            meta.size,
+        );
+        let (pos_tx, pos_rx) = watch::channel(pos);
+        guard.available_size = pos_rx;
+        guard.ahead.clear();
+        guard.status = FileCacheStatus::Downloading { truncate: None };
+        drop(guard);
+        self.spawn_download(file, meta, pos, pos_tx, onedrive, event_tx, client);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn spawn_download(
+        &self,
+        file: &Arc<FileCache>,
+        meta: &RemoteFileMeta,
+        start_pos: u64,
+        pos_tx: watch::Sender<u64>,
+        onedrive: ManagedOnedrive,
+        event_tx: mpsc::Sender<UpdateEvent>,
+        client: reqwest::Client,
+    ) {
+        // The channel size doesn't really matter, since it's just for synchronization
+        // between downloading and writing.
+        let (chunk_tx, chunk_rx) = mpsc::channel(64);
+        let (size_tx, size_rx) = oneshot::channel();
+        let download_task = tokio::spawn(download_segments_thread(
+            meta.size,
+            start_pos,
+            meta.download_url.clone(),
+            UrlRefresher::new(onedrive.clone(), &file.item_id, meta),
+            size_tx,
+            chunk_tx,
+            file.wanted.clone(),
+            client.clone(),
+            self.config.download.clone(),
+        ));
+        let expected_hash = if self.config.download.verify_hash {
+            meta.quick_xor_hash.clone()
+        } else {
+            None
+        };
+        tokio::spawn(FileCache::write_to_cache_thread(
+            file.clone(),
+            expected_hash,
+            size_rx,
+            chunk_rx,
+            download_task,
+            start_pos,
+            pos_tx,
+            Arc::downgrade(&self.cache),
+            onedrive,
+            client,
+            event_tx,
+            self.config.download.progress_interval,
+            self.config.upload.clone(),
+        ));
+    }
+
+    async fn insert_empty(&self, item_id: ItemId, c_tag: Tag) -> Result<Arc<FileCache>> {
+        let (cache_file, cache_path) = self.new_cache_file(&item_id)?;
+        let (file, old) = {
+            let mut cache = self.cache.lock().unwrap();
+            let (file, _) = FileCache::new(
+                item_id.clone(),
+                0,
+                c_tag.clone(),
+                FileCacheStatus::Available,
+                cache_file.into(),
+                cache_path,
+                self,
+            );
+            let old = cache.insert(item_id, file.clone());
+            (file, old)
+        };
+        self.register_live(&file);
+        file.save_index(0, &c_tag);
+        if let Some(old) = old {
+            old.state.lock().await.status = FileCacheStatus::Invalidated;
+            old.bump_version();
+            old.emit(CacheEvent::Invalidated(old.item_id.clone()));
+        }
+        Ok(file)
+    }
+
+    async fn stats(&self) -> CacheStats {
+        let disk_config = &self.config.disk_cache;
+        let mut stats = CacheStats {
+            total_size: self.total_size.load(Ordering::Relaxed),
+            max_total_size: disk_config.max_total_size,
+            file_count: self.cache.lock().unwrap().len(),
+            max_files: disk_config.max_files,
+            block_file_count: self.blocks.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        let files = self
+            .live_files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        for file in files {
+            let count = match file.state.lock().await.status {
+                FileCacheStatus::Downloading { .. } => &mut stats.downloading,
+                FileCacheStatus::DownloadFailed => &mut stats.download_failed,
+                FileCacheStatus::Available => &mut stats.available,
+                FileCacheStatus::Dirty { .. } => &mut stats.dirty,
+                FileCacheStatus::DownloadTimeout
+                | FileCacheStatus::Unlinked
+                | FileCacheStatus::Invalidated => &mut stats.invalidated,
+            };
+            *count += 1;
+        }
+        stats
+    }
+
+    async fn sync_items(&self, items: &[DriveItem]) {
+        let mut outdated = Vec::new();
+        let mut deleted = Vec::new();
+        let mut outdated_blocks = Vec::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            self.sync_seq.fetch_add(1, Ordering::Relaxed);
+            for item in items {
+                if ItemKind::of(item) != Some(ItemKind::File) {
+                    continue;
+                }
+
+                let id = match &item.id {
+                    Some(id) => id.clone(),
+                    None => {
+                        log::warn!("Skip cache sync of item without id: {:?}", item);
+                        continue;
+                    }
+                };
+                let file = match cache.get_mut(&id) {
+                    Some(file) => file,
+                    None => continue,
+                };
+                if item.deleted.is_some() {
+                    log::debug!("Cached file {:?} is deleted", file.item_id);
+                    deleted.push(cache.remove(&id).unwrap());
+                    continue;
+                }
+
+                let c_tag = match content_tag(item) {
+                    Some(c_tag) => c_tag,
+                    None => {
+                        log::warn!("Missing c_tag of cached file {:?}, invalidate it", id);
+                        outdated.push(cache.remove(&id).unwrap());
+                        continue;
+                    }
+                };
+                let old_c_tag = file.c_tag.lock().unwrap();
+                if *old_c_tag == c_tag {
+                    log::debug!("Cached file {:?} is still up-to-date", *old_c_tag);
+                } else {
+                    log::debug!(
+                        "Cached file {:?} is outdated, ctag: {:?} -> {:?}",
+                        file.item_id,
+                        *old_c_tag,
+                        c_tag,
+                    );
+                    drop(old_c_tag);
+                    outdated.push(cache.remove(&id).unwrap());
+                }
+            }
+
+            let mut blocks = self.blocks.lock().unwrap();
+            for item in items {
+                if ItemKind::of(item) != Some(ItemKind::File) {
+                    continue;
+                }
+                let id = match &item.id {
+                    Some(id) => id,
+                    None => {
+                        log::warn!("Skip block cache sync of item without id: {:?}", item);
+                        continue;
+                    }
+                };
+                let up_to_date = match blocks.get_mut(id) {
+                    Some(file) => {
+                        item.deleted.is_none() && content_tag(item).as_ref() == Some(&file.c_tag)
+                    }
+                    None => continue,
+                };
+                if !up_to_date {
+                    log::debug!("Block cache of {:?} is outdated or deleted", id);
+                    outdated_blocks.push(blocks.remove(id).unwrap());
+                }
+            }
+        }
+        for file in outdated_blocks {
+            file.invalidated.store(true, Ordering::Release);
+            file.emit(CacheEvent::Invalidated(file.item_id.clone()));
+        }
+        for file in outdated {
+            let mut guard = file.state.lock().await;
+            if self.config.disk_cache.on_invalidate_dirty == InvalidateDirtyPolicy::KeepBoth
+                && matches!(guard.status, FileCacheStatus::Dirty { .. })
+            {
+                // Opened handles keep the local version, which is uploaded as a conflict copy.
+                log::warn!(
+                    "Dirty cached file {:?} is changed in remote side, keep both",
+                    file.item_id,
+                );
+                file.conflicted.store(true, Ordering::Release);
+            } else {
+                guard.status = FileCacheStatus::Invalidated;
+                file.bump_version();
+            }
+            drop(guard);
+            file.emit(CacheEvent::Invalidated(file.item_id.clone()));
+        }
+        for file in deleted {
+            let mut guard = file.state.lock().await;
+            let complete = matches!(
+                guard.status,
+                FileCacheStatus::Available | FileCacheStatus::Dirty { .. },
+            );
+            guard.status = if complete && self.config.disk_cache.open_unlinked {
+                // Opened handles keep reading it. It's freed after the last handle is closed.
+                FileCacheStatus::Unlinked
+            } else {
+                FileCacheStatus::Invalidated
+            };
+            drop(guard);
+            file.bump_version();
+            file.emit(CacheEvent::Invalidated(file.item_id.clone()));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FileCache {
+    state: Mutex<FileCacheState>,
+    item_id: ItemId,
+    c_tag: SyncMutex<Tag>,
+    cache_total_size: Weak<AtomicU64>,
+    events: broadcast::Sender<CacheEvent>,
+    /// Bumped when the content is modified or invalidated, to expire read windows of handles.
+    version: AtomicU64,
+    /// Path of the named cache file if `persistent` is enabled.
+    path: Option<PathBuf>,
+    /// Set on shutdown for files still in cache, to keep indexed ones for the next run.
+    keep_on_drop: AtomicBool,
+    /// The offset a read is waiting for, or `u64::MAX` if none. Only for segmented downloads,
+    /// which download the segment covering it next.
+    wanted: Option<Arc<AtomicU64>>,
+    /// Set if the file is changed in remote side while dirty, to upload it as a conflict copy.
+    /// See `InvalidateDirtyPolicy::KeepBoth`.
+    conflicted: AtomicBool,
+    /// Whether the `UploadSidecar` of this file exists.
+    has_upload_sidecar: AtomicBool,
+    /// The upload session persisted by the previous run, taken by the next upload to resume.
+    upload_resume: SyncMutex<Option<UploadSidecar>>,
+}
+
+#[derive(Debug)]
+struct FileCacheState {
+    status: FileCacheStatus,
+    file_size: u64,
+    available_size: watch::Receiver<u64>,
+    cache_file: tokio::fs::File,
+    /// Ranges written locally during downloading, which must not be overwritten by the download.
+    overwritten: Vec<Range<u64>>,
+    /// Ranges downloaded beyond `available_size` by segmented downloads, which are already
+    /// readable.
+    ahead: Vec<Range<u64>>,
+    /// The download URL for fetching the first read directly, taken when used.
+    priority_read_url: Option<String>,
+}
+
+#[derive(Debug)]
+enum FileCacheStatus {
+    /// File is downloading.
+    ///
+    /// `truncate` is `Some(download_size, truncate_mtime)` if there is a pending truncation.
+    Downloading { truncate: Option<(u64, SystemTime)> },
+    /// Download failed.
+    DownloadFailed,
+    /// Download exceeded `max_total_duration` and is aborted.
+    /// Like `Invalidated`, it is removed from cache.
+    DownloadTimeout,
+    /// File is downloaded or created, and is synchronized with remote side.
+    Available,
+    /// File is downloaded or created, and is uploading or waiting for uploading.
+    /// The parameter is used for mark-up of delayed flush.
+    Dirty {
+        lock_mtime: Instant,
+        /// When the file becomes dirty since the last successful upload.
+        first_dirty: Instant,
+        flush_tx: Option<oneshot::Sender<()>>,
+        /// When closed, `true` indicates a successful upload, while `false` indicates still dirty.
+        done_rx: watch::Receiver<bool>,
+    },
+    /// File is deleted in remote side, but the complete content is still readable from cache.
+    /// It is removed from cache, and pending uploads are dropped.
+    Unlinked,
+    /// File is changed in remote side, local cache is invalidated.
+    Invalidated,
+}
+
+impl FileCache {
+    fn new(
+        item_id: ItemId,
+        file_size: u64,
+        c_tag: Tag,
+        status: FileCacheStatus,
+        cache_file: tokio::fs::File,
+        path: Option<PathBuf>,
+        disk_cache: &DiskCache,
+    ) -> (Arc<Self>, watch::Sender<u64>) {
+        let (pos_tx, pos_rx) = watch::channel(0);
+        disk_cache
+            .total_size
+            .fetch_add(file_size, Ordering::Relaxed);
+        let this = Arc::new(Self {
+            state: Mutex::new(FileCacheState {
+                status,
+                file_size,
+                available_size: pos_rx,
+                cache_file,
+                overwritten: Vec::new(),
+                ahead: Vec::new(),
+                priority_read_url: None,
+            }),
+            item_id,
+            c_tag: SyncMutex::new(c_tag),
+            cache_total_size: Arc::downgrade(&disk_cache.total_size),
+            events: disk_cache.events.clone(),
+            version: AtomicU64::new(0),
+            path,
+            keep_on_drop: AtomicBool::new(false),
+            conflicted: AtomicBool::new(false),
+            has_upload_sidecar: AtomicBool::new(false),
+            upload_resume: SyncMutex::new(None),
+            wanted: (disk_cache.config.download.segments > 1)
+                .then(|| Arc::new(AtomicU64::new(u64::MAX))),
+        });
+        (this, pos_tx)
+    }
+
+    fn bump_version(&self) {
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    fn emit(&self, event: CacheEvent) {
+        // Fails only if there is no subscriber.
+        let _ = self.events.send(event);
+    }
+
+    /// Adjust `total_size` for a change of the file size.
+    fn account_resize(&self, old_size: u64, new_size: u64) {
+        if let Some(total) = self.cache_total_size.upgrade() {
+            if old_size < new_size {
+                total.fetch_add(new_size - old_size, Ordering::Relaxed);
+            } else {
+                let _ = total.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                    Some(total.saturating_sub(old_size - new_size))
+                });
+            }
+        }
+    }
+
+    /// Index the complete content of a named cache file, so that it can be reloaded after restart.
+    fn save_index(&self, file_size: u64, c_tag: &Tag) {
+        self.write_index(&IndexEntry {
+            item_id: self.item_id.clone(),
+            size: file_size,
+            c_tag: c_tag.clone(),
+            status: IndexStatus::Available,
+            mtime: None,
+        });
+    }
+
+    /// Index the content diverged from the remote side, so that it's uploaded after restart.
+    fn save_dirty_index(&self, file_size: u64, mtime: SystemTime) {
+        self.write_index(&IndexEntry {
+            item_id: self.item_id.clone(),
+            size: file_size,
+            c_tag: self.c_tag.lock().unwrap().clone(),
+            status: IndexStatus::Dirty,
+            mtime: Some(mtime),
+        });
+    }
+
+    fn write_index(&self, entry: &IndexEntry) {
+        let path = match &self.path {
+            Some(path) => index_path(path),
+            None => return,
+        };
+        let buf = serde_json::to_vec(entry).unwrap();
+        if let Err(err) = write_atomic(&path, &buf) {
+            log::warn!("Failed to save cache index of {:?}: {}", self.item_id, err);
+        }
+    }
+
+    fn upload_sidecar_path(&self) -> Option<PathBuf> {
+        let dir = self.path.as_ref()?.parent()?;
+        Some(upload_sidecar_path(dir, &self.item_id))
+    }
+
+    /// Record the progress of an upload session. Must be called with the state locked, and the
+    /// upload up-to-date, to not race `remove_upload_sidecar` of a later modification.
+    fn save_upload_sidecar(&self, sess: &UploadSession, file_size: u64, uploaded: u64) {
+        let path = match self.upload_sidecar_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let sidecar = UploadSidecar {
+            upload_url: sess.upload_url().to_owned(),
+            file_size,
+            uploaded,
+        };
+        let buf = serde_json::to_vec(&sidecar).unwrap();
+        match write_atomic(&path, &buf) {
+            Ok(()) => self.has_upload_sidecar.store(true, Ordering::Relaxed),
+            Err(err) => log::warn!(
+                "Failed to save upload sidecar of {:?}: {}",
+                self.item_id,
+                err
+            ),
+        }
+    }
+
+    fn remove_upload_sidecar(&self) {
+        if !self.has_upload_sidecar.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        if let Some(path) = self.upload_sidecar_path() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    /// Invalidate the cache after an upload with an unexpected result, if it's not changed since
+    /// the upload is queued at `init_lock_mtime`. It's re-downloaded on the next open.
+    async fn invalidate_uploaded(&self, init_lock_mtime: Instant) {
+        let mut guard = self.state.lock().await;
+        if matches!(guard.status, FileCacheStatus::Dirty { lock_mtime, .. } if lock_mtime == init_lock_mtime)
+        {
+            guard.status = FileCacheStatus::Invalidated;
+            drop(guard);
+            self.bump_version();
+            self.emit(CacheEvent::Invalidated(self.item_id.clone()));
+        }
+    }
+
+    /// Remove this entry from cache, if it's not replaced yet.
+    fn remove_from(self: &Arc<Self>, cache: &Weak<CacheMap>) {
+        if let Some(cache) = cache.upgrade() {
+            let mut cache = cache.lock().unwrap();
+            if cache
+                .get_mut(&self.item_id)
+                .is_some_and(|file| Arc::ptr_eq(file, self))
+            {
+                cache.remove(&self.item_id);
+            }
+        }
+    }
+
+    /// Invalidate a downloading file and remove it from cache, so that the next open downloads it
+    /// again. Readers waiting for the download get `Error::Invalidated`.
+    fn abort_download(
+        self: &Arc<Self>,
+        mut guard: MutexGuard<'_, FileCacheState>,
+        cache: &Weak<CacheMap>,
+    ) {
+        guard.status = FileCacheStatus::Invalidated;
+        drop(guard);
+        self.bump_version();
+        self.emit(CacheEvent::Invalidated(self.item_id.clone()));
+        self.remove_from(cache);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn write_to_cache_thread(
+        this: Arc<FileCache>,
+        expected_hash: Option<String>,
+        size_rx: oneshot::Receiver<u64>,
+        mut chunk_rx: mpsc::Receiver<(u64, Bytes)>,
+        download_task: JoinHandle<DownloadResult>,
+        start_pos: u64,
+        pos_tx: watch::Sender<u64>,
+        cache: Weak<CacheMap>,
+        onedrive: ManagedOnedrive,
+        client: reqwest::Client,
+        event_tx: mpsc::Sender<UpdateEvent>,
+        progress_interval: Duration,
+        upload_config: UploadConfig,
+    ) {
+        let mut pos = start_pos;
+        // Position and time of the last `DownloadProgress`.
+        let mut progress = (start_pos, Instant::now());
+
+        // Closed without value if the size is confirmed, or the download failed.
+        if let Ok(file_size) = size_rx.await {
+            let mut guard = this.state.lock().await;
+            match guard.status {
+                FileCacheStatus::Downloading { truncate: None } if guard.overwritten.is_empty() => {
+                    if let Err(err) = guard.cache_file.set_len(file_size).await {
+                        log::error!("Failed to resize cache {:?}: {}", this.item_id, err);
+                        this.abort_download(guard, &cache);
+                        return;
+                    }
+                    if let Some(total) = this.cache_total_size.upgrade() {
+                        total.fetch_add(file_size, Ordering::Relaxed);
+                        total.fetch_sub(guard.file_size, Ordering::Relaxed);
+                    }
+                    guard.file_size = file_size;
+                    drop(guard);
+                    this.bump_version();
+                    let c_tag = this.c_tag.lock().unwrap().clone();
+                    let _ = event_tx
+                        .send(UpdateEvent::CorrectSize {
+                            item_id: this.item_id.clone(),
+                            c_tag,
+                            size: file_size,
+                        })
+                        .await;
+                }
+                // Local changes are made upon the wrong size.
+                FileCacheStatus::Downloading { .. } => {
+                    log::error!(
+                        "Cache {:?} is modified before its size is corrected, invalidate it",
+                        this.item_id,
+                    );
+                    this.abort_download(guard, &cache);
+                    return;
+                }
+                FileCacheStatus::Invalidated => return,
+                FileCacheStatus::DownloadFailed
+                | FileCacheStatus::DownloadTimeout
+                | FileCacheStatus::Available
+                | FileCacheStatus::Dirty { .. }
+                | FileCacheStatus::Unlinked => unreachable!(),
+            }
+        }
+
+        let complete = |mut guard: MutexGuard<'_, FileCacheState>, download_size: u64| {
+            log::debug!(
+                "Cache {:?} is fully available (downloaded {} bytes, total {} bytes)",
+                this.item_id,
+                download_size,
+                guard.file_size,
+            );
+            this.emit(CacheEvent::DownloadComplete(this.item_id.clone()));
+
+            match guard.status {
+                FileCacheStatus::Downloading {
+                    truncate: Some((_, mtime)),
+                } => {
+                    log::debug!(
+                        "Pending upload for truncated file {:?}, size: {}, mtime: {}",
+                        this.item_id,
+                        guard.file_size,
+                        humantime::format_rfc3339_seconds(mtime),
+                    );
+                    this.queue_upload(
+                        &mut guard,
+                        mtime,
+                        onedrive.clone(),
+                        client.clone(),
+                        event_tx,
+                        upload_config,
+                    );
+                }
+                FileCacheStatus::Downloading { truncate: None } => {
+                    guard.status = FileCacheStatus::Available;
+                    this.save_index(guard.file_size, &this.c_tag.lock().unwrap());
+                }
+                _ => unreachable!(),
+            }
+        };
+
+        // Expected hash, running hash and hashed length of the content.
+        // Only chunks from the very beginning in order can be verified.
+        let mut verify = expected_hash
+            .filter(|_| start_pos == 0)
+            .map(|expected| (expected, QuickXorHash::new(), 0u64));
+        while let Some((offset, mut chunk)) = chunk_rx.recv().await {
+            match &mut verify {
+                Some((_, hasher, hashed)) if *hashed == offset => {
+                    hasher.update(&chunk);
+                    *hashed += chunk.len() as u64;
+                }
+                Some(_) => {
+                    log::debug!("Skip verifying {:?} downloaded out of order", this.item_id);
+                    verify = None;
+                }
+                None => {}
+            }
+
+            let mut guard = this.state.lock().await;
+            let download_size = match guard.status {
+                FileCacheStatus::Downloading {
+                    truncate: Some((download_size, _)),
+                } => download_size,
+                // If there is no pending set_len, download should be aborted when removed from cache.
+                FileCacheStatus::Downloading { truncate: None }
+                    if Arc::strong_count(&this) != 1 =>
+                {
+                    guard.file_size
+                }
+                FileCacheStatus::Downloading { .. } | FileCacheStatus::Invalidated => return,
+                FileCacheStatus::DownloadFailed
+                | FileCacheStatus::DownloadTimeout
+                | FileCacheStatus::Available
+                | FileCacheStatus::Dirty { .. }
+                | FileCacheStatus::Unlinked => unreachable!(),
+            };
+            assert!(download_size <= guard.file_size);
+
+            // Truncate extra data if `set_len` is called.
+            let rest_len = download_size.saturating_sub(offset);
+            if rest_len < chunk.len() as u64 {
+                chunk.truncate(rest_len as usize);
+            }
+
+            if !chunk.is_empty() {
+                let end = offset + chunk.len() as u64;
+                for range in uncovered_ranges(offset..end, &guard.overwritten) {
+                    let data =
+                        &chunk[(range.start - offset) as usize..(range.end - offset) as usize];
+                    let file = &mut guard.cache_file;
+                    let ret = match file.seek(SeekFrom::Start(range.start)).await {
+                        Ok(_) => file.write_all(data).await,
+                        Err(err) => Err(err),
+                    };
+                    // Eg. the filesystem is full. Dropping `chunk_rx` stops the download.
+                    if let Err(err) = ret {
+                        log::error!("Failed to write cache {:?}: {}", this.item_id, err);
+                        this.abort_download(guard, &cache);
+                        return;
+                    }
+                }
+                // Chunks of a segment are in order, so `ahead` has at most one range per segment.
+                let ahead = &mut guard.ahead;
+                match ahead.iter_mut().find(|r| r.end == offset) {
+                    Some(r) => r.end = end,
+                    None => ahead.push(offset..end),
+                }
+                // Advance the contiguous prefix.
+                while let Some(i) = ahead.iter().position(|r| r.start <= pos) {
+                    pos = pos.max(ahead.swap_remove(i).end);
+                }
+            }
+            log::trace!(
+                "Write {} bytes to cache {:?}, current pos: {}, total need download: {}, file size: {}",
+                chunk.len(),
+                this.item_id,
+                pos,
+                download_size,
+                guard.file_size,
+            );
+
+            if pos < download_size {
+                // We are holding `state`.
+                pos_tx.send(pos).unwrap();
+                if !progress_interval.is_zero()
+                    && progress.0 < pos
+                    && (progress_interval <= progress.1.elapsed()
+                        || download_size / 20 <= pos - progress.0)
+                {
+                    progress = (pos, Instant::now());
+                    this.emit(CacheEvent::DownloadProgress {
+                        item_id: this.item_id.clone(),
+                        downloaded: pos,
+                        total: download_size,
+                    });
+                }
+            } else {
+                // Truncated downloads miss some bytes to verify.
+                if let (
+                    Some((expected, hasher, _)),
+                    FileCacheStatus::Downloading { truncate: None },
+                ) = (&verify, &guard.status)
+                {
+                    let actual = hasher.finish_base64();
+                    if actual != *expected {
+                        log::error!(
+                            "Hash mismatch of downloaded {:?}, expect {}, got {}. Invalidate it",
+                            this.item_id,
+                            expected,
+                            actual,
+                        );
+                        this.abort_download(guard, &cache);
+                        return;
+                    }
+                    log::debug!("Verified downloaded {:?}, hash: {}", this.item_id, actual);
+                }
+
+                // We are holding `state`.
+                // The file size may be larger then download size due to set_len.
+                // Space after data written is already zero as expected.
+                pos_tx.send(guard.file_size).unwrap();
+                guard.overwritten = Vec::new();
+                guard.ahead = Vec::new();
+
+                complete(guard, download_size);
+                return;
+            }
+        }
+
+        let mut guard = this.state.lock().await;
+        let download_size = match guard.status {
+            FileCacheStatus::Downloading { truncate } => {
+                truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size)
+            }
+            FileCacheStatus::Invalidated => return,
+            FileCacheStatus::DownloadFailed
+            | FileCacheStatus::DownloadTimeout
+            | FileCacheStatus::Available
+            | FileCacheStatus::Dirty { .. }
+            | FileCacheStatus::Unlinked => unreachable!(),
+        };
+
+        if pos < download_size {
+            log::error!(
+                "Download failed of {:?}, got {}/{}",
+                this.item_id,
+                pos,
+                download_size,
+            );
+            match download_task.await {
+                Ok(Err(DownloadFailure::Timeout)) => {
+                    guard.status = FileCacheStatus::DownloadTimeout;
+                }
+                // Downloaded bytes belong to the old content.
+                Ok(Err(DownloadFailure::Changed)) => {
+                    guard.status = FileCacheStatus::Invalidated;
+                    this.bump_version();
+                    this.emit(CacheEvent::Invalidated(this.item_id.clone()));
+                }
+                _ => {
+                    guard.status = FileCacheStatus::DownloadFailed;
+                    return;
+                }
+            }
+            drop(guard);
+            // Do not keep the partial content, so that the next open downloads it again.
+            this.remove_from(&cache);
+        } else {
+            // File is set to a larger length than remote side.
+            guard.overwritten = Vec::new();
+            guard.ahead = Vec::new();
+            complete(guard, download_size);
+        }
+    }
+
+    async fn read(this: &Arc<Self>, offset: u64, size: usize) -> Result<Bytes> {
+        let mut guard = this.state.lock().await;
+        let file_size = guard.file_size;
+        if file_size <= offset || size == 0 {
+            return Ok(Bytes::new());
+        }
+        let end = offset + size as u64;
+
+        // `available_size` is updated under `state` together with the status, so either enough
+        // bytes are available or the status is changed after the download completes.
+        // Status is re-checked after each wait, since a failed download may be resumed with
+        // a new `available_size` channel, closing the old one.
+        loop {
+            // The last bytes are available when it reaches the file size.
+            let target = end.min(guard.file_size);
+            match guard.status {
+                FileCacheStatus::Available
+                | FileCacheStatus::Dirty { .. }
+                | FileCacheStatus::Unlinked => break,
+                FileCacheStatus::Invalidated => return Err(Error::Invalidated),
+                FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+                FileCacheStatus::DownloadTimeout => return Err(Error::DownloadTimeout),
+                FileCacheStatus::Downloading { .. }
+                    if target <= *guard.available_size.borrow()
+                        || guard
+                            .ahead
+                            .iter()
+                            .any(|r| r.start <= offset && target <= r.end) =>
+                {
+                    break
+                }
+                FileCacheStatus::Downloading { .. } => {
+                    let mut rx = guard.available_size.clone();
+                    drop(guard);
+                    match &this.wanted {
+                        // Segments may complete out of order, so re-check `ahead` on each chunk.
+                        Some(wanted) => {
+                            wanted.store(offset.max(*rx.borrow()), Ordering::Relaxed);
+                            let _ = rx.changed().await;
+                        }
+                        // Wait until finished or enough bytes are available.
+                        None => while rx.changed().await.is_ok() && *rx.borrow() < target {},
+                    }
+                    guard = this.state.lock().await;
+                }
+            }
+        }
+
+        // File size should be retrieved after waiting since it may change.
+        let end = end.min(guard.file_size);
+        if end <= offset {
+            // Truncated during the wait.
+            return Ok(Bytes::new());
+        }
+
+        let mut buf = vec![0u8; (end - offset) as usize];
+        guard
+            .cache_file
+            .seek(SeekFrom::Start(offset))
+            .await
+            .unwrap();
+        guard.cache_file.read_exact(&mut buf).await.unwrap();
+        Ok(buf.into())
+    }
+
+    /// Fetch the range of the first read directly if it's not downloaded yet, instead of waiting
+    /// for the whole-file download to reach it. Return `None` if not applicable or failed.
+    async fn priority_read(
+        this: &Arc<Self>,
+        offset: u64,
+        size: usize,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+    ) -> Option<Bytes> {
+        let (url, end) = {
+            let mut guard = this.state.lock().await;
+            let url = guard.priority_read_url.take()?;
+            // Refused, and is reported by the whole-file download.
+            config.check_host(&url).ok()?;
+            let download_size = match guard.status {
+                FileCacheStatus::Downloading { truncate } => {
+                    truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size)
+                }
+                _ => return None,
+            };
+            let end = download_size.min(offset.saturating_add(size as u64));
+            if end <= offset || end <= *guard.available_size.borrow() {
+                return None;
+            }
+            (url, end)
+        };
+
+        let ret: anyhow::Result<Bytes> = async {
+            let resp = client
+                .get(&url)
+                .timeout(config.chunk_timeout)
+                .header(header::RANGE, format!("bytes={}-{}", offset, end - 1))
+                .send()
+                .await?;
+            if resp.status() != StatusCode::PARTIAL_CONTENT {
+                anyhow::bail!("Not Partial Content response: {}", resp.status());
+            }
+            let data = resp.bytes().await?;
+            anyhow::ensure!(data.len() as u64 == end - offset, "Length mismatch");
+            Ok(data)
+        }
+        .await;
+        let data = match ret {
+            Ok(data) => data,
+            Err(err) => {
+                log::warn!("Priority read of {:?} failed: {}", this.item_id, err);
+                return None;
+            }
+        };
+
+        let mut guard = this.state.lock().await;
+        // Fall back if it's modified during the fetch.
+        if !matches!(guard.status, FileCacheStatus::Downloading { .. })
+            || guard.file_size < end
+            || guard
+                .overwritten
+                .iter()
+                .any(|r| r.start < end && offset < r.end)
+        {
+            return None;
+        }
+        // The download will write the same content again later.
+        let file = &mut guard.cache_file;
+        if let Err(err) = file.seek(SeekFrom::Start(offset)).await {
+            log::warn!(
+                "Failed to write priority read of {:?}: {}",
+                this.item_id,
+                err
+            );
+            return None;
+        }
+        if let Err(err) = file.write_all(&data).await {
+            log::warn!(
+                "Failed to write priority read of {:?}: {}",
+                this.item_id,
+                err
+            );
+            return None;
+        }
+        log::debug!(
+            "Priority read {}..{} of {:?} served",
+            offset,
+            end,
+            this.item_id,
+        );
+        Some(data)
+    }
+
+    /// Wait until the status is not `Downloading`, including downloads resumed during the wait.
+    async fn wait_downloaded<'a>(
+        this: &'a Arc<Self>,
+        mut guard: MutexGuard<'a, FileCacheState>,
+    ) -> MutexGuard<'a, FileCacheState> {
+        while matches!(guard.status, FileCacheStatus::Downloading { .. }) {
+            let mut rx = guard.available_size.clone();
+            drop(guard);
+            while rx.changed().await.is_ok() {}
+            guard = this.state.lock().await;
+        }
+        guard
+    }
+
+    async fn flush(this: &Arc<Self>) -> Result<()> {
+        let mut guard = this.state.lock().await;
+        match guard.status {
+            FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+            FileCacheStatus::DownloadTimeout => return Err(Error::DownloadTimeout),
+            FileCacheStatus::Available
+            | FileCacheStatus::Unlinked
+            | FileCacheStatus::Invalidated => return Ok(()),
+            FileCacheStatus::Downloading { .. } => {
+                guard = Self::wait_downloaded(this, guard).await;
+            }
+            FileCacheStatus::Dirty { .. } => {}
+        }
+        loop {
+            let (lock_mtime, flush_tx, mut done_rx) = match &mut guard.status {
+                FileCacheStatus::Downloading { .. } => unreachable!(),
+                FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+                FileCacheStatus::DownloadTimeout => return Err(Error::DownloadTimeout),
+                FileCacheStatus::Invalidated
+                | FileCacheStatus::Unlinked
+                | FileCacheStatus::Available => return Ok(()),
+                FileCacheStatus::Dirty {
+                    lock_mtime,
+                    flush_tx,
+                    done_rx,
+                    ..
+                } => (*lock_mtime, flush_tx.take(), done_rx.clone()),
+            };
+            drop(guard);
+            if let Some(flush_tx) = flush_tx {
+                let _ = flush_tx.send(());
+            }
+            while done_rx.changed().await.is_ok() {}
+            // May be canceled by another modification during the upload.
+            if *done_rx.borrow() {
+                return Ok(());
+            }
+            guard = this.state.lock().await;
+            // The upload gave up without any further modification.
+            if matches!(guard.status, FileCacheStatus::Dirty { lock_mtime: cur, .. } if cur == lock_mtime)
+            {
+                return Err(Error::UploadFailed);
+            }
+            // Local changes are discarded.
+            if matches!(guard.status, FileCacheStatus::Invalidated) {
+                return Err(Error::Invalidated);
+            }
+        }
+    }
+
+    /// Write to the cache and queue an upload.
+    ///
+    /// Writes from all handles of the same file are serialized on `state`, so no byte is lost.
+    /// The file size is the maximum end of all writes, and the upload always reflects the
+    /// content after the latest write.
+    async fn write(
+        this: &Arc<Self>,
+        offset: u64,
+        data: &[u8],
+        event_tx: mpsc::Sender<UpdateEvent>,
+        onedrive: ManagedOnedrive,
+        unlimit_client: reqwest::Client,
+        config: UploadConfig,
+    ) -> Result<UpdatedFileAttr> {
+        let mut guard = this.state.lock().await;
+        if config.max_size < offset + data.len() as u64 {
+            return Err(Error::FileTooLarge);
+        }
+        this.bump_version();
+        let mtime = SystemTime::now();
+        match guard.status {
+            FileCacheStatus::Available | FileCacheStatus::Dirty { .. } => {}
+            FileCacheStatus::Invalidated => return Err(Error::Invalidated),
+            FileCacheStatus::Unlinked => return Err(Error::Deleted),
+            FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+            FileCacheStatus::DownloadTimeout => return Err(Error::DownloadTimeout),
+            FileCacheStatus::Downloading { truncate }
+                if config.write_during_download == WriteDuringDownloadPolicy::Overwrite =>
+            {
+                // Upload is queued like a pending truncation after the download completes.
+                let download_size = truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size);
+                guard.status = FileCacheStatus::Downloading {
+                    truncate: Some((download_size, mtime)),
+                };
+                guard.overwritten.push(offset..(offset + data.len() as u64));
+            }
+            FileCacheStatus::Downloading { .. } => {
+                guard = Self::wait_downloaded(this, guard).await;
+            }
+        }
+
+        match guard.status {
+            FileCacheStatus::Invalidated => return Err(Error::Invalidated),
+            FileCacheStatus::Unlinked => return Err(Error::Deleted),
+            FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+            FileCacheStatus::DownloadTimeout => return Err(Error::DownloadTimeout),
+            FileCacheStatus::Downloading { .. } => {}
+            FileCacheStatus::Dirty { .. } | FileCacheStatus::Available => {
+                this.queue_upload(
+                    &mut guard,
+                    mtime,
+                    onedrive,
+                    unlimit_client.clone(),
+                    event_tx.clone(),
+                    config,
+                );
+            }
+        }
+
+        guard.cache_file.seek(SeekFrom::Start(offset)).await?;
+        guard.cache_file.write_all(data).await?;
+
+        let new_size = guard.file_size.max(offset + data.len() as u64);
+        if guard.file_size < new_size {
+            if let Some(total) = this.cache_total_size.upgrade() {
+                total.fetch_add(new_size - guard.file_size, Ordering::Relaxed);
+            }
+        }
+        log::debug!(
+            "Cached file {:?} is dirty, size: {} -> {}",
+            this.item_id,
+            guard.file_size,
+            new_size,
+        );
+        guard.file_size = new_size;
+
+        Ok(UpdatedFileAttr {
+            item_id: this.item_id.clone(),
+            size: new_size,
+            mtime,
+            // CTag is currently unknown and will be filled after a successful upload.
+            c_tag: Tag(String::new()),
+        })
+    }
+
+    /// Mark the file dirty and spawn an upload task after `flush_delay`.
+    ///
+    /// Only the task of the latest call uploads. Previous tasks find the status re-locked with a
+    /// newer `lock_mtime` and quit, canceling their upload sessions if already started.
+    fn queue_upload(
+        self: &Arc<Self>,
+        guard: &mut MutexGuard<'_, FileCacheState>,
+        mtime: SystemTime,
+        onedrive: ManagedOnedrive,
+        client: reqwest::Client,
+        event_tx: mpsc::Sender<UpdateEvent>,
+        config: UploadConfig,
+    ) {
+        let (flush_tx, flush_rx) = oneshot::channel();
+        let (done_tx, done_rx) = watch::channel(false);
+        let init_lock_mtime = Instant::now();
+        // The session in progress uploads the content before this change.
+        self.remove_upload_sidecar();
+        *self.upload_resume.lock().unwrap() = None;
+        let first_dirty = match guard.status {
+            FileCacheStatus::Dirty { first_dirty, .. } => first_dirty,
+            _ => {
+                self.save_dirty_index(guard.file_size, mtime);
+                init_lock_mtime
+            }
+        };
+        guard.status = FileCacheStatus::Dirty {
+            lock_mtime: init_lock_mtime,
+            first_dirty,
+            flush_tx: Some(flush_tx),
+            done_rx,
+        };
+        // Continuous writes should not postpone the upload forever.
+        let flush_delay = config
+            .flush_delay
+            .min(config.max_flush_delay.saturating_sub(first_dirty.elapsed()));
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            let _ = time::timeout(flush_delay, flush_rx).await;
+            let transfer = METRICS.start_upload(&this.item_id);
+
+            let is_up_to_date = |status: &FileCacheStatus| matches!(status, FileCacheStatus::Dirty { lock_mtime, .. } if *lock_mtime == init_lock_mtime);
+
+            let mut verify_tries = 0;
+            // Parent id and name to upload a conflict copy to, instead of the file itself.
+            let mut conflict_copy: Option<(ItemId, String)> = None;
+            loop {
+                // Check not changed since last lock.
+                let file_size = {
+                    let guard = this.state.lock().await;
+                    if !is_up_to_date(&guard.status) {
+                        return;
+                    }
+                    guard.file_size
+                };
+                // Writes since the file became dirty are settled now.
+                this.save_dirty_index(file_size, mtime);
+
+                if conflict_copy.is_none() && this.conflicted.load(Ordering::Acquire) {
+                    match fetch_conflict_copy_location(&this.item_id, &onedrive).await {
+                        Ok(loc) => {
+                            log::info!(
+                                "Upload local {:?} as a conflict copy {:?}",
+                                this.item_id,
+                                loc.1,
+                            );
+                            conflict_copy = Some(loc);
+                        }
+                        Err(err) => {
+                            log::error!(
+                                "Failed to locate conflict copy of {:?}: {}",
+                                this.item_id,
+                                err,
+                            );
+                            // Keep it dirty. `fsync` on it fails.
+                            return;
+                        }
+                    }
+                }
+
+                log::info!("Uploading {:?} ({} B)", this.item_id, file_size);
+                this.emit(CacheEvent::UploadStarted(this.item_id.clone()));
+                let start_time = Instant::now();
+
+                // Resume the session persisted by the previous run.
+                let resume = this.upload_resume.lock().unwrap().take();
+                let resumed = match resume {
+                    Some(sidecar) if conflict_copy.is_none() => {
+                        let sess = UploadSession::from_upload_url(sidecar.upload_url.clone());
+                        match sess.get_meta(&client).await {
+                            Ok(meta) => {
+                                match resume_pos(&sidecar, file_size, &meta.next_expected_ranges) {
+                                    Some(pos) => {
+                                        log::info!(
+                                        "Resume upload session of {:?} from {}/{} (recorded {})",
+                                        this.item_id,
+                                        pos,
+                                        file_size,
+                                        sidecar.uploaded,
+                                    );
+                                        Some((sess, pos))
+                                    }
+                                    None => {
+                                        log::warn!(
+                                        "Upload session of {:?} cannot be resumed, start a new one",
+                                        this.item_id,
+                                    );
+                                        let _ = sess.delete(&client).await;
+                                        None
+                                    }
+                                }
+                            }
+                            Err(err) => {
+                                log::warn!(
+                                    "Upload session of {:?} is expired, start a new one: {}",
+                                    this.item_id,
+                                    err,
+                                );
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                if resumed.is_none() {
+                    this.remove_upload_sidecar();
+                }
+
+                // Create upload session.
+                let (sess, mut pos) = if let Some(resumed) = resumed {
+                    resumed
+                } else {
+                    let mut initial = DriveItem::default();
+                    initial.file_system_info = Some(Box::new(serde_json::json!({
+                        "lastModifiedDateTime": humantime::format_rfc3339_seconds(mtime).to_string(),
+                    })));
+                    let (location, option) = match &conflict_copy {
+                        Some((parent_id, name)) => (
+                            ItemLocation::child_of_id(
+                                parent_id,
+                                FileName::new(name).expect("Valid name"),
+                            ),
+                            DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Rename),
+                        ),
+                        None => {
+                            let mut option = DriveItemPutOption::new()
+                                .conflict_behavior(ConflictBehavior::Replace);
+                            if config.on_conflict != UploadConflictPolicy::Overwrite {
+                                option = option.if_match(&this.c_tag.lock().unwrap());
+                            }
+                            (ItemLocation::from_id(&this.item_id), option)
+                        }
+                    };
+                    let ret: Result<_> = match onedrive.get().await {
+                        Ok(onedrive) => onedrive
+                            .new_upload_session_with_initial_option(location, &initial, option)
+                            .await
+                            .map_err(Into::into),
+                        Err(err) => Err(err.into()),
+                    };
+                    let sess = match ret {
+                        Ok((sess, _)) => sess,
+                        // Changed in remote side before the change is synchronized.
+                        Err(Error::Conflict) => {
+                            log::warn!(
+                                "Upload of {:?} conflicts with remote changes, policy: {:?}",
+                                this.item_id,
+                                config.on_conflict,
+                            );
+                            let _ = event_tx
+                                .send(UpdateEvent::UploadConflict {
+                                    item_id: this.item_id.clone(),
+                                })
+                                .await;
+                            if config.on_conflict == UploadConflictPolicy::KeepBoth {
+                                match fetch_conflict_copy_location(&this.item_id, &onedrive).await {
+                                    Ok(loc) => {
+                                        log::info!(
+                                            "Upload local {:?} as a conflict copy {:?}",
+                                            this.item_id,
+                                            loc.1,
+                                        );
+                                        conflict_copy = Some(loc);
+                                        continue;
+                                    }
+                                    Err(err) => log::error!(
+                                        "Failed to locate conflict copy of {:?}: {}",
+                                        this.item_id,
+                                        err,
+                                    ),
+                                }
+                            }
+                            // Keep it dirty. `fsync` on it fails.
+                            return;
+                        }
+                        // Deleted in remote side before the deletion is synchronized.
+                        Err(Error::NotFound) => {
+                            log::error!(
+                                "Upload target {:?} no longer exists, local changes are discarded",
+                                this.item_id,
+                            );
+                            let mut guard = this.state.lock().await;
+                            if is_up_to_date(&guard.status) {
+                                guard.status = FileCacheStatus::Invalidated;
+                                this.bump_version();
+                                drop(guard);
+                                this.emit(CacheEvent::UploadAborted(this.item_id.clone()));
+                            }
+                            return;
+                        }
+                        Err(err) => {
+                            log::error!(
+                                "Failed to create upload session of {:?} ({} B), retrying: {}",
+                                this.item_id,
+                                file_size,
+                                err,
+                            );
+                            // Retry
+                            time::sleep(config.retry_delay).await;
+                            continue;
+                        }
+                    };
+                    (sess, 0)
+                };
+
+                // Upload parts.
+                let fragment_size = config.session_fragment_size;
+                let mut tries = 0;
+                let mut buf = vec![0u8; fragment_size];
+                let item = loop {
+                    let end = file_size.min(pos + fragment_size as u64);
+                    let len = (end - pos) as usize;
+                    {
+                        let mut guard = this.state.lock().await;
+                        if !is_up_to_date(&guard.status) {
+                            log::debug!("Upload session of {:?} outdates", this.item_id);
+                            drop(guard);
+                            if let Err(err) = sess.delete(&client).await {
+                                log::error!(
+                                    "Failed to delete outdated upload session of {:?}: {}",
+                                    this.item_id,
+                                    err,
+                                );
+                            }
+                            return;
+                        }
+                        assert_eq!(file_size, guard.file_size, "Truncation restarts uploading");
+                        this.save_upload_sidecar(&sess, file_size, pos);
+                        guard.cache_file.seek(SeekFrom::Start(pos)).await.unwrap();
+                        guard.cache_file.read_exact(&mut buf[..len]).await.unwrap();
+                    }
+
+                    let ret = upload_part(
+                        &sess,
+                        buf[..len].to_owned(),
+                        pos..end,
+                        file_size,
+                        &client,
+                        config.retry_delay,
+                    )
+                    .await;
+                    if ret.is_ok() {
+                        transfer.add(len as u64);
+                    }
+                    match ret {
+                        Ok(None) if end == file_size => {
+                            log::error!(
+                                "Upload session of {:?} is not completed after the last part, invalidate it",
+                                this.item_id,
+                            );
+                            if let Err(err) = sess.delete(&client).await {
+                                log::error!(
+                                    "Failed to delete upload session of {:?}: {}",
+                                    this.item_id,
+                                    err,
+                                );
+                            }
+                            this.remove_upload_sidecar();
+                            this.invalidate_uploaded(init_lock_mtime).await;
+                            return;
+                        }
+                        Ok(None) => {
+                            log::debug!(
+                                "Uploaded part {}..{}/{} of file {:?}",
+                                pos,
+                                end,
+                                file_size,
+                                this.item_id,
+                            );
+                            pos = end;
+                            tries = 0;
+                            this.emit(CacheEvent::UploadProgress {
+                                item_id: this.item_id.clone(),
+                                uploaded: pos,
+                                total: file_size,
+                            });
+                        }
+                        Ok(Some(_)) if end != file_size => {
+                            log::error!(
+                                "Upload session of {:?} is completed early at {}/{}, invalidate it",
+                                this.item_id,
+                                end,
+                                file_size,
+                            );
+                            this.remove_upload_sidecar();
+                            this.invalidate_uploaded(init_lock_mtime).await;
+                            return;
+                        }
+                        Ok(Some(item)) => break Some(item),
+                        // Throttling costs no retry.
+                        Err(err) if err.is::<Throttled>() => {
+                            let Throttled(delay) = *err.downcast_ref().unwrap();
+                            log::warn!(
+                                "Upload of {:?} throttled at {}, retry after {:?}",
+                                this.item_id,
+                                pos,
+                                delay,
+                            );
+                            time::sleep(delay).await;
+                        }
+                        Err(err) => {
+                            tries += 1;
+                            Metrics::add(&METRICS.upload_retries, 1);
+                            log::error!(
+                                "Failed to upload part {}..{}/{} of file {:?} (try {}/{}): {}",
+                                pos,
+                                end,
+                                file_size,
+                                this.item_id,
+                                tries,
+                                config.part_max_retry,
+                                err,
+                            );
+                            if config.part_max_retry < tries {
+                                break None;
+                            }
+                            // Retry
+                            time::sleep(config.retry_delay).await;
+                            // The part may be partially or fully received in spite of the error.
+                            match sess.get_meta(&client).await {
+                                Ok(meta) => match meta.next_expected_ranges.first() {
+                                    Some(range) if range.start < file_size => {
+                                        if range.start != pos {
+                                            log::info!(
+                                                "Resume uploading {:?} from {} as expected by server",
+                                                this.item_id,
+                                                range.start,
+                                            );
+                                        }
+                                        pos = range.start;
+                                    }
+                                    _ => {}
+                                },
+                                Err(err) => log::error!(
+                                    "Failed to query upload session of {:?}: {}",
+                                    this.item_id,
+                                    err,
+                                ),
+                            }
+                            continue;
+                        }
+                    }
+                };
+                this.remove_upload_sidecar();
+                let item = match item {
+                    Some(item) => item,
+                    None => {
+                        // Cancel the session, so the remote side doesn't keep partial content.
+                        // The cache is still dirty and the upload restarts with a new session.
+                        log::error!(
+                            "Cancel upload session of {:?} and restart uploading",
+                            this.item_id,
+                        );
+                        if let Err(err) = sess.delete(&client).await {
+                            log::error!(
+                                "Failed to delete failed upload session of {:?}: {}",
+                                this.item_id,
+                                err,
+                            );
+                        }
+                        time::sleep(config.retry_delay).await;
+                        continue;
+                    }
+                };
+
+                if config.verify_after_upload {
+                    // Hash the local content, which is unchanged if still up-to-date.
+                    let mut hasher = QuickXorHash::new();
+                    let mut pos = 0u64;
+                    while pos < file_size {
+                        let end = file_size.min(pos + fragment_size as u64);
+                        let len = (end - pos) as usize;
+                        let mut guard = this.state.lock().await;
+                        if !is_up_to_date(&guard.status) {
+                            log::debug!(
+                                "Upload of {:?} outdates during verification",
+                                this.item_id
+                            );
+                            return;
+                        }
+                        guard.cache_file.seek(SeekFrom::Start(pos)).await.unwrap();
+                        guard.cache_file.read_exact(&mut buf[..len]).await.unwrap();
+                        drop(guard);
+                        hasher.update(&buf[..len]);
+                        pos = end;
+                    }
+                    let local_hash = hasher.finish_base64();
+
+                    let remote_hash = match quick_xor_hash_of(&item) {
+                        Some(hash) => Some(hash.to_owned()),
+                        None => {
+                            let id = item.id.as_ref().unwrap_or(&this.item_id);
+                            fetch_quick_xor_hash(id, &onedrive).await
+                        }
+                    };
+                    match remote_hash {
+                        None => log::warn!(
+                            "Remote hash of {:?} is unavailable, skip verification",
+                            this.item_id,
+                        ),
+                        Some(hash) if hash == local_hash => {
+                            log::debug!("Verified upload of {:?}: {}", this.item_id, hash);
+                        }
+                        Some(hash) => {
+                            verify_tries += 1;
+                            log::error!(
+                                "Uploaded content of {:?} mismatches (try {}/{}), local hash: {}, remote hash: {}",
+                                this.item_id,
+                                verify_tries,
+                                config.verify_max_retry,
+                                local_hash,
+                                hash,
+                            );
+                            if config.verify_max_retry < verify_tries {
+                                // Keep it dirty. It's uploaded again on the next modification.
+                                log::error!(
+                                    "Verification of {:?} failed after {} tries, give up uploading",
+                                    this.item_id,
+                                    verify_tries,
+                                );
+                                return;
+                            }
+                            time::sleep(config.retry_delay).await;
+                            continue;
+                        }
+                    }
+                }
+
+                if conflict_copy.is_some() {
+                    log::info!(
+                        "Uploaded conflict copy {:?} of {:?} ({} B)",
+                        item.id,
+                        this.item_id,
+                        file_size,
+                    );
+                    // The copy shows up by sync. The original file is left with remote changes.
+                    let mut guard = this.state.lock().await;
+                    if is_up_to_date(&guard.status) {
+                        guard.status = FileCacheStatus::Invalidated;
+                        drop(guard);
+                        this.bump_version();
+                        this.emit(CacheEvent::Invalidated(this.item_id.clone()));
+                    }
+                    let _ = done_tx.send(true);
+                    return;
+                }
+
+                let parsed = InodeAttr::parse_response(&item).and_then(|(_, attr)| {
+                    let c_tag = attr.c_tag.clone().ok_or(Error::MissingField("cTag"))?;
+                    Ok((attr, c_tag))
+                });
+                let (attr, c_tag) = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        // The content is uploaded, but the new CTag is unknown.
+                        log::error!("Uploaded {:?} but failed to parse: {}", this.item_id, err);
+                        this.invalidate_uploaded(init_lock_mtime).await;
+                        let _ = done_tx.send(true);
+                        return;
+                    }
+                };
+                if item.id.as_ref() != Some(&this.item_id) || attr.size != file_size {
+                    log::error!(
+                        "Uploaded {:?} ({} B) but got a different item {:?} ({} B)",
+                        this.item_id,
+                        file_size,
+                        item.id,
+                        attr.size,
+                    );
+                    this.invalidate_uploaded(init_lock_mtime).await;
+                    return;
+                }
+                log::info!(
+                    "Uploaded {:?} ({} B) at {}, new c_tag: {:?}",
+                    this.item_id,
+                    file_size,
+                    format_rate(file_size, start_time.elapsed()),
+                    c_tag,
+                );
+
+                {
+                    let mut guard = this.state.lock().await;
+                    match guard.status {
+                        FileCacheStatus::Downloading { .. } => unreachable!(),
+                        FileCacheStatus::Dirty { lock_mtime, .. }
+                            if lock_mtime == init_lock_mtime =>
+                        {
+                            guard.status = FileCacheStatus::Available;
+                            this.emit(CacheEvent::UploadComplete(this.item_id.clone()));
+                        }
+                        FileCacheStatus::Invalidated => {
+                            log::warn!(
+                                "Cache invalidated during the upload of {:?}, maybe both changed? Suppress update event",
+                                this.item_id,
+                            );
+                            return;
+                        }
+                        FileCacheStatus::Unlinked => {
+                            log::warn!(
+                                "File {:?} is deleted during the upload. Suppress update event",
+                                this.item_id,
+                            );
+                            return;
+                        }
+                        // Race another upload.
+                        _ => {
+                            log::debug!("Racing upload? Suppress update event");
+                            return;
+                        }
+                    }
+                    *this.c_tag.lock().unwrap() = c_tag.clone();
+                    log::debug!("New c_tag of {:?} saved", this.item_id);
+                    this.save_index(file_size, &c_tag);
+                }
+
+                let _ = event_tx
+                    .send(UpdateEvent::UpdateFile(UpdatedFileAttr {
+                        item_id: this.item_id.clone(),
+                        size: attr.size,
+                        mtime: attr.mtime,
+                        c_tag,
+                    }))
+                    .await;
+                let _ = done_tx.send(true);
+
+                return;
+            }
+        });
+    }
+}
+
+/// Sparse cache of a file opened read-only in `blocks` mode. Blocks are downloaded by ranged
+/// requests when read, and kept until the entry is dropped.
+#[derive(Debug)]
+struct BlockFile {
+    item_id: ItemId,
+    c_tag: Tag,
+    file_size: u64,
+    block_size: u64,
+    state: Mutex<BlockFileState>,
+    cache_total_size: Weak<AtomicU64>,
+    blocks: Weak<BlockMap>,
+    max_total_size: u64,
+    events: broadcast::Sender<CacheEvent>,
+    /// Set when the remote side is changed or deleted. It's removed from `blocks` as well.
+    invalidated: AtomicBool,
+    /// Path of the named cache file if `persistent` is enabled. Block caches are never indexed.
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct BlockFileState {
+    cache_file: tokio::fs::File,
+    download_url: String,
+    /// Whether each block is downloaded.
+    present: Vec<bool>,
+    /// Total size of downloaded blocks, which is accounted in `total_size`.
+    downloaded_size: u64,
+}
+
+impl BlockFile {
+    fn emit(&self, event: CacheEvent) {
+        // Fails only if there is no subscriber.
+        let _ = self.events.send(event);
+    }
+
+    /// Read from cache, downloading missing blocks of the range first.
+    /// Reads of the same file are serialized.
+    async fn read(
+        this: &Arc<Self>,
+        offset: u64,
+        size: usize,
+        onedrive: &ManagedOnedrive,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+    ) -> Result<Bytes> {
+        let mut guard = this.state.lock().await;
+        if this.invalidated.load(Ordering::Acquire) {
+            return Err(Error::Invalidated);
+        }
+        if this.file_size <= offset || size == 0 {
+            return Ok(Bytes::new());
+        }
+        let end = this.file_size.min(offset + size as u64);
+
+        let last = (end - 1) / this.block_size;
+        let mut idx = offset / this.block_size;
+        while idx <= last {
+            if guard.present[idx as usize] {
+                idx += 1;
+                continue;
+            }
+            // Consecutive missing blocks are fetched in one request.
+            let first_missing = idx;
+            while idx <= last && !guard.present[idx as usize] {
+                idx += 1;
+            }
+            let range =
+                first_missing * this.block_size..(idx * this.block_size).min(this.file_size);
+            let len = range.end - range.start;
+            this.make_room(len);
+            match this
+                .fetch(&mut guard, range, onedrive, client, config)
+                .await?
+            {
+                Ok(()) => {}
+                Err(DownloadFailure::Changed) => {
+                    this.invalidate();
+                    return Err(Error::Invalidated);
+                }
+                Err(err) => return Err(err.into()),
+            }
+            guard.present[first_missing as usize..idx as usize].fill(true);
+            guard.downloaded_size += len;
+            if let Some(total) = this.cache_total_size.upgrade() {
+                total.fetch_add(len, Ordering::Relaxed);
+            }
+        }
+
+        let mut buf = vec![0u8; (end - offset) as usize];
+        guard
             .cache_file
             .seek(SeekFrom::Start(offset))
             .await
             .unwrap();
-        guard.cache_file.write_all(data).await.unwrap();
+        guard.cache_file.read_exact(&mut buf).await.unwrap();
+        Ok(buf.into())
+    }
+
+    /// Download `range` into the cache file. Errors writing the cache file are returned outside.
+    async fn fetch(
+        &self,
+        state: &mut BlockFileState,
+        range: Range<u64>,
+        onedrive: &ManagedOnedrive,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+    ) -> io::Result<DownloadResult> {
+        if let Err(host) = config.check_host(&state.download_url) {
+            log::error!("Refused to download from disallowed host {:?}", host);
+            return Ok(Err(DownloadFailure::HostNotAllowed(host)));
+        }
+        log::debug!("Fetching blocks {:?} of {:?}", range, self.item_id);
+        let refresher = UrlRefresher {
+            onedrive: onedrive.clone(),
+            item_id: self.item_id.clone(),
+            c_tag: self.c_tag.clone(),
+        };
+        let (tx, mut rx) = mpsc::channel(config.stream_buffer_chunks.max(1));
+        let waits = ThrottleWaits::default();
+        let download = download_file(
+            self.file_size,
+            range.start,
+            Some(range.end),
+            state.download_url.clone(),
+            refresher,
+            None,
+            tx,
+            Vec::new(),
+            client.clone(),
+            config.clone(),
+            waits.clone(),
+        );
+        let download = limit_duration(
+            range.end - range.start,
+            config.max_total_duration,
+            config.exclude_throttle_waits.then_some(&waits),
+            download,
+        );
+        let cache_file = &mut state.cache_file;
+        // Returning early drops `rx`, which stops the download.
+        let write = async move {
+            cache_file.seek(SeekFrom::Start(range.start)).await?;
+            while let Some(chunk) = rx.recv().await {
+                cache_file.write_all(&chunk).await?;
+            }
+            io::Result::Ok(())
+        };
+        let (download, write) = tokio::join!(download, write);
+        write?;
+        Ok(download)
+    }
+
+    /// Evict other block caches in LRU order until `len` more bytes fit in `max_total_size`.
+    /// Like the whole-file cache, evicted ones are still alive until all handles are closed.
+    fn make_room(self: &Arc<Self>, len: u64) {
+        let (total, blocks) = match (self.cache_total_size.upgrade(), self.blocks.upgrade()) {
+            (Some(total), Some(blocks)) => (total, blocks),
+            _ => return,
+        };
+        let mut blocks = blocks.lock().unwrap();
+        while self.max_total_size < total.load(Ordering::Relaxed) + len {
+            match blocks.remove_lru() {
+                Some((id, file)) if Arc::ptr_eq(&file, self) => {
+                    // Nothing else to evict.
+                    blocks.insert(id, file);
+                    break;
+                }
+                Some((id, _)) => {
+                    log::debug!("Evicted block cache {:?}", id);
+                    Metrics::add(&METRICS.cache_evictions, 1);
+                    self.emit(CacheEvent::Evicted(id));
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn invalidate(self: &Arc<Self>) {
+        self.invalidated.store(true, Ordering::Release);
+        if let Some(blocks) = self.blocks.upgrade() {
+            let mut blocks = blocks.lock().unwrap();
+            if blocks
+                .get_mut(&self.item_id)
+                .is_some_and(|file| Arc::ptr_eq(file, self))
+            {
+                blocks.remove(&self.item_id);
+            }
+        }
+        self.emit(CacheEvent::Invalidated(self.item_id.clone()));
+    }
+}
+
+/// Parent id and name for the conflict copy of a file, like `report (conflict).txt`.
+async fn fetch_conflict_copy_location(
+    item_id: &ItemId,
+    onedrive: &ManagedOnedrive,
+) -> Result<(ItemId, String)> {
+    let item = onedrive
+        .get()
+        .await?
+        .get_item_with_option(
+            ItemLocation::from_id(item_id),
+            ObjectOption::new().select(&[DriveItemField::name, DriveItemField::parent_reference]),
+        )
+        .await?
+        .ok_or(Error::NotFound)?;
+    let parent_id = item
+        .parent_reference
+        .as_ref()
+        .and_then(|parent| parent.get("id")?.as_str())
+        .ok_or(Error::MissingField("parentReference"))?;
+    let name = item.name.ok_or(Error::MissingField("name"))?;
+    let name = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{} (conflict).{}", stem, ext),
+        _ => format!("{} (conflict)", name),
+    };
+    Ok((ItemId(parent_id.to_owned()), name))
+}
+
+async fn fetch_quick_xor_hash(item_id: &ItemId, onedrive: &ManagedOnedrive) -> Option<String> {
+    let ret: Result<_> = match onedrive.get().await {
+        Ok(onedrive) => onedrive
+            .get_item_with_option(
+                ItemLocation::from_id(item_id),
+                ObjectOption::new().select(&[DriveItemField::file]),
+            )
+            .await
+            .map_err(Into::into),
+        Err(err) => Err(err.into()),
+    };
+    match ret {
+        Ok(item) => Some(quick_xor_hash_of(&item?)?.to_owned()),
+        Err(err) => {
+            log::error!("Failed to fetch hash of {:?}: {}", item_id, err);
+            None
+        }
+    }
+}
+
+// Average transfer rate in human readable form, like `1.5 MiB/s`.
+fn format_rate(bytes: u64, elapsed: Duration) -> String {
+    let rate = bytes as f64 / elapsed.as_secs_f64().max(1e-3);
+    if rate < (1 << 20) as f64 {
+        format!("{:.1} KiB/s", rate / (1 << 10) as f64)
+    } else {
+        format!("{:.1} MiB/s", rate / (1 << 20) as f64)
+    }
+}
+
+// Sub-ranges of `range` not covered by any of `covered`, in order.
+fn uncovered_ranges(range: Range<u64>, covered: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut covered = covered
+        .iter()
+        .filter(|r| r.start < range.end && range.start < r.end)
+        .cloned()
+        .collect::<Vec<_>>();
+    covered.sort_by_key(|r| r.start);
+    let mut ret = Vec::new();
+    let mut pos = range.start;
+    for r in covered {
+        if pos < r.start {
+            ret.push(pos..r.start);
+        }
+        pos = pos.max(r.end);
+    }
+    if pos < range.end {
+        ret.push(pos..range.end);
+    }
+    ret
+}
+
+impl Drop for DiskCache {
+    fn drop(&mut self) {
+        if self.config.disk_cache.persistent {
+            for (_, file) in self.cache.lock().unwrap().iter() {
+                file.keep_on_drop.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Drop for BlockFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+        if let Some(arc) = self.cache_total_size.upgrade() {
+            let downloaded_size = self.state.get_mut().downloaded_size;
+            let _ = arc.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                Some(total.saturating_sub(downloaded_size))
+            });
+        }
+    }
+}
+
+impl Drop for FileCache {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let index_path = index_path(path);
+            if !self.keep_on_drop.load(Ordering::Relaxed) || !index_path.exists() {
+                let _ = std::fs::remove_file(&index_path);
+                let _ = std::fs::remove_file(path);
+                self.remove_upload_sidecar();
+            }
+        }
+        if let Some(arc) = self.cache_total_size.upgrade() {
+            let file_size = self.state.get_mut().file_size;
+            // Saturating, since the total may be reconciled concurrently.
+            let _ = arc.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                Some(total.saturating_sub(file_size))
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::mock;
+
+    fn test_config(dir: &Path) -> Config {
+        test_config_with(dir, &["disk_cache.persistent=true"])
+    }
+
+    fn test_config_with(dir: &Path, options: &[&str]) -> Config {
+        let options = std::iter::once(format!(
+            "vfs.file.disk_cache.path={:?}",
+            dir.to_str().unwrap(),
+        ))
+        .chain(options.iter().map(|opt| format!("vfs.file.{}", opt)))
+        .collect::<Vec<_>>();
+        crate::config::Config::merge_from_default(None, &options)
+            .unwrap()
+            .vfs
+            .file
+    }
+
+    /// Wait until `server` receives `request`, or panic after a while.
+    async fn wait_request(server: &mock::MockServer, request: &str) {
+        let wait = async {
+            while !server.requests().iter().any(|received| received == request) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        if time::timeout(Duration::from_secs(5), wait).await.is_err() {
+            panic!("{:?} not received, got {:?}", request, server.requests());
+        }
+    }
+
+    /// Leave a dirty file `id` in the cache directory of drive `drive`, to be uploaded once
+    /// reloaded. Return the directory.
+    fn write_dirty(root: &Path, id: &str, content: &[u8]) -> PathBuf {
+        let dir = root.join("drive");
+        std::fs::create_dir_all(&dir).unwrap();
+        write_cache(
+            &dir,
+            &format!("{}.1", id),
+            content,
+            Some(IndexEntry {
+                item_id: ItemId(id.to_owned()),
+                size: content.len() as u64,
+                c_tag: Tag("c".to_owned()),
+                status: IndexStatus::Dirty,
+                mtime: Some(SystemTime::UNIX_EPOCH),
+            }),
+        );
+        dir
+    }
+
+    /// A pool of drive `drive` with persistent cache, whose requests all go to `server`.
+    fn new_pool(root: &Path, server: &mock::MockServer, options: &[&str]) -> FilePool {
+        let options = std::iter::once("disk_cache.persistent=true")
+            .chain(options.iter().copied())
+            .collect::<Vec<_>>();
+        // Updates are dropped.
+        let (event_tx, _) = mpsc::channel(16);
+        let (_, offline_rx) = watch::channel(false);
+        FilePool::new(
+            &DriveId("drive".to_owned()),
+            event_tx,
+            ManagedOnedrive::new_for_test(server.client()),
+            server.client(),
+            offline_rx,
+            test_config_with(root, &options),
+        )
+        .unwrap()
+    }
+
+    fn write_cache(dir: &Path, name: &str, content: &[u8], index: Option<IndexEntry>) -> PathBuf {
+        let path = dir.join(format!("{}{}", name, CACHE_FILE_SUFFIX));
+        std::fs::write(&path, content).unwrap();
+        if let Some(index) = index {
+            std::fs::write(index_path(&path), serde_json::to_vec(&index).unwrap()).unwrap();
+        }
+        path
+    }
+
+    #[tokio::test]
+    async fn reload_dirty_index() {
+        let root = tempfile::tempdir().unwrap();
+        let drive_id = DriveId("drive".to_owned());
+        let dir = root.path().join(drive_id.as_str());
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        // Written after the index is saved.
+        let dirty = write_cache(
+            &dir,
+            "dirty.1",
+            b"hello",
+            Some(IndexEntry {
+                item_id: ItemId("dirty".to_owned()),
+                size: 3,
+                c_tag: Tag("c1".to_owned()),
+                status: IndexStatus::Dirty,
+                mtime: Some(mtime),
+            }),
+        );
+        let outdated = write_cache(
+            &dir,
+            "outdated.1",
+            b"hello",
+            Some(IndexEntry {
+                item_id: ItemId("outdated".to_owned()),
+                size: 3,
+                c_tag: Tag("c2".to_owned()),
+                status: IndexStatus::Available,
+                mtime: None,
+            }),
+        );
+        let unindexed = write_cache(&dir, "unindexed.1", b"hello", None);
+
+        let (events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+        let (cache, reloaded) =
+            DiskCache::new(test_config(root.path()), &drive_id, events).unwrap();
+        assert_eq!(reloaded.len(), 1);
+        let (file, reloaded_mtime, resume) = &reloaded[0];
+        assert_eq!(file.item_id.as_str(), "dirty");
+        assert_eq!(*reloaded_mtime, mtime);
+        assert_eq!(file.state.lock().await.file_size, 5);
+        assert!(resume.is_none());
+        assert!(cache.get(&ItemId("dirty".to_owned())).is_some());
+
+        assert!(dirty.exists());
+        assert!(!outdated.exists() && !index_path(&outdated).exists());
+        assert!(!unindexed.exists());
+    }
+
+    #[tokio::test]
+    async fn purge_leftovers_on_start() {
+        let root = tempfile::tempdir().unwrap();
+        let drive_id = DriveId("drive".to_owned());
+        let dir = root.path().join(drive_id.as_str());
+        std::fs::create_dir_all(&dir).unwrap();
+        let index = |id: &str, status| IndexEntry {
+            item_id: ItemId(id.to_owned()),
+            size: 5,
+            c_tag: Tag("c".to_owned()),
+            status,
+            mtime: None,
+        };
+        let new = |options: &[&str]| {
+            let (events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+            DiskCache::new(test_config_with(root.path(), options), &drive_id, events)
+                .map(|(cache, dirty)| (cache, dirty.len()))
+        };
+
+        let available = write_cache(
+            &dir,
+            "available.1",
+            b"hello",
+            Some(index("available", IndexStatus::Available)),
+        );
+        let unindexed = write_cache(&dir, "unindexed.1", b"hello", None);
+        let sidecar = upload_sidecar_path(&dir, &ItemId("orphan".to_owned()));
+        std::fs::write(&sidecar, b"{}").unwrap();
+        let temp_index = dir.join(format!("temp.1{}.json.tmp", CACHE_FILE_SUFFIX));
+        std::fs::write(&temp_index, b"{").unwrap();
+        let unrelated = dir.join("unrelated");
+        std::fs::write(&unrelated, b"").unwrap();
+        let leftovers = [
+            &available,
+            &index_path(&available),
+            &unindexed,
+            &sidecar,
+            &temp_index,
+        ];
+
+        // Persistent cache keeps unused files with `purge_on_start` disabled.
+        let (cache, _) = new(&[
+            "disk_cache.persistent=true",
+            "disk_cache.purge_on_start=false",
+        ])
+        .unwrap();
+        assert!(cache.get(&ItemId("available".to_owned())).is_some());
+        drop(cache);
+        assert!(leftovers.iter().all(|path| path.exists()));
+
+        // So does non-persistent cache, which uses none.
+        let (cache, _) = new(&["disk_cache.purge_on_start=false"]).unwrap();
+        assert!(cache.get(&ItemId("available".to_owned())).is_none());
+        drop(cache);
+        assert!(leftovers.iter().all(|path| path.exists()));
+
+        // Dirty files are never removed, and fail the start without persistent cache.
+        let dirty = write_cache(
+            &dir,
+            "dirty.1",
+            b"hello",
+            Some(index("dirty", IndexStatus::Dirty)),
+        );
+        let err = new(&[]).err().unwrap();
+        assert!(err.to_string().contains("dirty.1.cache"), "{}", err);
+        assert!(leftovers.iter().all(|path| path.exists()));
+        assert!(dirty.exists() && index_path(&dirty).exists());
+        let (_, reloaded) = new(&["disk_cache.purge_on_start=false"]).unwrap();
+        assert_eq!(reloaded, 0);
+        assert!(dirty.exists() && index_path(&dirty).exists());
+
+        let (_, reloaded) = new(&["disk_cache.persistent=true"]).unwrap();
+        assert_eq!(reloaded, 1);
+        assert!(dirty.exists() && index_path(&dirty).exists());
+        assert!(available.exists() && index_path(&available).exists());
+        assert!(!unindexed.exists() && !sidecar.exists() && !temp_index.exists());
+        std::fs::remove_file(index_path(&dirty)).unwrap();
+
+        // Non-persistent cache removes all named cache files.
+        new(&[]).unwrap();
+        assert!(!available.exists() && !index_path(&available).exists());
+        assert!(!dirty.exists());
+        assert!(unrelated.exists());
+    }
+
+    #[tokio::test]
+    async fn cache_small_files_up_to_max_total_size() {
+        let root = tempfile::tempdir().unwrap();
+        let prestage = tempfile::tempdir().unwrap();
+        let drive_id = DriveId("drive".to_owned());
+        let config = test_config_with(
+            root.path(),
+            &[
+                &format!(
+                    "disk_cache.prestage_path={:?}",
+                    prestage.path().to_str().unwrap()
+                ),
+                "disk_cache.max_cached_file_size=8",
+                "disk_cache.max_total_size=20",
+            ],
+        );
+        let (events, mut event_rx) = broadcast::channel(CACHE_EVENT_CAPACITY);
+        let (cache, _) = DiskCache::new(config, &drive_id, events).unwrap();
+        let onedrive = ManagedOnedrive::new_for_test(reqwest::Client::new());
+        let (event_tx, _event_rx) = mpsc::channel(1);
+
+        // Pre-staged, so that nothing is downloaded.
+        let fetch = |id: &str| {
+            std::fs::write(prestage.path().join(id), b"hello").unwrap();
+            std::fs::write(
+                prestage.path().join(format!("{}.json", id)),
+                br#"{ "size": 5, "c_tag": "c" }"#,
+            )
+            .unwrap();
+            let meta = RemoteFileMeta {
+                size: 5,
+                c_tag: Tag("c".to_owned()),
+                download_url: "https://example.com/".to_owned(),
+                quick_xor_hash: None,
+            };
+            let alloc = cache
+                .try_alloc_and_fetch(
+                    &ItemId(id.to_owned()),
+                    &meta,
+                    cache.sync_seq.load(Ordering::Relaxed),
+                    None,
+                    onedrive.clone(),
+                    event_tx.clone(),
+                    reqwest::Client::new(),
+                )
+                .unwrap();
+            assert!(matches!(alloc, Alloc::Cached(_)));
+        };
+        let cached = |id: &str| cache.get(&ItemId(id.to_owned())).is_some();
+        let mut evicted = || {
+            std::iter::from_fn(|| event_rx.try_recv().ok())
+                .filter_map(|event| match event {
+                    CacheEvent::Evicted(id) => Some(id.as_str().to_owned()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Larger than `max_cached_file_size` in total.
+        for id in ["a", "b", "c", "d"] {
+            fetch(id);
+        }
+        assert!(["a", "b", "c", "d"].into_iter().all(cached));
+        assert_eq!(cache.total_size.load(Ordering::Relaxed), 20);
+        assert!(evicted().is_empty());
+
+        fetch("e");
+        assert_eq!(evicted(), ["a"]);
+        assert!(["b", "c", "d", "e"].into_iter().all(cached));
+        assert_eq!(cache.total_size.load(Ordering::Relaxed), 20);
+    }
+
+    #[tokio::test]
+    async fn reconcile_drifted_total_size() {
+        let root = tempfile::tempdir().unwrap();
+        let drive_id = DriveId("drive".to_owned());
+        let dir = root.path().join(drive_id.as_str());
+        std::fs::create_dir_all(&dir).unwrap();
+        write_cache(
+            &dir,
+            "dirty.1",
+            b"hello",
+            Some(IndexEntry {
+                item_id: ItemId("dirty".to_owned()),
+                size: 5,
+                c_tag: Tag("c".to_owned()),
+                status: IndexStatus::Dirty,
+                mtime: Some(SystemTime::UNIX_EPOCH),
+            }),
+        );
+        // Reconciled below in a shorter period.
+        let config = test_config_with(
+            root.path(),
+            &[
+                "disk_cache.persistent=true",
+                "disk_cache.reconcile_period=0",
+            ],
+        );
+        let (events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+        let (cache, reloaded) = DiskCache::new(config, &drive_id, events).unwrap();
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(cache.total_size.load(Ordering::Relaxed), 5);
+
+        cache.total_size.fetch_add(100, Ordering::Relaxed);
+        let reconciler = tokio::spawn(DiskCache::reconcile_thread(
+            Arc::downgrade(&cache.total_size),
+            Arc::downgrade(&cache.live_files),
+            Arc::downgrade(&cache.live_blocks),
+            Duration::from_millis(10),
+        ));
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.total_size.load(Ordering::Relaxed), 5);
+
+        // Stops with the cache.
+        drop((cache, reloaded));
+        time::timeout(Duration::from_secs(1), reconciler)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn lock_cache_dir_against_other_instances() {
+        let root = tempfile::tempdir().unwrap();
+        let drive_id = DriveId("drive".to_owned());
+        let new = || {
+            let (events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+            DiskCache::new(test_config(root.path()), &drive_id, events).map(|(cache, _)| cache)
+        };
+
+        let cache = new().unwrap();
+        let err = new().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(
+            err.to_string().contains("in use by another instance"),
+            "{}",
+            err
+        );
+        // Another drive has its own directory.
+        let (events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+        DiskCache::new(
+            test_config(root.path()),
+            &DriveId("other".to_owned()),
+            events,
+        )
+        .unwrap();
+
+        drop(cache);
+        new().unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_half_uploaded_session() {
+        let root = tempfile::tempdir().unwrap();
+        let drive_id = DriveId("drive".to_owned());
+        let dir = root.path().join(drive_id.as_str());
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let dirty_index = |id: &str| IndexEntry {
+            item_id: ItemId(id.to_owned()),
+            size: 10,
+            c_tag: Tag("c".to_owned()),
+            status: IndexStatus::Dirty,
+            mtime: Some(SystemTime::UNIX_EPOCH),
+        };
+        let write_sidecar = |id: &str, sidecar: &UploadSidecar| {
+            let path = upload_sidecar_path(&dir, &ItemId(id.to_owned()));
+            std::fs::write(&path, serde_json::to_vec(sidecar).unwrap()).unwrap();
+            path
+        };
+        let half = UploadSidecar {
+            upload_url: "https://upload.example.com/session".to_owned(),
+            file_size: 10,
+            uploaded: 5,
+        };
+        write_cache(&dir, "half.1", &[0; 10], Some(dirty_index("half")));
+        let half_path = write_sidecar("half", &half);
+        // Modified after the session is created, but the sidecar is somehow left.
+        write_cache(&dir, "grown.1", &[0; 12], Some(dirty_index("grown")));
+        let grown_path = write_sidecar("grown", &half);
+        let orphan_path = write_sidecar("orphan", &half);
+
+        let (events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+        let (_cache, mut reloaded) =
+            DiskCache::new(test_config(root.path()), &drive_id, events).unwrap();
+        reloaded.sort_by(|a, b| a.0.item_id.as_str().cmp(b.0.item_id.as_str()));
+        let resumes = reloaded
+            .iter()
+            .map(|(file, _, resume)| (file.item_id.as_str(), resume.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(resumes, [("grown", None), ("half", Some(half.clone()))]);
+        assert!(half_path.exists());
+        assert!(!grown_path.exists());
+        assert!(!orphan_path.exists());
+
+        let from = |start| ExpectRange { start, end: None };
+        assert_eq!(resume_pos(&half, 10, &[from(5)]), Some(5));
+        // The server received more than recorded.
+        assert_eq!(resume_pos(&half, 10, &[from(8)]), Some(8));
+        assert_eq!(resume_pos(&half, 10, &[]), None);
+        assert_eq!(resume_pos(&half, 12, &[from(5)]), None);
+    }
+
+    #[tokio::test]
+    async fn delete_failed_upload_session() {
+        let server = mock::MockServer::start(|req| match (&*req.method, &*req.path) {
+            ("GET", "/session") => mock::Response::json(
+                200,
+                serde_json::json!({
+                    "nextExpectedRanges": ["0-"],
+                    "expirationDateTime": "2100-01-01T00:00:00Z",
+                }),
+            ),
+            ("DELETE", "/session") => mock::Response::new(204),
+            _ => mock::Response::new(500),
+        });
+        let root = tempfile::tempdir().unwrap();
+        let dir = write_dirty(root.path(), "f", b"hello");
+        let sidecar = UploadSidecar {
+            upload_url: server.url("/session"),
+            file_size: 5,
+            uploaded: 0,
+        };
+        std::fs::write(
+            upload_sidecar_path(&dir, &ItemId("f".to_owned())),
+            serde_json::to_vec(&sidecar).unwrap(),
+        )
+        .unwrap();
+
+        let _pool = new_pool(
+            root.path(),
+            &server,
+            &[
+                "upload.flush_delay=0",
+                "upload.retry_delay=1",
+                "upload.part_max_retry=0",
+            ],
+        );
+
+        // The resumed session fails permanently, and is cancelled before restarting.
+        wait_request(&server, "DELETE /session").await;
+        assert_eq!(
+            server.requests()[..3],
+            ["GET /session", "PUT /session", "DELETE /session"],
+        );
+    }
+
+    #[tokio::test]
+    async fn abort_upload_of_deleted_target() {
+        let server = mock::MockServer::start(|_| {
+            mock::Response::json(
+                404,
+                serde_json::json!({
+                    "error": { "code": "itemNotFound", "message": "Item does not exist" },
+                }),
+            )
+        });
+        let root = tempfile::tempdir().unwrap();
+        write_dirty(root.path(), "f", b"hello");
+        let pool = new_pool(
+            root.path(),
+            &server,
+            &["upload.flush_delay=0", "upload.retry_delay=0"],
+        );
+        let mut events = pool.subscribe_events();
+
+        let aborted = async {
+            loop {
+                match events.recv().await.unwrap() {
+                    CacheEvent::UploadAborted(id) => break id,
+                    _ => continue,
+                }
+            }
+        };
+        let id = time::timeout(Duration::from_secs(5), aborted)
+            .await
+            .unwrap();
+        assert_eq!(id.as_str(), "f");
+        // Not retried, even without delay.
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            server.requests(),
+            ["POST /v1.0/me/drive/items/f/createUploadSession"],
+        );
+        let file = pool.disk_cache.as_ref().unwrap().get(&id).unwrap();
+        assert!(matches!(
+            file.state.lock().await.status,
+            FileCacheStatus::Invalidated,
+        ));
+    }
 
-        let new_size = guard.file_size.max(offset + data.len() as u64);
-        if guard.file_size < new_size {
-            if let Some(total) = this.cache_total_size.upgrade() {
-                total.fetch_add(new_size - guard.file_size, Ordering::Relaxed);
+    #[tokio::test]
+    async fn flush_all_skips_flush_delay() {
+        let item = |size: u64| {
+            serde_json::json!({
+                "id": "f",
+                "size": size,
+                "cTag": format!("c{}", size),
+                "file": {},
+                "fileSystemInfo": {
+                    "createdDateTime": "2020-01-01T00:00:00Z",
+                    "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+                },
+            })
+        };
+        let server = mock::MockServer::start(move |req| {
+            let url = |path| format!("{}{}", req.base_url, path);
+            match (&*req.method, &*req.path) {
+                ("GET", "/v1.0/me/drive/items/f") => {
+                    let mut item = item(5);
+                    item["@microsoft.graph.downloadUrl"] = url("/download").into();
+                    mock::Response::json(200, item)
+                }
+                ("GET", "/download") => mock::Response::ranged(req, b"hello"),
+                ("POST", "/v1.0/me/drive/items/f/createUploadSession") => mock::Response::json(
+                    200,
+                    serde_json::json!({
+                        "uploadUrl": url("/session"),
+                        "nextExpectedRanges": ["0-"],
+                        "expirationDateTime": "2100-01-01T00:00:00Z",
+                    }),
+                ),
+                ("PUT", "/session") => mock::Response::json(201, item(11)),
+                _ => mock::Response::new(500),
             }
-        }
-        log::debug!(
-            "Cached file {:?} is dirty, size: {} -> {}",
-            this.item_id,
-            guard.file_size,
-            new_size,
-        );
-        guard.file_size = new_size;
+        });
+        let root = tempfile::tempdir().unwrap();
+        let pool = new_pool(root.path(), &server, &["upload.flush_delay=600"]);
 
-        Ok(UpdatedFileAttr {
-            item_id: this.item_id.clone(),
-            size: new_size,
-            mtime,
-            // CTag is currently unknown and will be filled after a successful upload.
-            c_tag: Tag(String::new()),
-        })
+        let fh = pool.open(&ItemId("f".to_owned()), "f", true).await.unwrap();
+        pool.write(fh, 5, b" world").await.unwrap();
+        // Far before `flush_delay`.
+        time::timeout(Duration::from_secs(5), pool.flush_all())
+            .await
+            .unwrap();
+        assert_eq!(
+            server.requests()[2..],
+            [
+                "POST /v1.0/me/drive/items/f/createUploadSession",
+                "PUT /session",
+            ],
+        );
+        let file = pool
+            .disk_cache
+            .as_ref()
+            .unwrap()
+            .get(&ItemId("f".to_owned()));
+        assert!(matches!(
+            file.unwrap().state.lock().await.status,
+            FileCacheStatus::Available,
+        ));
     }
 
-    fn queue_upload(
-        self: &Arc<Self>,
-        guard: &mut MutexGuard<'_, FileCacheState>,
-        mtime: SystemTime,
-        onedrive: ManagedOnedrive,
-        client: reqwest::Client,
-        event_tx: mpsc::Sender<UpdateEvent>,
-        config: UploadConfig,
-    ) {
-        const UPLOAD_PART_SIZE: usize = 10 << 20;
-        static_assertions::const_assert!(
-            UPLOAD_PART_SIZE <= onedrive_api::UploadSession::MAX_PART_SIZE,
+    #[tokio::test]
+    async fn upload_by_fragments() {
+        const FRAGMENT: usize = UPLOAD_FRAGMENT_ALIGN;
+        let content = (0..FRAGMENT * 5 / 2).map(|i| i as u8).collect::<Vec<_>>();
+        let parts = Arc::new(SyncMutex::new(Vec::new()));
+        let server = {
+            let (content, parts) = (content.clone(), parts.clone());
+            mock::MockServer::start(move |req| match (&*req.method, &*req.path) {
+                ("POST", "/v1.0/me/drive/items/f/createUploadSession") => mock::Response::json(
+                    200,
+                    serde_json::json!({
+                        "uploadUrl": format!("{}/session", req.base_url),
+                        "nextExpectedRanges": ["0-"],
+                        "expirationDateTime": "2100-01-01T00:00:00Z",
+                    }),
+                ),
+                ("PUT", "/session") => {
+                    let range = req.header("content-range").unwrap().to_owned();
+                    let mut parts = parts.lock().unwrap();
+                    let start = parts.iter().map(|(_, len)| len).sum::<usize>();
+                    assert_eq!(req.body, content[start..start + req.body.len()]);
+                    parts.push((range, req.body.len()));
+                    if start + req.body.len() < content.len() {
+                        return mock::Response::new(202);
+                    }
+                    mock::Response::json(
+                        201,
+                        serde_json::json!({
+                            "id": "f",
+                            "size": content.len(),
+                            "cTag": "c2",
+                            "file": {},
+                            "fileSystemInfo": {
+                                "createdDateTime": "2020-01-01T00:00:00Z",
+                                "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+                            },
+                        }),
+                    )
+                }
+                _ => mock::Response::new(500),
+            })
+        };
+        let root = tempfile::tempdir().unwrap();
+        write_dirty(root.path(), "f", &content);
+        let pool = new_pool(
+            root.path(),
+            &server,
+            &[&format!("upload.session_fragment_size={}", FRAGMENT)],
         );
+        time::timeout(Duration::from_secs(5), pool.flush_all())
+            .await
+            .unwrap();
 
-        let (flush_tx, flush_rx) = oneshot::channel();
-        let (done_tx, done_rx) = watch::channel(false);
-        let init_lock_mtime = Instant::now();
-        guard.status = FileCacheStatus::Dirty {
-            lock_mtime: init_lock_mtime,
-            flush_tx: Some(flush_tx),
-            done_rx,
+        // Each part is read from the cache file into a buffer of one fragment.
+        let total = content.len();
+        let range = |start: usize, end: usize| {
+            (
+                format!("bytes {}-{}/{}", start, end - 1, total),
+                end - start,
+            )
         };
+        assert_eq!(
+            *parts.lock().unwrap(),
+            [
+                range(0, FRAGMENT),
+                range(FRAGMENT, FRAGMENT * 2),
+                range(FRAGMENT * 2, total),
+            ],
+        );
+    }
 
-        let this = self.clone();
-        tokio::spawn(async move {
-            let _ = time::timeout(config.flush_delay, flush_rx).await;
+    #[tokio::test]
+    async fn sync_skips_items_without_id() {
+        let root = tempfile::tempdir().unwrap();
+        let drive_id = DriveId("drive".to_owned());
+        let dir = root.path().join(drive_id.as_str());
+        std::fs::create_dir_all(&dir).unwrap();
+        write_cache(
+            &dir,
+            "f.1",
+            b"hello",
+            Some(IndexEntry {
+                item_id: ItemId("f".to_owned()),
+                size: 5,
+                c_tag: Tag("c1".to_owned()),
+                status: IndexStatus::Available,
+                mtime: None,
+            }),
+        );
+        let (events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+        let (cache, _) = DiskCache::new(test_config(root.path()), &drive_id, events).unwrap();
 
-            let is_up_to_date = |status: &FileCacheStatus| matches!(status, FileCacheStatus::Dirty { lock_mtime, .. } if *lock_mtime == init_lock_mtime);
+        let item = |value| serde_json::from_value::<DriveItem>(value).unwrap();
+        cache
+            .sync_items(&[
+                item(serde_json::json!({ "file": {}, "cTag": "c2" })),
+                item(serde_json::json!({ "id": "f", "file": {}, "cTag": "c1" })),
+            ])
+            .await;
+        assert!(cache.get(&ItemId("f".to_owned())).is_some());
+    }
 
-            loop {
-                // Check not changed since last lock.
-                let file_size = {
-                    let guard = this.state.lock().await;
-                    if !is_up_to_date(&guard.status) {
-                        return;
-                    }
-                    guard.file_size
-                };
+    #[tokio::test]
+    async fn unaligned_read_fetches_whole_blocks() {
+        const CONTENT: &[u8] = b"0123456789";
+        let server = mock::MockServer::start(|req| mock::Response::ranged(req, CONTENT));
+        let root = tempfile::tempdir().unwrap();
+        let config = test_config_with(
+            root.path(),
+            &["disk_cache.mode=\"blocks\"", "disk_cache.block_size=4"],
+        );
+        let download_config = config.download.clone();
+        let (events, _) = broadcast::channel(CACHE_EVENT_CAPACITY);
+        let (cache, _) = DiskCache::new(config, &DriveId("drive".to_owned()), events).unwrap();
+        let meta = RemoteFileMeta {
+            size: CONTENT.len() as u64,
+            c_tag: Tag("c".to_owned()),
+            download_url: server.url("/f"),
+            quick_xor_hash: None,
+        };
+        let file = cache
+            .open_blocks(
+                &ItemId("f".to_owned()),
+                &meta,
+                cache.sync_seq.load(Ordering::Relaxed),
+            )
+            .unwrap()
+            .unwrap();
+        let onedrive = ManagedOnedrive::new_for_test(reqwest::Client::new());
+        let client = reqwest::Client::new();
+        let read = |offset, size| {
+            BlockFile::read(&file, offset, size, &onedrive, &client, &download_config)
+        };
 
-                // Create upload session.
-                log::info!("Uploading {:?} ({} B)", this.item_id, file_size);
-                let mut initial = DriveItem::default();
-                initial.file_system_info = Some(Box::new(serde_json::json!({
-                    "lastModifiedDateTime": humantime::format_rfc3339_seconds(mtime).to_string(),
-                })));
-                let sess = match onedrive
-                    .get()
-                    .await
-                    .new_upload_session_with_initial_option(
-                        ItemLocation::from_id(&this.item_id),
-                        &initial,
-                        DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Replace),
-                    )
-                    .await
-                {
-                    Ok((sess, _)) => sess,
-                    Err(err) => {
-                        log::error!(
-                            "Failed to create upload session of {:?} ({} B), retrying: {}",
-                            this.item_id,
-                            file_size,
-                            err,
-                        );
-                        // Retry
-                        time::sleep(config.retry_delay).await;
-                        continue;
-                    }
-                };
+        // Straddling block 0 and 1.
+        assert_eq!(read(3, 2).await.unwrap(), &b"34"[..]);
+        assert_eq!(server.requests(), ["GET /f bytes=0-7"]);
+        {
+            let state = file.state.lock().await;
+            assert_eq!(state.present, [true, true, false]);
+            assert_eq!(state.downloaded_size, 8);
+        }
+        assert_eq!(cache.total_size.load(Ordering::Relaxed), 8);
 
-                // Upload parts.
-                let mut pos = 0u64;
-                let mut buf = vec![0u8; UPLOAD_PART_SIZE];
-                let item = loop {
-                    let end = file_size.min(pos + UPLOAD_PART_SIZE as u64);
-                    let len = (end - pos) as usize;
-                    {
-                        let mut guard = this.state.lock().await;
-                        if !is_up_to_date(&guard.status) {
-                            log::debug!("Upload session of {:?} outdates", this.item_id);
-                            if let Err(err) = sess.delete(onedrive.get().await.client()).await {
-                                log::error!(
-                                    "Failed to delete outdated upload session of {:?}: {}",
-                                    this.item_id,
-                                    err,
-                                );
-                            }
-                            return;
-                        }
-                        assert_eq!(file_size, guard.file_size, "Truncation restarts uploading");
-                        guard.cache_file.seek(SeekFrom::Start(pos)).await.unwrap();
-                        guard.cache_file.read_exact(&mut buf[..len]).await.unwrap();
-                    }
+        // Served from the materialized blocks.
+        assert_eq!(read(0, 8).await.unwrap(), &b"01234567"[..]);
+        assert_eq!(server.requests().len(), 1);
+        // The last block is shorter.
+        assert_eq!(read(7, 10).await.unwrap(), &b"789"[..]);
+        assert_eq!(server.requests()[1..], ["GET /f bytes=8-9"]);
+        assert_eq!(file.state.lock().await.downloaded_size, 10);
+    }
 
-                    match sess
-                        .upload_part(buf[..len].to_owned(), pos..end, file_size, &client)
-                        .await
-                    {
-                        Ok(None) => {
-                            assert_ne!(end, file_size);
-                            log::debug!(
-                                "Uploaded part {}..{}/{} of file {:?}",
-                                pos,
-                                end,
-                                file_size,
-                                this.item_id,
-                            );
-                            pos = end;
-                        }
-                        Ok(Some(item)) => {
-                            assert_eq!(end, file_size);
-                            break item;
-                        }
-                        Err(err) => {
-                            log::error!(
-                                "Failed to upload part {}..{}/{} of file {:?}, retrying: {}",
-                                pos,
-                                end,
-                                file_size,
-                                this.item_id,
-                                err,
-                            );
-                            // Retry
-                            time::sleep(config.retry_delay).await;
-                            continue;
-                        }
-                    }
-                };
+    /// Download `content` from `server` by `download_thread`, returning the result and the time
+    /// it takes.
+    async fn download_from(
+        server: &mock::MockServer,
+        content: &[u8],
+        options: &[&str],
+    ) -> (DownloadResult, Duration) {
+        let root = tempfile::tempdir().unwrap();
+        let meta = RemoteFileMeta {
+            size: content.len() as u64,
+            c_tag: Tag("c".to_owned()),
+            download_url: server.url("/download"),
+            quick_xor_hash: None,
+        };
+        let item_id = ItemId("f".to_owned());
+        let onedrive = ManagedOnedrive::new_for_test(reqwest::Client::new());
+        let (size_tx, _size_rx) = oneshot::channel();
+        let (tx, mut rx) = mpsc::channel(content.len());
+        let start = Instant::now();
+        let ret = download_thread(
+            meta.size,
+            0,
+            meta.download_url.clone(),
+            UrlRefresher::new(onedrive, &item_id, &meta),
+            size_tx,
+            tx,
+            Vec::new(),
+            reqwest::Client::new(),
+            test_config_with(root.path(), options).download,
+        )
+        .await;
+        let elapsed = start.elapsed();
+        if ret.is_ok() {
+            let mut buf = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                buf.extend_from_slice(&chunk);
+            }
+            assert_eq!(buf, content);
+        }
+        (ret, elapsed)
+    }
 
-                let attr = super::InodeAttr::parse_item(&item).expect("Invalid attrs");
-                assert_eq!(item.id.as_ref(), Some(&this.item_id));
-                assert_eq!(attr.size, file_size);
-                let c_tag = item.c_tag.expect("Missing c_tag");
-                log::info!(
-                    "Uploaded {:?} ({} B), new c_tag: {:?}",
-                    this.item_id,
-                    file_size,
-                    c_tag,
-                );
+    #[tokio::test]
+    async fn abort_slow_download_at_max_total_duration() {
+        const CONTENT: &[u8] = &[0; 100];
+        // Takes 2s in total.
+        let server = mock::MockServer::start(|req| {
+            mock::Response::ranged(req, CONTENT).byte_delay(Duration::from_millis(20))
+        });
+        let (ret, elapsed) =
+            download_from(&server, CONTENT, &["download.max_total_duration=1"]).await;
+        assert!(matches!(ret, Err(DownloadFailure::Timeout)), "{:?}", ret);
+        assert!(
+            Duration::from_secs(1) <= elapsed && elapsed < Duration::from_millis(1900),
+            "{:?}",
+            elapsed,
+        );
+    }
 
-                {
-                    let mut guard = this.state.lock().await;
-                    match guard.status {
-                        FileCacheStatus::Downloading { .. } => unreachable!(),
-                        FileCacheStatus::Dirty { lock_mtime, .. }
-                            if lock_mtime == init_lock_mtime =>
-                        {
-                            guard.status = FileCacheStatus::Available;
-                        }
-                        FileCacheStatus::Invalidated => {
-                            log::warn!(
-                                "Cache invalidated during the upload of {:?}, maybe both changed? Suppress update event",
-                                this.item_id,
-                            );
-                            return;
-                        }
-                        // Race another upload.
-                        _ => {
-                            log::debug!("Racing upload? Suppress update event");
-                            return;
-                        }
-                    }
-                    *this.c_tag.lock().unwrap() = c_tag.clone();
-                    log::debug!("New c_tag of {:?} saved", this.item_id);
+    #[tokio::test]
+    async fn exclude_throttle_waits_from_max_total_duration() {
+        const CONTENT: &[u8] = &[0; 10];
+        // Throttled for 1s first, then takes 0.3s.
+        let start_server = || {
+            let throttled = AtomicBool::new(false);
+            mock::MockServer::start(move |req| {
+                if !throttled.swap(true, Ordering::Relaxed) {
+                    return mock::Response::new(429).header("retry-after", "1");
                 }
+                mock::Response::ranged(req, CONTENT).byte_delay(Duration::from_millis(30))
+            })
+        };
 
-                let _ = event_tx
-                    .send(UpdateEvent::UpdateFile(UpdatedFileAttr {
-                        item_id: this.item_id.clone(),
-                        size: attr.size,
-                        mtime: attr.mtime,
-                        c_tag,
-                    }))
-                    .await;
-                let _ = done_tx.send(true);
+        let (ret, _) =
+            download_from(&start_server(), CONTENT, &["download.max_total_duration=1"]).await;
+        assert!(matches!(ret, Err(DownloadFailure::Timeout)), "{:?}", ret);
 
-                return;
+        let server = start_server();
+        let (ret, elapsed) = download_from(
+            &server,
+            CONTENT,
+            &[
+                "download.max_total_duration=1",
+                "download.exclude_throttle_waits=true",
+            ],
+        )
+        .await;
+        ret.unwrap();
+        assert!(Duration::from_secs(1) < elapsed, "{:?}", elapsed);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn throttle_waits_count_overlaps_once() {
+        let waits = ThrottleWaits::default();
+        tokio::join!(
+            waits.sleep(Duration::from_millis(100)),
+            waits.sleep(Duration::from_millis(100)),
+        );
+        let total = waits.total();
+        assert!(
+            Duration::from_millis(100) <= total && total < Duration::from_millis(150),
+            "{:?}",
+            total,
+        );
+    }
+
+    #[test]
+    fn remote_meta_requires_fields() {
+        let parse = |omit: &str| {
+            let mut value = serde_json::json!({
+                "id": "f",
+                "size": 5,
+                "cTag": "c",
+                "eTag": "e",
+                "@microsoft.graph.downloadUrl": "https://example.com/f",
+                "file": { "hashes": { "quickXorHash": "hash" } },
+            });
+            value.as_object_mut().unwrap().remove(omit);
+            RemoteFileMeta::parse(serde_json::from_value(value).unwrap())
+        };
+
+        let meta = parse("").unwrap();
+        assert_eq!(
+            (meta.size, meta.c_tag.as_str(), &*meta.download_url),
+            (5, "c", "https://example.com/f"),
+        );
+        assert_eq!(meta.quick_xor_hash.as_deref(), Some("hash"));
+        // Falls back to `eTag`.
+        assert_eq!(parse("cTag").unwrap().c_tag.as_str(), "e");
+        assert!(parse("file").unwrap().quick_xor_hash.is_none());
+        for field in ["size", "@microsoft.graph.downloadUrl"] {
+            match parse(field) {
+                Err(Error::MissingField(missing)) => assert_eq!(missing, field),
+                ret => panic!("Unexpected result without {}: {:?}", field, ret),
             }
+        }
+        let mut value = serde_json::json!({ "id": "f", "size": 5 });
+        value["@microsoft.graph.downloadUrl"] = "https://example.com/f".into();
+        assert!(matches!(
+            RemoteFileMeta::parse(serde_json::from_value(value).unwrap()),
+            Err(Error::MissingField("cTag")),
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalidate_streaming_read_window() {
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(Bytes::from_static(b"hello world")).await.unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(1);
+        let file = File::streaming(FileStreamState {
+            item_id: ItemId("f".to_owned()),
+            c_tag: Tag("c".to_owned()),
+            file_size: 11,
+            size_rx: None,
+            event_tx,
+            buf_start_pos: 0,
+            buf: ChunkBuf::new(64),
+            rx,
+            buffer_budgets: Vec::new(),
+            download_task: None,
+            failure: None,
+            invalidated: Arc::new(AtomicBool::new(false)),
         });
+        let state = match &file {
+            File::Streaming(state, _) => state.clone(),
+            _ => unreachable!(),
+        };
+        let window = ReadWindow {
+            offset: 0,
+            data: state.lock().await.read(0, 11).await.unwrap(),
+            eof: true,
+            version: file.read_version(),
+        };
+        assert_eq!(
+            window.get(6, 5, file.read_version()).as_deref(),
+            Some(&b"world"[..]),
+        );
+
+        // As `FilePool::sync_streams` does once it's changed in remote side.
+        state
+            .lock()
+            .await
+            .invalidated
+            .store(true, Ordering::Release);
+        assert!(window.get(6, 5, file.read_version()).is_none());
+        assert!(matches!(
+            state.lock().await.read(6, 5).await,
+            Err(Error::Invalidated),
+        ));
     }
-}
 
-impl Drop for FileCache {
-    fn drop(&mut self) {
-        if let Some(arc) = self.cache_total_size.upgrade() {
-            arc.fetch_sub(self.state.get_mut().file_size, Ordering::Relaxed);
-        }
+    #[test]
+    fn throttle_delay_from_retry_after() {
+        let default = Duration::from_secs(5);
+        let retry_after = |value: &str| {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(header::RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+
+        let headers = retry_after("120");
+        assert_eq!(
+            throttle_delay(StatusCode::TOO_MANY_REQUESTS, Some(&headers), default),
+            Some(Duration::from_secs(120)),
+        );
+        assert_eq!(
+            throttle_delay(StatusCode::SERVICE_UNAVAILABLE, Some(&headers), default),
+            Some(Duration::from_secs(120)),
+        );
+        // Not throttling, even with `Retry-After`.
+        assert_eq!(
+            throttle_delay(StatusCode::INTERNAL_SERVER_ERROR, Some(&headers), default),
+            None,
+        );
+        assert_eq!(
+            throttle_delay(StatusCode::OK, Some(&headers), default),
+            None
+        );
+
+        // 429 is throttling by itself, while 503 is only with `Retry-After`.
+        let empty = header::HeaderMap::new();
+        assert_eq!(
+            throttle_delay(StatusCode::TOO_MANY_REQUESTS, Some(&empty), default),
+            Some(default),
+        );
+        assert_eq!(
+            throttle_delay(StatusCode::TOO_MANY_REQUESTS, None, default),
+            Some(default),
+        );
+        assert_eq!(
+            throttle_delay(StatusCode::SERVICE_UNAVAILABLE, Some(&empty), default),
+            None,
+        );
+
+        // The HTTP-date form is not used by Graph and falls back to the default.
+        let headers = retry_after("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(
+            throttle_delay(StatusCode::TOO_MANY_REQUESTS, Some(&headers), default),
+            Some(default),
+        );
+
+        let headers = retry_after("86400");
+        assert_eq!(
+            throttle_delay(StatusCode::TOO_MANY_REQUESTS, Some(&headers), default),
+            Some(MAX_RETRY_AFTER),
+        );
+    }
+
+    #[test]
+    fn open_rules_match_by_extension_and_size() {
+        let options = [r#"vfs.file.disk_cache.open_rules=[
+            { extensions = ["mkv", "MP4"], strategy = "stream" },
+            { extensions = ["pdf"], max_size = 1000, strategy = "cache" },
+            { min_size = 100, max_size = 200, strategy = "stream" },
+        ]"#
+        .to_owned()];
+        let config = crate::config::Config::merge_from_default(None, &options).unwrap();
+        let rules = config.vfs.file.disk_cache.open_rules;
+        let strategy = |name: &str, size: u64| {
+            let rule = rules.iter().find(|rule| rule.matches(name, size))?;
+            Some(rule.strategy)
+        };
+
+        assert_eq!(strategy("movie.mkv", 1 << 30), Some(OpenStrategy::Stream));
+        assert_eq!(strategy("movie.MKV", 0), Some(OpenStrategy::Stream));
+        assert_eq!(strategy("clip.mp4", 0), Some(OpenStrategy::Stream));
+        assert_eq!(strategy("paper.pdf", 1000), Some(OpenStrategy::Cache));
+        assert_eq!(strategy("paper.Pdf", 150), Some(OpenStrategy::Cache));
+        // Too large for the pdf rule, and matched by none.
+        assert_eq!(strategy("paper.pdf", 1001), None);
+        // Rules without extensions match all files in the size range.
+        assert_eq!(strategy("notes.txt", 100), Some(OpenStrategy::Stream));
+        assert_eq!(strategy("notes", 200), Some(OpenStrategy::Stream));
+        assert_eq!(strategy("notes.txt", 99), None);
+        assert_eq!(strategy("notes.txt", 201), None);
+        // Extensions are after the last dot only.
+        assert_eq!(strategy("mkv", 0), None);
+        assert_eq!(strategy("movie.mkv.part", 0), None);
+    }
+
+    #[test]
+    fn check_download_host() {
+        let options = [
+            r#"vfs.file.download.allowed_hosts=["*.sharepoint.com", "files.1drv.com"]"#.to_owned(),
+        ];
+        let config = crate::config::Config::merge_from_default(None, &options).unwrap();
+        let download = config.vfs.file.download;
+
+        assert_eq!(
+            download.check_host("https://contoso.sharepoint.com/download?x=1"),
+            Ok(()),
+        );
+        assert_eq!(download.check_host("https://A.B.SharePoint.com/"), Ok(()));
+        assert_eq!(download.check_host("https://files.1drv.com/y4m"), Ok(()));
+        // Only subdomains match wildcards, and exact hosts match no subdomains.
+        assert_eq!(
+            download.check_host("https://sharepoint.com/"),
+            Err("sharepoint.com".to_owned()),
+        );
+        assert_eq!(
+            download.check_host("https://evilsharepoint.com/"),
+            Err("evilsharepoint.com".to_owned()),
+        );
+        assert_eq!(
+            download.check_host("https://a.files.1drv.com/"),
+            Err("a.files.1drv.com".to_owned()),
+        );
+        assert_eq!(
+            download.check_host("https://sharepoint.com.evil.example/"),
+            Err("sharepoint.com.evil.example".to_owned()),
+        );
+        assert_eq!(download.check_host("not a url"), Err(String::new()));
+
+        let config = crate::config::Config::merge_from_default(None, &[]).unwrap();
+        assert_eq!(
+            config.vfs.file.download.check_host("https://any.example/"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn uncovered_ranges_of_chunk() {
+        // As `(start, end)` pairs, since arrays of a single range read like a typo.
+        let uncovered = |range: (u64, u64), covered: &[(u64, u64)]| {
+            let covered = covered.iter().map(|&(l, r)| l..r).collect::<Vec<_>>();
+            uncovered_ranges(range.0..range.1, &covered)
+                .into_iter()
+                .map(|r| (r.start, r.end))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(uncovered((0, 100), &[]), [(0, 100)]);
+        assert_eq!(uncovered((0, 100), &[(0, 100)]), []);
+        assert_eq!(uncovered((0, 100), &[(200, 300)]), [(0, 100)]);
+        // Adjacent ranges do not overlap.
+        assert_eq!(uncovered((10, 20), &[(0, 10), (20, 30)]), [(10, 20)]);
+        assert_eq!(
+            uncovered((0, 100), &[(90, 110), (20, 30)]),
+            [(0, 20), (30, 90)],
+        );
+        // Overlapping and nested covered ranges.
+        assert_eq!(
+            uncovered((0, 100), &[(40, 60), (10, 50), (45, 55), (70, 80)]),
+            [(0, 10), (60, 70), (80, 100)],
+        );
+        assert_eq!(uncovered((50, 60), &[(0, 55), (58, 200)]), [(55, 58)]);
+    }
+
+    #[test]
+    fn segment_queue_takes_wanted_first() {
+        let mut queue = SegmentQueue {
+            taken: vec![false; 5],
+            next: 0,
+        };
+        assert_eq!(queue.take(None), Some(0));
+        // A waiting read jumps the queue.
+        assert_eq!(queue.take(Some(3)), Some(3));
+        assert_eq!(queue.take(None), Some(1));
+        // Already taken, so continue in order.
+        assert_eq!(queue.take(Some(3)), Some(2));
+        assert_eq!(queue.take(Some(0)), Some(4));
+        assert_eq!(queue.take(None), None);
+        assert_eq!(queue.take(Some(1)), None);
+        assert!(queue.taken.iter().all(|&taken| taken));
     }
 }
@@ -0,0 +1,96 @@
+//! Content hashes of files, to verify uploads against the hashes provided by OneDrive.
+//!
+//! QuickXorHash is the only one provided for all files.
+//! See: https://learn.microsoft.com/en-us/onedrive/developer/code-snippets/quickxorhash
+
+const WIDTH_IN_BITS: usize = 160;
+const SHIFT: usize = 11;
+
+#[derive(Debug, Clone, Default)]
+pub struct QuickXorHash {
+    /// 160 bits in total. The last cell only uses its lower 32 bits.
+    data: [u64; 3],
+    /// Bit offset to xor the next byte into.
+    shift: usize,
+    length: u64,
+}
+
+impl QuickXorHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            let (cell, offset) = (self.shift / 64, self.shift % 64);
+            let bits_in_cell = if cell == self.data.len() - 1 { 32 } else { 64 };
+            self.data[cell] ^= u64::from(b) << offset;
+            // Wrap around to the next cell.
+            if bits_in_cell < offset + 8 {
+                let next = (cell + 1) % self.data.len();
+                self.data[next] ^= u64::from(b) >> (bits_in_cell - offset);
+            }
+            self.shift = (self.shift + SHIFT) % WIDTH_IN_BITS;
+        }
+        self.length += bytes.len() as u64;
+    }
+
+    pub fn finish(&self) -> [u8; WIDTH_IN_BITS / 8] {
+        let mut ret = [0u8; WIDTH_IN_BITS / 8];
+        ret[..8].copy_from_slice(&self.data[0].to_le_bytes());
+        ret[8..16].copy_from_slice(&self.data[1].to_le_bytes());
+        ret[16..].copy_from_slice(&self.data[2].to_le_bytes()[..4]);
+        for (x, l) in ret[WIDTH_IN_BITS / 8 - 8..]
+            .iter_mut()
+            .zip(self.length.to_le_bytes())
+        {
+            *x ^= l;
+        }
+        ret
+    }
+
+    /// Base64 encoded digest, in the same form as `quickXorHash` of `hashes` facet.
+    pub fn finish_base64(&self) -> String {
+        base64::encode(self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(data: &[u8]) -> String {
+        let mut hasher = QuickXorHash::new();
+        hasher.update(data);
+        hasher.finish_base64()
+    }
+
+    #[test]
+    fn quick_xor_hash_vectors() {
+        assert_eq!(hash(b""), "AAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+        // Vectors of the reference implementation, as base64 of input and digest.
+        for (input, digest) in [
+            ("Sg==", "SgAAAAAAAAAAAAAAAQAAAAAAAAA="),
+            ("tbQ=", "taAFAAAAAAAAAAAAAgAAAAAAAAA="),
+            ("0pZP", "0rDEEwAAAAAAAAAAAwAAAAAAAAA="),
+            ("jRRDVA==", "jaDAEKgAAAAAAAAABAAAAAAAAAA="),
+        ] {
+            assert_eq!(hash(&base64::decode(input).unwrap()), digest, "{}", input);
+        }
+        // Long enough to wrap around all 160 bits.
+        assert_eq!(
+            hash(b"The quick brown fox jumps over the lazy dog"),
+            "bMSlbysmxJL6S75XwfMcQZOpcr4=",
+        );
+    }
+
+    #[test]
+    fn quick_xor_hash_in_chunks() {
+        let data = (0..=255u8).cycle().take(5000).collect::<Vec<_>>();
+        let mut hasher = QuickXorHash::new();
+        for chunk in data.chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finish_base64(), hash(&data));
+    }
+}
@@ -1,5 +1,8 @@
 //! Directory hierarchy and item attributes.
-use crate::vfs::error::{Error, Result};
+use crate::vfs::{
+    error::{Error, Result},
+    file::UpdatedFileAttr,
+};
 use http::StatusCode;
 use indexmap::IndexMap;
 use onedrive_api::{
@@ -10,9 +13,10 @@ use onedrive_api::{
 use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
-    sync::Mutex as SyncMutex,
+    sync::{Arc, Mutex as SyncMutex},
     time::SystemTime,
 };
+use unicode_normalization::UnicodeNormalization as _;
 
 #[derive(Debug, Clone)]
 pub struct InodeAttr {
@@ -24,6 +28,64 @@ pub struct InodeAttr {
     pub c_tag: Option<Tag>,
     // Whether this file is changed locally and waiting for uploading.
     pub dirty: bool,
+    pub meta: Arc<ItemMeta>,
+}
+
+/// Extra metadata of an item exposed as extended attributes, see `InodeAttr::xattrs`.
+#[derive(Debug, Clone, Default)]
+pub struct ItemMeta {
+    pub web_url: Option<String>,
+    pub e_tag: Option<Tag>,
+    /// Base64 encoded quickXorHash of the content, if provided.
+    pub quick_xor_hash: Option<String>,
+    pub shared: bool,
+    /// Display name of the application created the item.
+    pub created_by_app: Option<String>,
+}
+
+impl ItemMeta {
+    fn parse(item: &DriveItem) -> Self {
+        Self {
+            web_url: item.web_url.as_ref().map(|url| url.to_string()),
+            e_tag: item.e_tag.clone(),
+            quick_xor_hash: quick_xor_hash_of(item).map(Into::into),
+            shared: item.shared.is_some(),
+            created_by_app: item
+                .created_by
+                .as_ref()
+                .and_then(|by| by.get("application")?.get("displayName")?.as_str())
+                .map(Into::into),
+        }
+    }
+}
+
+/// Kind of an item classified by its facets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemKind {
+    File,
+    Directory,
+}
+
+impl ItemKind {
+    /// Classify an item with facet precedence `folder` > `file` > `package`.
+    ///
+    /// Items with only `package` facet (eg. OneNote notebooks) or without any of them
+    /// are not supported, and `None` is returned.
+    pub fn of(item: &DriveItem) -> Option<Self> {
+        if item.folder.is_some() {
+            Some(Self::Directory)
+        } else if item.file.is_some() {
+            Some(Self::File)
+        } else {
+            None
+        }
+    }
+
+    /// Whether an item unexpectedly carries more than one of `folder`, `file` and `package` facets.
+    pub fn is_ambiguous(item: &DriveItem) -> bool {
+        let facets = [&item.folder, &item.file, &item.package];
+        facets.iter().filter(|facet| facet.is_some()).count() > 1
+    }
 }
 
 impl InodeAttr {
@@ -47,43 +109,178 @@ impl InodeAttr {
                 size: item.size.context("Missing size")? as u64,
                 mtime: parse_time(fs_info, "lastModifiedDateTime")?,
                 crtime: parse_time(fs_info, "createdDateTime")?,
-                is_directory: item.folder.is_some(),
-                c_tag: if item.folder.is_some() {
+                is_directory: ItemKind::of(item) == Some(ItemKind::Directory),
+                c_tag: if ItemKind::of(item) == Some(ItemKind::Directory) {
                     None
                 } else {
-                    Some(item.c_tag.clone().context("Missing c_tag for file")?)
+                    Some(content_tag(item).context("Missing c_tag and e_tag for file")?)
                 },
                 dirty: false,
+                meta: Arc::new(ItemMeta::parse(item)),
             })
         }
 
         parse_attr(item).with_context(|| format!("Failed to parse item: {:?}", item))
     }
+
+    /// Parse the id and attributes of an item returned by a request, where an unexpected item is
+    /// an error of the request instead of a bug.
+    pub fn parse_response(item: &DriveItem) -> Result<(ItemId, InodeAttr)> {
+        let id = item.id.clone().ok_or(Error::MissingField("id"))?;
+        let attr = Self::parse_item(item).map_err(|err| Error::UnsupportedItem {
+            item_id: id.clone(),
+            reason: format!("{:#}", err),
+        })?;
+        Ok((id, attr))
+    }
+}
+
+/// The tag to detect content changes of a file, which is `cTag`, or `eTag` if it's unavailable.
+/// `eTag` also changes with metadata, which only causes extra invalidations.
+pub fn content_tag(item: &DriveItem) -> Option<Tag> {
+    if let Some(c_tag) = &item.c_tag {
+        return Some(c_tag.clone());
+    }
+    let e_tag = item.e_tag.clone()?;
+    log::debug!("Missing c_tag of {:?}, fallback to e_tag", item.id);
+    Some(e_tag)
+}
+
+pub fn quick_xor_hash_of(item: &DriveItem) -> Option<&str> {
+    item.file
+        .as_ref()?
+        .get("hashes")?
+        .get("quickXorHash")?
+        .as_str()
+}
+
+/// Values of HTTP caching headers of an item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpCacheHeaders {
+    pub etag: String,
+    pub last_modified: String,
+}
+
+impl InodeAttr {
+    /// Derive `ETag` and `Last-Modified` from cached metadata.
+    ///
+    /// Files with a known CTag get a strong ETag from it, which changes only if the content changes.
+    /// Otherwise (directories, or files with pending uploads), a weak ETag is derived from mtime and size.
+    pub fn http_cache_headers(&self) -> HttpCacheHeaders {
+        let etag = match &self.c_tag {
+            Some(c_tag) if !self.dirty && !c_tag.0.is_empty() => {
+                format!("\"{}\"", c_tag.0.replace(['"', '\\'], ""))
+            }
+            _ => {
+                let mtime = self
+                    .mtime
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                format!("W/\"{:x}-{:x}\"", mtime.as_secs(), self.size)
+            }
+        };
+        HttpCacheHeaders {
+            etag,
+            last_modified: httpdate::fmt_http_date(self.mtime),
+        }
+    }
+
+    /// Read-only extended attributes as `user.onedrive.*` names and values.
+    ///
+    /// Tags and the hash are omitted for files changed locally, since they are outdated until the
+    /// next sync. HTTP caching headers are always present, see `http_cache_headers`.
+    pub fn xattrs(&self) -> Vec<(&'static str, String)> {
+        let meta = &self.meta;
+        let mut ret = Vec::new();
+        if let Some(web_url) = &meta.web_url {
+            ret.push(("user.onedrive.web_url", web_url.clone()));
+        }
+        if !self.dirty {
+            if let Some(e_tag) = &meta.e_tag {
+                ret.push(("user.onedrive.etag", e_tag.0.clone()));
+            }
+            if let Some(c_tag) = &self.c_tag {
+                ret.push(("user.onedrive.ctag", c_tag.0.clone()));
+            }
+            if let Some(hash) = &meta.quick_xor_hash {
+                ret.push(("user.onedrive.hash", hash.clone()));
+            }
+        }
+        ret.push(("user.onedrive.shared", meta.shared.to_string()));
+        if let Some(app) = &meta.created_by_app {
+            ret.push(("user.onedrive.created_by_app", app.clone()));
+        }
+        let headers = self.http_cache_headers();
+        ret.push(("user.onedrive.http_etag", headers.etag));
+        ret.push(("user.onedrive.http_last_modified", headers.last_modified));
+        ret
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct DirEntry {
-    pub item_id: ItemId,
     pub name: String,
     pub attr: InodeAttr,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct Config {}
+pub struct Config {
+    duplicate_name: DuplicateNamePolicy,
+    ambiguous_item: AmbiguousItemPolicy,
+    case_insensitive_lookup: bool,
+}
+
+/// How to deal with items with more than one of `folder`, `file` and `package` facets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AmbiguousItemPolicy {
+    /// Log a warning and classify it by the facet precedence.
+    Warn,
+    /// Log a warning and skip the item.
+    Skip,
+}
+
+/// How to deal with two items having the same name under one directory.
+/// This should not happen on OneDrive, but a partially applied change batch may produce it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateNamePolicy {
+    /// Only the most recently modified item is visible under the name.
+    KeepNewest,
+    /// The most recently modified item keeps the name, others are shown with item id appended.
+    Suffix,
+}
 
 pub struct InodePool {
     tree: SyncMutex<InodeTree>,
+    ambiguous_item: AmbiguousItemPolicy,
 }
 
 struct InodeTree {
     // ItemId -> Content, (parent_id, parent_child_idx)
     map: HashMap<ItemId, (Inode, Option<(ItemId, usize)>)>,
+    // (parent_id, folded child name) -> Child item ids, if `case_insensitive_lookup` is enabled.
+    folded: Option<HashMap<(ItemId, String), Vec<ItemId>>>,
+    // ItemId -> CTags replaced by our own uploads. Changes with them are fetched before the upload
+    // is visible due to eventual consistency, and must not overwrite the uploaded attributes.
+    replaced_c_tags: HashMap<ItemId, Vec<Tag>>,
+    // (parent_id, child name) -> Items losing the name to another item, which are detached or
+    // suffixed by `duplicate_name`. The best of them takes the name back once it's freed.
+    losers: HashMap<(ItemId, String), Vec<ItemId>>,
+    // Loser item id -> The key of `losers` containing it.
+    lost_names: HashMap<ItemId, (ItemId, String)>,
+    duplicate_name: DuplicateNamePolicy,
 }
 
 impl InodeTree {
-    fn new() -> Self {
+    fn new(duplicate_name: DuplicateNamePolicy, case_insensitive_lookup: bool) -> Self {
         Self {
             map: HashMap::new(),
+            folded: case_insensitive_lookup.then(HashMap::new),
+            replaced_c_tags: HashMap::new(),
+            losers: HashMap::new(),
+            lost_names: HashMap::new(),
+            duplicate_name,
         }
     }
 
@@ -108,6 +305,10 @@ impl InodeTree {
         // Detach itself from parent.
         self.set_parent(id, None);
         let (inode, _) = self.map.remove(id).unwrap();
+        self.replaced_c_tags.remove(id);
+        // Names under it are gone.
+        self.losers.retain(|(parent_id, _), _| parent_id != id);
+        self.lost_names.retain(|_, (parent_id, _)| parent_id != id);
         // For directory, also detach all children.
         if let Inode::Dir { children, .. } = inode {
             for (_, child_id) in children {
@@ -118,22 +319,64 @@ impl InodeTree {
 
     // Set parent of an existing item, or panic if source item or parent item or does not exists.
     fn set_parent(&mut self, item_id: &ItemId, new_parent: Option<(ItemId, String)>) {
-        // Detach from old parent.
-        if let Some((parent_id, child_idx)) =
-            self.map.get_mut(item_id).expect("Item not exists").1.take()
-        {
-            let children = self.get_mut(&parent_id).unwrap().children_mut().unwrap();
-            children.swap_remove_index(child_idx);
-            if child_idx < children.len() {
-                // Previous last child is swapped to a `child_idx`. Maintain parent reference.
-                let swapped_child_item_id = children[child_idx].clone();
-                let (_, parent) = self.map.get_mut(&swapped_child_item_id).unwrap();
-                parent.as_mut().unwrap().1 = child_idx;
+        let freed = self.detach(item_id);
+        self.attach(item_id, new_parent);
+        if let Some((parent_id, name)) = freed {
+            self.restore_loser(&parent_id, &name);
+        }
+    }
+
+    // Detach an existing item from its parent, returning the parent and the name freed, if it's
+    // not a suffixed name of a loser.
+    fn detach(&mut self, item_id: &ItemId) -> Option<(ItemId, String)> {
+        let was_loser = self.forget_loser(item_id);
+        let (parent_id, child_idx) = self
+            .map
+            .get_mut(item_id)
+            .expect("Item not exists")
+            .1
+            .take()?;
+        let children = self.get_mut(&parent_id)?.children_mut().unwrap();
+        let (child_name, _) = children.swap_remove_index(child_idx).unwrap();
+        if child_idx < children.len() {
+            // Previous last child is swapped to a `child_idx`. Maintain parent reference.
+            let swapped_child_item_id = children[child_idx].clone();
+            let (_, parent) = self.map.get_mut(&swapped_child_item_id).unwrap();
+            parent.as_mut().unwrap().1 = child_idx;
+        }
+        if let Some(folded) = &mut self.folded {
+            let key = (parent_id.clone(), fold_name(&child_name));
+            let ids = folded.get_mut(&key).unwrap();
+            ids.retain(|id| id != item_id);
+            if ids.is_empty() {
+                folded.remove(&key);
             }
         }
+        (!was_loser).then_some((parent_id, child_name))
+    }
 
-        // Set a new parent.
+    fn attach(&mut self, item_id: &ItemId, new_parent: Option<(ItemId, String)>) {
         if let Some((new_parent_id, child_name)) = new_parent {
+            let child_name = match self.resolve_duplicate_name(&new_parent_id, child_name, item_id)
+            {
+                Some(name) => name,
+                None => return,
+            };
+            if let Some(folded) = &mut self.folded {
+                let ids = folded
+                    .entry((new_parent_id.clone(), fold_name(&child_name)))
+                    .or_default();
+                if !ids.is_empty() {
+                    log::warn!(
+                        "Name {:?} in directory {:?} is case-insensitively equal to {:?}, \
+                        which are not found by case-insensitive lookups",
+                        child_name,
+                        new_parent_id,
+                        ids,
+                    );
+                }
+                ids.push(item_id.clone());
+            }
             let (inode, _) = self.map.get_mut(&new_parent_id).expect("Item not exists");
             let children = inode.children_mut().unwrap();
             let (child_idx, old) = children.insert_full(child_name, item_id.clone());
@@ -142,6 +385,138 @@ impl InodeTree {
             self.map.get_mut(item_id).unwrap().1 = Some((new_parent_id, child_idx));
         }
     }
+
+    // Remove an item from `losers`, returning whether it was a loser.
+    fn forget_loser(&mut self, item_id: &ItemId) -> bool {
+        let key = match self.lost_names.remove(item_id) {
+            Some(key) => key,
+            None => return false,
+        };
+        if let Some(ids) = self.losers.get_mut(&key) {
+            ids.retain(|id| id != item_id);
+            if ids.is_empty() {
+                self.losers.remove(&key);
+            }
+        }
+        true
+    }
+
+    fn record_loser(&mut self, item_id: ItemId, parent_id: ItemId, name: String) {
+        let key = (parent_id, name);
+        self.losers
+            .entry(key.clone())
+            .or_default()
+            .push(item_id.clone());
+        self.lost_names.insert(item_id, key);
+    }
+
+    // Give a freed name back to the best item losing it, if any.
+    fn restore_loser(&mut self, parent_id: &ItemId, name: &str) {
+        let is_free = self
+            .get(parent_id)
+            .and_then(|parent| parent.children().ok())
+            .is_some_and(|children| !children.contains_key(name));
+        if !is_free {
+            return;
+        }
+        let key = (parent_id.clone(), name.to_owned());
+        let winner_id = match self.losers.get(&key).and_then(|ids| {
+            ids.iter()
+                .max_by_key(|id| self.duplicate_name_key(id))
+                .cloned()
+        }) {
+            Some(id) => id,
+            None => return,
+        };
+        log::info!(
+            "Name {:?} in directory {:?} is freed, restore it to {:?}",
+            name,
+            parent_id,
+            winner_id,
+        );
+        self.set_parent(&winner_id, Some(key));
+    }
+
+    // The greatest one wins a name.
+    // Item id breaks the tie, so the result doesn't depend on the order of changes.
+    fn duplicate_name_key(&self, id: &ItemId) -> (SystemTime, String) {
+        (self.get(id).unwrap().attr().mtime, id.as_str().to_owned())
+    }
+
+    // Resolve the name for attaching a detached `item_id` under `parent_id`.
+    // Returns `None` if the item should be kept detached.
+    fn resolve_duplicate_name(
+        &mut self,
+        parent_id: &ItemId,
+        name: String,
+        item_id: &ItemId,
+    ) -> Option<String> {
+        let children = self
+            .get(parent_id)
+            .expect("Item not exists")
+            .children()
+            .unwrap();
+        let other_id = match children.get(&name) {
+            None => return Some(name),
+            Some(other_id) => other_id.clone(),
+        };
+
+        let (winner_id, loser_id) =
+            if self.duplicate_name_key(&other_id) < self.duplicate_name_key(item_id) {
+                (item_id.clone(), other_id)
+            } else {
+                (other_id, item_id.clone())
+            };
+        log::warn!(
+            "Duplicated name {:?} in directory {:?}: {:?} and {:?}, keep {:?}",
+            name,
+            parent_id,
+            winner_id,
+            loser_id,
+            winner_id,
+        );
+
+        // Detach the old one if the new one wins. Its name is taken right after.
+        if winner_id == *item_id {
+            self.detach(&loser_id);
+        }
+        let loser_name = match self.duplicate_name {
+            DuplicateNamePolicy::KeepNewest => None,
+            DuplicateNamePolicy::Suffix => Some(suffixed_name(&name, &loser_id)).filter(|name| {
+                !self
+                    .get(parent_id)
+                    .unwrap()
+                    .children()
+                    .unwrap()
+                    .contains_key(name)
+            }),
+        };
+
+        if winner_id == *item_id {
+            if let Some(loser_name) = loser_name {
+                self.set_parent(&loser_id, Some((parent_id.clone(), loser_name)));
+            }
+            self.record_loser(loser_id, parent_id.clone(), name.clone());
+            Some(name)
+        } else {
+            self.record_loser(loser_id, parent_id.clone(), name);
+            loser_name
+        }
+    }
+}
+
+/// Case-folded NFC form of a name for case-insensitive lookups.
+/// Full case folding is approximated by lowercasing.
+fn fold_name(name: &str) -> String {
+    name.nfc().collect::<String>().to_lowercase()
+}
+
+// `foo.txt` -> `foo (ITEM_ID).txt`.
+pub fn suffixed_name(name: &str, item_id: &ItemId) -> String {
+    match name.rfind('.').filter(|&pos| pos != 0) {
+        Some(pos) => format!("{} ({}){}", &name[..pos], item_id.as_str(), &name[pos..]),
+        None => format!("{} ({})", name, item_id.as_str()),
+    }
 }
 
 #[derive(Debug)]
@@ -216,11 +591,21 @@ impl InodePool {
         DriveItemField::file,
         DriveItemField::file_system_info,
         DriveItemField::folder,
+        // Facet classification.
+        DriveItemField::package,
+        // ItemMeta. `eTag` is selected by `FilePool`.
+        DriveItemField::web_url,
+        DriveItemField::shared,
+        DriveItemField::created_by,
     ];
 
-    pub fn new(_config: Config) -> Self {
+    pub fn new(config: Config) -> Self {
         Self {
-            tree: SyncMutex::new(InodeTree::new()),
+            tree: SyncMutex::new(InodeTree::new(
+                config.duplicate_name,
+                config.case_insensitive_lookup,
+            )),
+            ambiguous_item: config.ambiguous_item,
         }
     }
 
@@ -230,14 +615,30 @@ impl InodePool {
         Ok(tree.get(item_id).ok_or(Error::NotFound)?.attr().clone())
     }
 
+    /// Get the name of an item, or `None` for root or detached items.
+    pub fn get_name(&self, item_id: &ItemId) -> Option<String> {
+        let tree = self.tree.lock().unwrap();
+        let (parent_id, child_idx) = tree.map.get(item_id)?.1.as_ref()?;
+        let children = tree.get(parent_id)?.children().ok()?;
+        let (name, _) = children.get_index(*child_idx)?;
+        Some(name.clone())
+    }
+
     /// Lookup a child by name of an directory item.
     pub fn lookup(&self, parent_id: &ItemId, child_name: &FileName) -> Result<ItemId> {
         let tree = self.tree.lock().unwrap();
         let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
-        children
-            .get(child_name.as_str())
-            .cloned()
-            .ok_or(Error::NotFound)
+        if let Some(id) = children.get(child_name.as_str()) {
+            return Ok(id.clone());
+        }
+        let folded = tree.folded.as_ref().ok_or(Error::NotFound)?;
+        match folded
+            .get(&(parent_id.clone(), fold_name(child_name.as_str())))
+            .map(|ids| &ids[..])
+        {
+            Some([id]) => Ok(id.clone()),
+            _ => Err(Error::NotFound),
+        }
     }
 
     /// Read entries of a directory.
@@ -253,7 +654,6 @@ impl InodePool {
             let child_attr = tree.get(child_id).unwrap().attr();
             entries.push(DirEntry {
                 name: name.clone(),
-                item_id: child_id.clone(),
                 attr: child_attr.clone(),
             });
         }
@@ -281,8 +681,7 @@ impl InodePool {
                 DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Fail),
             )
             .await?;
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attrs");
-        let id = item.id.expect("Missing id");
+        let (id, attr) = InodeAttr::parse_response(&item)?;
 
         let mut tree = self.tree.lock().unwrap();
         tree.insert_item(id.clone(), attr.clone());
@@ -314,14 +713,11 @@ impl InodePool {
                     return Err(Error::Uploading);
                 }
             }
-            let item_id = old_children
+            // Pending uploads address the item by id, so a dirty file can still be moved.
+            old_children
                 .get(old_name.as_str())
                 .ok_or(Error::NotFound)?
-                .clone();
-            if tree.get(&item_id).unwrap().attr().dirty {
-                return Err(Error::Uploading);
-            }
-            item_id
+                .clone()
         };
 
         match onedrive
@@ -371,7 +767,7 @@ impl InodePool {
         name: &FileName,
         directory: bool,
         onedrive: &OneDrive,
-    ) -> Result<()> {
+    ) -> Result<ItemId> {
         let item_id = {
             let tree = self.tree.lock().unwrap();
             let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
@@ -392,7 +788,7 @@ impl InodePool {
         onedrive.delete(ItemLocation::from_id(&item_id)).await?;
 
         self.tree.lock().unwrap().remove_item(&item_id);
-        Ok(())
+        Ok(item_id)
     }
 
     /// Update attribute of an item. Return updated attribute.
@@ -408,6 +804,31 @@ impl InodePool {
         inode.attr().clone()
     }
 
+    /// Set attributes of a file just uploaded by us, see `InodeTree::replaced_c_tags`.
+    pub fn update_uploaded(&self, updated: &UpdatedFileAttr) {
+        let mut tree = self.tree.lock().unwrap();
+        let inode = match tree.get_mut(&updated.item_id) {
+            Some(inode) => inode,
+            None => return,
+        };
+        let old_attr = inode.attr().clone();
+        inode.set_attr(InodeAttr {
+            size: updated.size,
+            mtime: updated.mtime,
+            c_tag: Some(updated.c_tag.clone()),
+            dirty: true,
+            ..old_attr
+        });
+        if let Some(old_c_tag) = old_attr.c_tag {
+            if old_c_tag != updated.c_tag {
+                tree.replaced_c_tags
+                    .entry(updated.item_id.clone())
+                    .or_default()
+                    .push(old_c_tag);
+            }
+        }
+    }
+
     /// Insert a new item to a directory.
     pub fn insert_item(
         &self,
@@ -428,7 +849,10 @@ impl InodePool {
         mtime: SystemTime,
         onedrive: &OneDrive,
     ) -> Result<InodeAttr> {
-        let opt = ObjectOption::new().select(Self::SYNC_SELECT_FIELDS);
+        let opt = ObjectOption::new()
+            .select(Self::SYNC_SELECT_FIELDS)
+            // Required by `InodeAttr` of files.
+            .select(&[DriveItemField::c_tag, DriveItemField::e_tag]);
         let mut patch = DriveItem::default();
 
         patch.file_system_info = Some(Box::new(serde_json::json!({
@@ -437,7 +861,7 @@ impl InodePool {
         let item = onedrive
             .update_item_with_option(ItemLocation::from_id(item_id), &patch, opt)
             .await?;
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attr");
+        let (_, attr) = InodeAttr::parse_response(&item)?;
         log::debug!(
             "Set attribute of {:?}: mtime -> {}",
             item_id,
@@ -458,15 +882,37 @@ impl InodePool {
         let mut dir_marked_deleted = HashSet::new();
 
         for item in updated {
-            if !(item.file.is_some() || item.folder.is_some()) {
-                continue;
+            let kind = match ItemKind::of(item) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let item_id = match &item.id {
+                Some(id) => id,
+                None => {
+                    log::warn!("Skip item without id: {:?}", item);
+                    continue;
+                }
+            };
+            if ItemKind::is_ambiguous(item) {
+                match self.ambiguous_item {
+                    AmbiguousItemPolicy::Warn => {
+                        log::warn!(
+                            "Item {:?} has ambiguous facets, treat as {:?}",
+                            item_id,
+                            kind
+                        );
+                    }
+                    AmbiguousItemPolicy::Skip => {
+                        log::warn!("Skip item {:?} with ambiguous facets", item_id);
+                        continue;
+                    }
+                }
             }
-            let item_id = item.id.as_ref().expect("Missing id");
 
             // Remove an existing item.
             if item.deleted.is_some() {
                 if tree.get(item_id).is_some() {
-                    if item.folder.is_some() {
+                    if kind == ItemKind::Directory {
                         log::debug!("Mark remove for directory {:?}", item_id);
                         dir_marked_deleted.insert(item_id);
                     } else {
@@ -477,18 +923,31 @@ impl InodePool {
                 continue;
             }
 
-            let parent_id = if item.root.is_some() {
+            // New parent and name for non-root items.
+            let parent = if item.root.is_some() {
                 None
             } else {
-                let parent_id = (|| {
+                let parent_id = match (|| {
                     let id = item.parent_reference.as_ref()?.get("id")?.as_str()?;
                     Some(ItemId(id.to_owned()))
-                })()
-                .expect("Missing new parent for non-root item");
+                })() {
+                    Some(parent_id) => parent_id,
+                    None => {
+                        log::warn!("Skip non-root item {:?} without new parent", item_id);
+                        continue;
+                    }
+                };
+                let name = match &item.name {
+                    Some(name) => name.clone(),
+                    None => {
+                        log::warn!("Skip non-root item {:?} without name", item_id);
+                        continue;
+                    }
+                };
 
                 match tree.get(&parent_id) {
                     // Normal case: parent is a directory.
-                    Some(Inode::Dir { .. }) => Some(parent_id),
+                    Some(Inode::Dir { .. }) => Some((parent_id, name)),
                     // Some items are children of non-directories. This can happen on `.one` files.
                     // We simply skip them.
                     Some(Inode::File { .. }) => {
@@ -509,25 +968,43 @@ impl InodePool {
                 }
             };
 
+            // A single unexpected item should not break the whole tree.
+            let attr = match InodeAttr::parse_item(item) {
+                Ok(attr) => attr,
+                Err(err) => {
+                    log::warn!("Skip unsupported item {:?}: {:#}", item_id, err);
+                    continue;
+                }
+            };
+            // Changes may be fetched before our own upload is visible due to eventual
+            // consistency. Any other version is newer, which ends the lag.
+            let is_stale = match (&attr.c_tag, tree.replaced_c_tags.get(item_id)) {
+                (Some(c_tag), Some(replaced)) if replaced.contains(c_tag) => true,
+                (_, Some(_)) => {
+                    tree.replaced_c_tags.remove(item_id);
+                    false
+                }
+                (_, None) => false,
+            };
             match tree.get_mut(item_id) {
                 // Insert a new item.
                 None => {
                     log::debug!("Insert item {:?}", item_id);
-                    let attr = InodeAttr::parse_item(item).expect("Invalid attrs");
                     tree.insert_item(item_id.clone(), attr);
                 }
+                Some(_) if is_stale => {
+                    log::debug!("Skip stale update of locally uploaded item {:?}", item_id);
+                }
                 // Update an existing item.
                 Some(inode) => {
                     log::debug!("Update item {:?}", item_id);
-                    let attr = InodeAttr::parse_item(item).expect("Invalid attrs");
                     inode.set_attr(attr);
                 }
             }
 
             // Update parent for non-root items.
-            if let Some(parent_id) = parent_id {
-                let name = item.name.clone().expect("Missing name");
-                tree.set_parent(item_id, Some((parent_id, name)));
+            if parent.is_some() {
+                tree.set_parent(item_id, parent);
             }
         }
 
@@ -544,3 +1021,279 @@ impl InodePool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(options: &[&str]) -> InodePool {
+        let options = options
+            .iter()
+            .map(|opt| opt.to_string())
+            .collect::<Vec<_>>();
+        let config = crate::config::Config::merge_from_default(None, &options).unwrap();
+        InodePool::new(config.vfs.inode)
+    }
+
+    fn item(value: serde_json::Value) -> DriveItem {
+        serde_json::from_value(value).unwrap()
+    }
+
+    const FS_INFO: &str = "2020-01-01T00:00:00Z";
+
+    fn root() -> DriveItem {
+        item(serde_json::json!({
+            "id": "root",
+            "root": {},
+            "folder": {},
+            "size": 0,
+            "fileSystemInfo": { "createdDateTime": FS_INFO, "lastModifiedDateTime": FS_INFO },
+        }))
+    }
+
+    fn file(id: &str, name: &str, size: u64, c_tag: &str) -> DriveItem {
+        file_at(id, name, size, c_tag, FS_INFO)
+    }
+
+    fn file_at(id: &str, name: &str, size: u64, c_tag: &str, mtime: &str) -> DriveItem {
+        item(serde_json::json!({
+            "id": id,
+            "name": name,
+            "parentReference": { "id": "root" },
+            "file": {},
+            "size": size,
+            "cTag": c_tag,
+            "fileSystemInfo": { "createdDateTime": FS_INFO, "lastModifiedDateTime": mtime },
+        }))
+    }
+
+    fn deleted(id: &str) -> DriveItem {
+        item(serde_json::json!({ "id": id, "file": {}, "deleted": {} }))
+    }
+
+    fn names(pool: &InodePool) -> Vec<String> {
+        let mut names = pool
+            .read_dir(&id("root"), 0, 100)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn lookup(pool: &InodePool, name: &str) -> Option<String> {
+        let id = pool
+            .lookup(&id("root"), FileName::new(name).unwrap())
+            .ok()?;
+        Some(id.as_str().to_owned())
+    }
+
+    #[test]
+    fn suffixed_name_keeps_extension() {
+        let id = id("ID");
+        assert_eq!(suffixed_name("foo.txt", &id), "foo (ID).txt");
+        assert_eq!(suffixed_name("foo.tar.gz", &id), "foo.tar (ID).gz");
+        assert_eq!(suffixed_name("foo", &id), "foo (ID)");
+        assert_eq!(suffixed_name(".bashrc", &id), ".bashrc (ID)");
+    }
+
+    #[test]
+    fn restore_suffixed_duplicate_name() {
+        let pool = pool(&[r#"vfs.inode.duplicate_name="suffix""#]);
+        pool.sync_items(&[
+            root(),
+            file_at("new", "a.txt", 1, "c1", "2021-01-01T00:00:00Z"),
+            file_at("old", "a.txt", 1, "c2", "2020-01-01T00:00:00Z"),
+        ]);
+        assert_eq!(names(&pool), ["a (old).txt", "a.txt"]);
+        assert_eq!(lookup(&pool, "a.txt").as_deref(), Some("new"));
+
+        pool.sync_items(&[deleted("new")]);
+        assert_eq!(names(&pool), ["a.txt"]);
+        assert_eq!(lookup(&pool, "a.txt").as_deref(), Some("old"));
+    }
+
+    #[test]
+    fn restore_detached_duplicate_name() {
+        let pool = pool(&[r#"vfs.inode.duplicate_name="keep_newest""#]);
+        pool.sync_items(&[
+            root(),
+            file_at("old", "a.txt", 1, "c1", "2020-01-01T00:00:00Z"),
+            file_at("new", "a.txt", 1, "c2", "2021-01-01T00:00:00Z"),
+        ]);
+        assert_eq!(names(&pool), ["a.txt"]);
+        assert_eq!(lookup(&pool, "a.txt").as_deref(), Some("new"));
+
+        // The winner is renamed away.
+        pool.sync_items(&[file_at("new", "b.txt", 1, "c2", "2021-01-01T00:00:00Z")]);
+        assert_eq!(names(&pool), ["a.txt", "b.txt"]);
+        assert_eq!(lookup(&pool, "a.txt").as_deref(), Some("old"));
+    }
+
+    fn id(id: &str) -> ItemId {
+        ItemId(id.to_owned())
+    }
+
+    #[test]
+    fn skip_stale_delta_after_upload() {
+        let pool = pool(&[]);
+        pool.sync_items(&[root(), file("f", "a.txt", 1, "c1")]);
+        pool.update_uploaded(&UpdatedFileAttr {
+            item_id: id("f"),
+            size: 2,
+            mtime: SystemTime::now(),
+            c_tag: Tag("c2".to_owned()),
+        });
+
+        // Fetched before the upload is visible.
+        pool.sync_items(&[file("f", "a.txt", 1, "c1")]);
+        let attr = pool.get_attr(&id("f")).unwrap();
+        assert_eq!((attr.size, attr.c_tag), (2, Some(Tag("c2".to_owned()))));
+        let entries = pool.read_dir(&id("root"), 0, 10).unwrap();
+        assert_eq!(entries[0].attr.size, 2);
+
+        // Edited in remote side after the upload, with an older mtime.
+        pool.sync_items(&[file("f", "a.txt", 3, "c3")]);
+        let attr = pool.get_attr(&id("f")).unwrap();
+        assert_eq!((attr.size, attr.c_tag), (3, Some(Tag("c3".to_owned()))));
+
+        // The replaced version is no longer stale once a newer one is seen.
+        pool.sync_items(&[file("f", "a.txt", 1, "c1")]);
+        assert_eq!(pool.get_attr(&id("f")).unwrap().size, 1);
+    }
+
+    #[test]
+    fn skip_items_missing_fields() {
+        let pool = pool(&[]);
+        let mut no_id = file("x", "no-id.txt", 1, "c");
+        no_id.id = None;
+        let mut no_parent = file("y", "no-parent.txt", 1, "c");
+        no_parent.parent_reference = None;
+        let mut no_name = file("z", "no-name.txt", 1, "c");
+        no_name.name = None;
+        pool.sync_items(&[
+            root(),
+            no_id,
+            no_parent,
+            no_name,
+            file("f", "a.txt", 1, "c"),
+        ]);
+        assert_eq!(names(&pool), ["a.txt"]);
+        assert!(pool.get_attr(&id("y")).is_err());
+        assert!(pool.get_attr(&id("z")).is_err());
+    }
+
+    #[test]
+    fn fold_name_normalizes_case_and_accents() {
+        assert_eq!(fold_name("Foo.TXT"), "foo.txt");
+        // Composed and decomposed forms of `é`.
+        assert_eq!(fold_name("Caf\u{e9}.txt"), fold_name("Cafe\u{301}.txt"));
+        assert_eq!(fold_name("CAF\u{c9}.txt"), fold_name("cafe\u{301}.TXT"));
+        assert_ne!(fold_name("cafe.txt"), fold_name("caf\u{e9}.txt"));
+    }
+
+    #[test]
+    fn case_insensitive_lookup() {
+        let folding = pool(&["vfs.inode.case_insensitive_lookup=true"]);
+        folding.sync_items(&[
+            root(),
+            file("f", "Caf\u{e9}.TXT", 1, "c1"),
+            file("g", "README.md", 1, "c2"),
+            file("h1", "dup.txt", 1, "c3"),
+            file("h2", "Dup.txt", 1, "c4"),
+        ]);
+        assert_eq!(lookup(&folding, "Caf\u{e9}.TXT").as_deref(), Some("f"));
+        assert_eq!(lookup(&folding, "caf\u{e9}.txt").as_deref(), Some("f"));
+        assert_eq!(lookup(&folding, "CAFE\u{301}.txt").as_deref(), Some("f"));
+        assert_eq!(lookup(&folding, "ReadMe.MD").as_deref(), Some("g"));
+        assert_eq!(lookup(&folding, "cafe.txt"), None);
+        // Exact names take precedence, and ambiguous folded names are not resolved.
+        assert_eq!(lookup(&folding, "Dup.txt").as_deref(), Some("h2"));
+        assert_eq!(lookup(&folding, "DUP.txt"), None);
+
+        // Folded names follow renames and deletions.
+        folding.sync_items(&[file("g", "Notes.md", 1, "c2"), deleted("h2")]);
+        assert_eq!(lookup(&folding, "readme.md"), None);
+        assert_eq!(lookup(&folding, "NOTES.MD").as_deref(), Some("g"));
+        assert_eq!(lookup(&folding, "DUP.txt").as_deref(), Some("h1"));
+
+        let exact = pool(&["vfs.inode.case_insensitive_lookup=false"]);
+        exact.sync_items(&[root(), file("g", "README.md", 1, "c2")]);
+        assert_eq!(lookup(&exact, "readme.md"), None);
+    }
+
+    #[test]
+    fn item_kind_by_facets() {
+        for (folder, file, package, kind) in [
+            (false, false, false, None),
+            (false, false, true, None),
+            (false, true, false, Some(ItemKind::File)),
+            (false, true, true, Some(ItemKind::File)),
+            (true, false, false, Some(ItemKind::Directory)),
+            (true, false, true, Some(ItemKind::Directory)),
+            (true, true, false, Some(ItemKind::Directory)),
+            (true, true, true, Some(ItemKind::Directory)),
+        ] {
+            let mut value = serde_json::json!({ "id": "x" });
+            for (facet, set) in [("folder", folder), ("file", file), ("package", package)] {
+                if set {
+                    value[facet] = serde_json::json!({});
+                }
+            }
+            let item = item(value);
+            let facets = (folder, file, package);
+            assert_eq!(ItemKind::of(&item), kind, "{:?}", facets);
+            let ambiguous = [folder, file, package].iter().filter(|&&set| set).count() > 1;
+            assert_eq!(ItemKind::is_ambiguous(&item), ambiguous, "{:?}", facets);
+        }
+    }
+
+    #[test]
+    fn http_cache_headers_from_c_tag() {
+        let pool = pool(&[]);
+        pool.sync_items(&[
+            root(),
+            file_at("f", "a.txt", 1, "c1", "2021-01-01T00:00:00Z"),
+            file("g", "b.txt", 1, "\"c\\2\""),
+        ]);
+        let headers = pool.get_attr(&id("f")).unwrap().http_cache_headers();
+        assert_eq!(headers.etag, "\"c1\"");
+        assert_eq!(headers.last_modified, "Fri, 01 Jan 2021 00:00:00 GMT");
+        // Quotes and backslashes are not allowed in ETags.
+        let headers = pool.get_attr(&id("g")).unwrap().http_cache_headers();
+        assert_eq!(headers.etag, "\"c2\"");
+
+        // Stable across metadata changes not touching the content.
+        pool.sync_items(&[file_at("f", "c.txt", 1, "c1", "2022-01-01T00:00:00Z")]);
+        let headers = pool.get_attr(&id("f")).unwrap().http_cache_headers();
+        assert_eq!(headers.etag, "\"c1\"");
+        assert_eq!(headers.last_modified, "Sat, 01 Jan 2022 00:00:00 GMT");
+
+        pool.sync_items(&[file("f", "c.txt", 2, "c3")]);
+        let attr = pool.get_attr(&id("f")).unwrap();
+        assert_eq!(attr.http_cache_headers().etag, "\"c3\"");
+
+        // Weak ETags for pending uploads, whose CTag is outdated.
+        let dirty = InodeAttr {
+            dirty: true,
+            ..attr
+        };
+        assert_eq!(dirty.http_cache_headers().etag, "W/\"5e0be100-2\"");
+        let xattrs = dirty.xattrs();
+        let xattr = |name| {
+            xattrs
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, v)| &**v)
+        };
+        assert_eq!(xattr("user.onedrive.http_etag"), Some("W/\"5e0be100-2\""));
+        assert_eq!(
+            xattr("user.onedrive.http_last_modified"),
+            Some("Wed, 01 Jan 2020 00:00:00 GMT"),
+        );
+        assert_eq!(xattr("user.onedrive.ctag"), None);
+        let dir = pool.get_attr(&id("root")).unwrap();
+        assert_eq!(dir.http_cache_headers().etag, "W/\"5e0be100-0\"");
+    }
+}
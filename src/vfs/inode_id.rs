@@ -42,6 +42,10 @@ impl InodeIdPool {
         assert!(inner.rev_map.insert(item_id, self.root_ino).is_none());
     }
 
+    pub fn root_ino(&self) -> u64 {
+        self.root_ino
+    }
+
     /// Update InodeAttr of existing inode or allocate a new inode,
     /// also increase the reference count.
     pub fn acquire_or_alloc(&self, item_id: &ItemId) -> u64 {
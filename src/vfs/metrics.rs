@@ -0,0 +1,377 @@
+//! Process-wide counters of transfers, updated where they happen regardless of which file pool
+//! or handle owns the transfer.
+use onedrive_api::ItemId;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as SyncMutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Window of the rolling average rates.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// Window of the instantaneous rates.
+const INSTANT_RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Min interval between samples of a transfer. Bytes in between are counted in the next sample.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+/// Max samples of a transfer, enough to cover `RATE_WINDOW`.
+const MAX_SAMPLES: usize = (RATE_WINDOW.as_millis() / SAMPLE_INTERVAL.as_millis()) as usize + 2;
+
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub downloaded_bytes: AtomicU64,
+    pub uploaded_bytes: AtomicU64,
+    /// Failed requests and broken streams of downloads. Throttling is not counted.
+    pub download_retries: AtomicU64,
+    /// Failed parts of uploads. Throttling is not counted.
+    pub upload_retries: AtomicU64,
+    /// Cache entries removed to make room for others.
+    pub cache_evictions: AtomicU64,
+    downloads: AtomicU64,
+    uploads: AtomicU64,
+    handles: AtomicU64,
+    /// Active transfers with rates sampled.
+    transfers: SyncMutex<Vec<Arc<TransferInner>>>,
+}
+
+/// Snapshot of `Metrics`, see `Vfs::metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+    pub download_retries: u64,
+    pub upload_retries: u64,
+    pub cache_evictions: u64,
+    pub downloads_in_flight: u64,
+    pub uploads_in_flight: u64,
+    pub open_handles: u64,
+    /// Rates of all active transfers in bytes per second, see `TransferRate`.
+    pub download_rate: u64,
+    pub download_instant_rate: u64,
+    pub upload_rate: u64,
+    pub upload_instant_rate: u64,
+    pub transfers: Vec<TransferRate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferDirection {
+    Download,
+    Upload,
+}
+
+/// Rates of an active transfer at the time of snapshot.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferRate {
+    pub item_id: ItemId,
+    pub direction: TransferDirection,
+    /// Bytes transferred so far.
+    pub bytes: u64,
+    /// Average bytes per second in the last 10 seconds, or since start if it's shorter.
+    pub rate: u64,
+    /// Average bytes per second in the last second, or since start if it's shorter.
+    pub instant_rate: u64,
+}
+
+/// Byte counts of a transfer sampled over time in a ring buffer, to calculate rates over
+/// recent windows. It takes at most one sample per `SAMPLE_INTERVAL`, so it's cheap to record
+/// every chunk.
+#[derive(Debug)]
+struct RateSampler {
+    start: Instant,
+    total: u64,
+    /// Sample times and `total` at them, oldest first.
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateSampler {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            total: 0,
+            samples: VecDeque::with_capacity(MAX_SAMPLES),
+        }
+    }
+
+    fn record(&mut self, now: Instant, bytes: u64) {
+        self.total += bytes;
+        if self
+            .samples
+            .back()
+            .is_some_and(|&(time, _)| now.saturating_duration_since(time) < SAMPLE_INTERVAL)
+        {
+            return;
+        }
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, self.total));
+    }
+
+    /// Bytes per second in the last `window`, or since start if it's shorter. Bytes are counted
+    /// since the last sample not after the window start, overestimating at most one interval.
+    fn rate(&self, now: Instant, window: Duration) -> u64 {
+        let elapsed = now.saturating_duration_since(self.start).min(window);
+        if elapsed.is_zero() {
+            return 0;
+        }
+        let base = now
+            .checked_sub(window)
+            .and_then(|window_start| {
+                self.samples
+                    .iter()
+                    .rev()
+                    .find(|&&(time, _)| time <= window_start)
+            })
+            .map_or(0, |&(_, total)| total);
+        ((self.total - base) as f64 / elapsed.as_secs_f64()) as u64
+    }
+}
+
+#[derive(Debug)]
+struct TransferInner {
+    item_id: ItemId,
+    direction: TransferDirection,
+    sampler: SyncMutex<RateSampler>,
+}
+
+/// An active transfer, counted as in flight and listed in `MetricsSnapshot::transfers` until
+/// dropped.
+#[derive(Debug)]
+pub struct Transfer {
+    inner: Arc<TransferInner>,
+    _in_flight: InFlight,
+}
+
+impl Transfer {
+    /// Count transferred bytes, both in total and for the rates of this transfer.
+    pub fn add(&self, bytes: u64) {
+        let counter = match self.inner.direction {
+            TransferDirection::Download => &METRICS.downloaded_bytes,
+            TransferDirection::Upload => &METRICS.uploaded_bytes,
+        };
+        Metrics::add(counter, bytes);
+        self.inner
+            .sampler
+            .lock()
+            .unwrap()
+            .record(Instant::now(), bytes);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        METRICS
+            .transfers
+            .lock()
+            .unwrap()
+            .retain(|transfer| !Arc::ptr_eq(transfer, &self.inner));
+    }
+}
+
+/// Counted as an in-flight transfer or an open handle until dropped.
+#[derive(Debug)]
+pub struct InFlight(&'static AtomicU64);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            downloaded_bytes: AtomicU64::new(0),
+            uploaded_bytes: AtomicU64::new(0),
+            download_retries: AtomicU64::new(0),
+            upload_retries: AtomicU64::new(0),
+            cache_evictions: AtomicU64::new(0),
+            downloads: AtomicU64::new(0),
+            uploads: AtomicU64::new(0),
+            handles: AtomicU64::new(0),
+            transfers: SyncMutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn start_download(&'static self, item_id: &ItemId) -> Transfer {
+        Self::add(&self.downloads, 1);
+        self.start_transfer(
+            item_id,
+            TransferDirection::Download,
+            InFlight(&self.downloads),
+        )
+    }
+
+    pub fn start_upload(&'static self, item_id: &ItemId) -> Transfer {
+        Self::add(&self.uploads, 1);
+        self.start_transfer(item_id, TransferDirection::Upload, InFlight(&self.uploads))
+    }
+
+    fn start_transfer(
+        &'static self,
+        item_id: &ItemId,
+        direction: TransferDirection,
+        in_flight: InFlight,
+    ) -> Transfer {
+        let inner = Arc::new(TransferInner {
+            item_id: item_id.clone(),
+            direction,
+            sampler: SyncMutex::new(RateSampler::new(Instant::now())),
+        });
+        self.transfers.lock().unwrap().push(inner.clone());
+        Transfer {
+            inner,
+            _in_flight: in_flight,
+        }
+    }
+
+    pub fn open_handle(&'static self) -> InFlight {
+        Self::add(&self.handles, 1);
+        InFlight(&self.handles)
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let now = Instant::now();
+        let transfers = self
+            .transfers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|transfer| {
+                let sampler = transfer.sampler.lock().unwrap();
+                TransferRate {
+                    item_id: transfer.item_id.clone(),
+                    direction: transfer.direction,
+                    bytes: sampler.total,
+                    rate: sampler.rate(now, RATE_WINDOW),
+                    instant_rate: sampler.rate(now, INSTANT_RATE_WINDOW),
+                }
+            })
+            .collect::<Vec<_>>();
+        let sum_rates = |direction, rate: fn(&TransferRate) -> u64| {
+            transfers
+                .iter()
+                .filter(|transfer| transfer.direction == direction)
+                .map(rate)
+                .sum()
+        };
+        MetricsSnapshot {
+            download_rate: sum_rates(TransferDirection::Download, |t| t.rate),
+            download_instant_rate: sum_rates(TransferDirection::Download, |t| t.instant_rate),
+            upload_rate: sum_rates(TransferDirection::Upload, |t| t.rate),
+            upload_instant_rate: sum_rates(TransferDirection::Upload, |t| t.instant_rate),
+            transfers,
+            downloaded_bytes: self.downloaded_bytes.load(Ordering::Relaxed),
+            uploaded_bytes: self.uploaded_bytes.load(Ordering::Relaxed),
+            download_retries: self.download_retries.load(Ordering::Relaxed),
+            upload_retries: self.upload_retries.load(Ordering::Relaxed),
+            cache_evictions: self.cache_evictions.load(Ordering::Relaxed),
+            downloads_in_flight: self.downloads.load(Ordering::Relaxed),
+            uploads_in_flight: self.uploads.load(Ordering::Relaxed),
+            open_handles: self.handles.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn rate_of_steady_transfer() {
+        let start = Instant::now();
+        let mut sampler = RateSampler::new(start);
+        // 1 MB per 500 ms for 20 s.
+        for i in 1..=40 {
+            sampler.record(start + ms(500 * i), 1_000_000);
+        }
+        let now = start + ms(20_000);
+        assert_eq!(sampler.total, 40_000_000);
+        assert_eq!(sampler.rate(now, RATE_WINDOW), 2_000_000);
+        assert_eq!(sampler.rate(now, INSTANT_RATE_WINDOW), 2_000_000);
+        assert!(sampler.samples.len() <= MAX_SAMPLES);
+    }
+
+    #[test]
+    fn rate_since_start() {
+        let start = Instant::now();
+        let mut sampler = RateSampler::new(start);
+        assert_eq!(sampler.rate(start, RATE_WINDOW), 0);
+        sampler.record(start + ms(200), 100);
+        sampler.record(start + ms(400), 100);
+        // Shorter than both windows.
+        assert_eq!(sampler.rate(start + ms(500), RATE_WINDOW), 400);
+        assert_eq!(sampler.rate(start + ms(500), INSTANT_RATE_WINDOW), 400);
+    }
+
+    #[test]
+    fn rate_after_stall() {
+        let start = Instant::now();
+        let mut sampler = RateSampler::new(start);
+        // 10 MB/s for 5 s, then stalled.
+        for i in 1..=50 {
+            sampler.record(start + ms(100 * i), 1_000_000);
+        }
+        let now = start + ms(8_000);
+        assert_eq!(sampler.rate(now, RATE_WINDOW), 50_000_000 / 8);
+        assert_eq!(sampler.rate(now, INSTANT_RATE_WINDOW), 0);
+
+        // Resumed by a burst.
+        sampler.record(now + ms(500), 3_000_000);
+        let now = now + ms(1_000);
+        assert_eq!(sampler.rate(now, INSTANT_RATE_WINDOW), 3_000_000);
+        // Bytes since 0 ms to 9 s, in the last 10 s.
+        assert_eq!(sampler.rate(now, RATE_WINDOW), 53_000_000 / 9);
+    }
+
+    #[test]
+    fn merge_samples_within_interval() {
+        let start = Instant::now();
+        let mut sampler = RateSampler::new(start);
+        for i in 1..=100 {
+            sampler.record(start + ms(10 * i), 1_000);
+        }
+        assert_eq!(sampler.samples.len(), 10);
+        assert_eq!(
+            sampler.rate(start + ms(1_000), INSTANT_RATE_WINDOW),
+            100_000
+        );
+    }
+
+    #[test]
+    fn snapshot_transfer_rates() {
+        let item_id = ItemId("RATE_TEST".to_owned());
+        let transfer = METRICS.start_upload(&item_id);
+        transfer.add(1_000);
+        let snapshot = METRICS.snapshot();
+        let rate = snapshot
+            .transfers
+            .iter()
+            .find(|rate| rate.item_id == item_id)
+            .unwrap();
+        assert_eq!(rate.direction, TransferDirection::Upload);
+        assert_eq!(rate.bytes, 1_000);
+        assert!(snapshot.upload_rate >= rate.rate);
+
+        drop(transfer);
+        let snapshot = METRICS.snapshot();
+        assert!(snapshot
+            .transfers
+            .iter()
+            .all(|rate| rate.item_id != item_id));
+    }
+}
@@ -1,5 +1,10 @@
 use crate::login::ManagedOnedrive;
-use onedrive_api::{resource::DriveItem, FileName, ItemLocation, OneDrive};
+use anyhow::Context as _;
+use onedrive_api::{
+    option::ObjectOption,
+    resource::{DriveField, DriveId, DriveItem},
+    FileName, ItemId, ItemLocation, OneDrive, Tag,
+};
 use serde::Deserialize;
 use std::{
     ffi::OsStr,
@@ -7,17 +12,25 @@ use std::{
     sync::{Arc, Weak},
     time::{Duration, SystemTime},
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 pub mod error;
 mod file;
+mod hash;
 mod inode;
 mod inode_id;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod shared;
+mod special;
 mod statfs;
 mod tracker;
 
 pub use error::{Error, Result};
+pub use file::{CacheEvent, CacheStats};
 pub use inode::{DirEntry, InodeAttr};
+pub use metrics::MetricsSnapshot;
 pub use statfs::StatfsData;
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +39,8 @@ pub struct Config {
     inode: inode::Config,
     file: file::Config,
     tracker: tracker::Config,
+    special_folders: special::Config,
+    shared: shared::Config,
 }
 
 #[derive(Debug)]
@@ -34,6 +49,14 @@ pub enum UpdateEvent {
     BatchUpdate(Vec<DriveItem>),
     /// Update attribute of a single file due to modification.
     UpdateFile(file::UpdatedFileAttr),
+    /// Uploading a file is refused since it's changed in remote side since the last sync.
+    UploadConflict { item_id: ItemId },
+    /// Correct the size of a file whose metadata disagrees with the downloaded content.
+    CorrectSize {
+        item_id: ItemId,
+        c_tag: Tag,
+        size: u64,
+    },
 }
 
 pub struct Vfs {
@@ -41,11 +64,25 @@ pub struct Vfs {
     id_pool: inode_id::InodeIdPool,
     inode_pool: inode::InodePool,
     file_pool: file::FilePool,
+    special_folders: special::SpecialFolders,
+    shared: shared::SharedItems,
     tracker: tracker::Tracker,
     onedrive: ManagedOnedrive,
+    drive_id: DriveId,
     readonly: bool,
 }
 
+/// Resolve the id of the drive `onedrive` is mounting.
+pub async fn fetch_drive_id(onedrive: &ManagedOnedrive) -> anyhow::Result<DriveId> {
+    onedrive
+        .get()
+        .await?
+        .get_drive_with_option(ObjectOption::new().select(&[DriveField::id]))
+        .await?
+        .id
+        .context("Missing drive id")
+}
+
 impl Vfs {
     pub async fn new(
         root_ino: u64,
@@ -54,7 +91,15 @@ impl Vfs {
         onedrive: ManagedOnedrive,
         client: reqwest::Client,
     ) -> anyhow::Result<Arc<Self>> {
+        let drive_id = fetch_drive_id(&onedrive).await?;
+        log::info!("Drive: {}", drive_id.as_str());
         let statfs = statfs::Statfs::new(onedrive.clone(), config.statfs).await?;
+        let special_folders = special::SpecialFolders::new(
+            &*onedrive.get().await?,
+            &drive_id,
+            config.special_folders,
+        )
+        .await;
 
         let (event_tx, event_rx) = mpsc::channel(1);
         let (init_tx, init_rx) = oneshot::channel();
@@ -75,13 +120,18 @@ impl Vfs {
             id_pool: inode_id::InodeIdPool::new(root_ino),
             inode_pool: inode::InodePool::new(config.inode),
             file_pool: file::FilePool::new(
+                &drive_id,
                 event_tx,
                 onedrive.clone(),
                 client.clone(),
+                tracker.subscribe_offline(),
                 config.file,
             )?,
+            special_folders,
+            shared: shared::SharedItems::new(config.shared),
             tracker,
             onedrive,
+            drive_id,
             readonly,
         });
 
@@ -104,7 +154,8 @@ impl Vfs {
             };
 
             match event {
-                UpdateEvent::BatchUpdate(updated) => {
+                UpdateEvent::BatchUpdate(mut updated) => {
+                    this.shared.resolve_shortcuts(&mut updated);
                     this.inode_pool.sync_items(&updated);
                     this.file_pool.sync_items(&updated).await;
 
@@ -125,21 +176,35 @@ impl Vfs {
                 }
                 // This event will be triggered after a successful upload.
                 UpdateEvent::UpdateFile(updated) => {
-                    this.inode_pool
-                        .update_attr(&updated.item_id, |attr| InodeAttr {
-                            size: updated.size,
-                            mtime: updated.mtime,
-                            c_tag: Some(updated.c_tag.clone()),
-                            dirty: true,
-                            ..attr
-                        });
+                    this.inode_pool.update_uploaded(&updated);
+                }
+                // The remote change will be synchronized soon.
+                UpdateEvent::UploadConflict { item_id } => {
+                    let name = this.inode_pool.get_name(&item_id);
+                    log::warn!("Upload conflict on {:?} ({:?})", name, item_id);
+                }
+                UpdateEvent::CorrectSize {
+                    item_id,
+                    c_tag,
+                    size,
+                } => {
+                    // Skip if the file is changed or removed in the meantime.
+                    let up_to_date = this
+                        .inode_pool
+                        .get_attr(&item_id)
+                        .is_ok_and(|attr| attr.c_tag.as_ref() == Some(&c_tag));
+                    if up_to_date {
+                        log::debug!("Correct size of {:?} to {}", item_id, size);
+                        this.inode_pool
+                            .update_attr(&item_id, |attr| InodeAttr { size, ..attr });
+                    }
                 }
             }
         }
     }
 
-    async fn onedrive(&self) -> impl Deref<Target = OneDrive> + '_ {
-        self.onedrive.get().await
+    async fn onedrive(&self) -> Result<impl Deref<Target = OneDrive> + '_> {
+        Ok(self.onedrive.get().await?)
     }
 
     fn ttl(&self) -> Duration {
@@ -161,13 +226,79 @@ impl Vfs {
     ) -> Result<(u64, InodeAttr, Duration)> {
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
         let child_name = cvt_filename(child_name)?;
-        let id = self.inode_pool.lookup(&parent_id, child_name)?;
-        let attr = self.inode_pool.get_attr(&id)?;
+        let id = if self.shared.contains(&parent_id) {
+            let (id, fetched) = self
+                .shared
+                .lookup(&parent_id, child_name, &*self.onedrive().await?)
+                .await?;
+            self.file_pool.sync_items(&fetched).await;
+            id
+        } else if let Some(id) = self.lookup_special(parent_ino, &parent_id, child_name)? {
+            id
+        } else {
+            self.inode_pool.lookup(&parent_id, child_name)?
+        };
+        let attr = self.get_attr_inner(&id)?;
         let ino = self.id_pool.acquire_or_alloc(&id);
         log::trace!(target: "vfs::inode", "lookup: id={:?} ino={} attr={:?}", id, ino, attr);
         Ok((ino, attr, self.ttl()))
     }
 
+    // Resolve names in virtual directories, which shadow real items in root.
+    fn lookup_special(
+        &self,
+        parent_ino: u64,
+        parent_id: &ItemId,
+        child_name: &FileName,
+    ) -> Result<Option<ItemId>> {
+        if let Some(dir_id) = self.shared.dir_id() {
+            if parent_ino == self.id_pool.root_ino() && child_name.as_str() == shared::DIR_NAME {
+                return Ok(Some(dir_id.clone()));
+            }
+        }
+        let dir_id = match self.special_folders.dir_id() {
+            Some(dir_id) => dir_id,
+            None => return Ok(None),
+        };
+        if parent_ino == self.id_pool.root_ino() && child_name.as_str() == special::DIR_NAME {
+            Ok(Some(dir_id.clone()))
+        } else if parent_id == dir_id {
+            let id = self
+                .special_folders
+                .resolve(child_name.as_str())
+                .ok_or(Error::NotFound)?;
+            Ok(Some(id.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_attr_inner(&self, id: &ItemId) -> Result<InodeAttr> {
+        if self.special_folders.dir_id() == Some(id) || self.shared.dir_id() == Some(id) {
+            let root_id = self.id_pool.get_item_id(self.id_pool.root_ino())?;
+            let root_attr = self.inode_pool.get_attr(&root_id)?;
+            return Ok(InodeAttr {
+                size: 0,
+                c_tag: None,
+                dirty: false,
+                meta: Default::default(),
+                ..root_attr
+            });
+        }
+        if self.shared.contains(id) {
+            return self.shared.get_attr(id);
+        }
+        self.inode_pool.get_attr(id)
+    }
+
+    /// Shared items are read-only, and so are directories of them.
+    fn check_writable(&self, id: &ItemId) -> Result<()> {
+        if self.shared.contains(id) {
+            return Err(Error::SharedReadOnly);
+        }
+        Ok(())
+    }
+
     pub async fn forget(&self, ino: u64, count: u64) -> Result<()> {
         let freed = self.id_pool.free(ino, count)?;
         log::trace!(target: "vfs::inode", "forget: ino={} count={} freed={}", ino, count, freed);
@@ -176,13 +307,50 @@ impl Vfs {
 
     pub async fn get_attr(&self, ino: u64) -> Result<(InodeAttr, Duration)> {
         let id = self.id_pool.get_item_id(ino)?;
-        let attr = self.inode_pool.get_attr(&id)?;
+        let attr = self.get_attr_inner(&id)?;
         log::trace!(target: "vfs::inode", "get_attr: id={:?} ino={} attr={:?}", id, ino, attr);
         Ok((attr, self.ttl()))
     }
 
+    /// Get extended attributes of an item from cached metadata, see `InodeAttr::xattrs`.
+    pub async fn get_xattrs(&self, ino: u64) -> Result<Vec<(&'static str, String)>> {
+        let id = self.id_pool.get_item_id(ino)?;
+        Ok(self.get_attr_inner(&id)?.xattrs())
+    }
+
+    /// Id of the mounted drive. Item ids in events and attributes are only unique in it.
+    pub fn drive_id(&self) -> &DriveId {
+        &self.drive_id
+    }
+
+    /// Subscribe lifecycle events of file caches, independent of internal update events.
+    /// They're counted by the metrics exporter.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn subscribe_cache_events(&self) -> broadcast::Receiver<CacheEvent> {
+        self.file_pool.subscribe_events()
+    }
+
+    /// Counters of transfers and cache evictions since start, and rates of active transfers.
+    /// They're exported as metrics.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn metrics(&self) -> MetricsSnapshot {
+        metrics::METRICS.snapshot()
+    }
+
+    /// Statistics of file caches, `None` if no cache is enabled. They're exported as metrics.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub async fn cache_stats(&self) -> Option<CacheStats> {
+        self.file_pool.cache_stats().await
+    }
+
     // fh is not used for directories.
     pub async fn open_dir(&self, ino: u64) -> Result<u64> {
+        let id = self.id_pool.get_item_id(ino)?;
+        // Shared directories are not synchronized, so list them on every open.
+        if self.shared.contains(&id) {
+            let fetched = self.shared.list(&id, &*self.onedrive().await?).await?;
+            self.file_pool.sync_items(&fetched).await;
+        }
         log::trace!(target: "vfs::dir", "open_dir: ino={}", ino);
         Ok(0)
     }
@@ -201,14 +369,50 @@ impl Vfs {
         count: usize,
     ) -> Result<impl AsRef<[DirEntry]>> {
         let parent_id = self.id_pool.get_item_id(ino)?;
-        let ret = self.inode_pool.read_dir(&parent_id, offset, count)?;
+        let ret = if self.special_folders.dir_id() == Some(&parent_id) {
+            let folders = self.special_folders.folders();
+            let l = (offset as usize).min(folders.len());
+            let r = (l + count).min(folders.len());
+            folders[l..r]
+                .iter()
+                .filter_map(|(name, id)| {
+                    let attr = self.inode_pool.get_attr(id).ok()?;
+                    Some(DirEntry {
+                        name: name.clone(),
+                        attr,
+                    })
+                })
+                .collect()
+        } else if self.shared.contains(&parent_id) {
+            self.shared.read_dir(&parent_id, offset, count)?
+        } else {
+            self.inode_pool.read_dir(&parent_id, offset, count)?
+        };
         log::trace!(target: "vfs::dir", "read_dir: ino={} offset={}", ino, offset);
         Ok(ret)
     }
 
     pub async fn open_file(&self, ino: u64, write: bool) -> Result<u64> {
         let item_id = self.id_pool.get_item_id(ino)?;
-        let fh = self.file_pool.open(&item_id, write).await?;
+        let name = if self.shared.contains(&item_id) {
+            if write {
+                return Err(Error::SharedReadOnly);
+            }
+            // Shared files are not synchronized. Refresh it to invalidate the outdated cache.
+            match self
+                .shared
+                .refresh(&item_id, &*self.onedrive().await?)
+                .await
+            {
+                Ok(item) => self.file_pool.sync_items(&[item]).await,
+                Err(err) => log::warn!("Failed to refresh shared file {:?}: {}", item_id, err),
+            }
+            self.shared.get_name(&item_id)
+        } else {
+            self.inode_pool.get_name(&item_id)
+        }
+        .unwrap_or_default();
+        let fh = self.file_pool.open(&item_id, &name, write).await?;
         log::trace!(target: "vfs::file", "open_file: ino={} fh={}", ino, fh);
         Ok(fh)
     }
@@ -222,6 +426,7 @@ impl Vfs {
     ) -> Result<(u64, u64, InodeAttr, Duration)> {
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
         let child_name = cvt_filename(child_name)?;
+        self.check_writable(&parent_id)?;
         if !truncate {
             // FIXME: Not atomic.
             match self.inode_pool.lookup(&parent_id, child_name) {
@@ -281,9 +486,10 @@ impl Vfs {
     ) -> Result<(u64, InodeAttr, Duration)> {
         let name = cvt_filename(name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
+        self.check_writable(&parent_id)?;
         let (id, attr) = self
             .inode_pool
-            .create_dir(&parent_id, name, &*self.onedrive().await)
+            .create_dir(&parent_id, name, &*self.onedrive().await?)
             .await?;
         let ino = self.id_pool.acquire_or_alloc(&id);
         log::trace!(
@@ -305,6 +511,8 @@ impl Vfs {
         let new_name = cvt_filename(new_name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
         let new_parent_id = self.id_pool.get_item_id(new_parent_ino)?;
+        self.check_writable(&parent_id)?;
+        self.check_writable(&new_parent_id)?;
         let replaced_item_id = self
             .inode_pool
             .rename(
@@ -312,15 +520,12 @@ impl Vfs {
                 name,
                 &new_parent_id,
                 new_name,
-                &*self.onedrive().await,
+                &*self.onedrive().await?,
             )
             .await?;
         // If some item is replace, remove it from cache.
         if let Some(id) = replaced_item_id {
-            let mut mock_item = DriveItem::default();
-            mock_item.id = Some(id);
-            mock_item.deleted = Some(Box::new(serde_json::Value::Null));
-            self.file_pool.sync_items(&[mock_item]).await;
+            self.forget_deleted(id).await;
         }
         log::trace!(
             target: "vfs::dir",
@@ -331,11 +536,20 @@ impl Vfs {
         Ok(())
     }
 
+    /// Drop the cache of an item deleted by us, without waiting for the next sync.
+    async fn forget_deleted(&self, id: ItemId) {
+        let mut mock_item = DriveItem::default();
+        mock_item.id = Some(id);
+        mock_item.deleted = Some(Box::new(serde_json::Value::Null));
+        self.file_pool.sync_items(&[mock_item]).await;
+    }
+
     pub async fn remove_dir(&self, parent_ino: u64, name: &OsStr) -> Result<()> {
         let name = cvt_filename(name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
+        self.check_writable(&parent_id)?;
         self.inode_pool
-            .remove(&parent_id, name, true, &*self.onedrive().await)
+            .remove(&parent_id, name, true, &*self.onedrive().await?)
             .await?;
         log::trace!(
             target: "vfs::dir",
@@ -348,9 +562,12 @@ impl Vfs {
     pub async fn remove_file(&self, parent_ino: u64, name: &OsStr) -> Result<()> {
         let name = cvt_filename(name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
-        self.inode_pool
-            .remove(&parent_id, name, false, &*self.onedrive().await)
+        self.check_writable(&parent_id)?;
+        let item_id = self
+            .inode_pool
+            .remove(&parent_id, name, false, &*self.onedrive().await?)
             .await?;
+        self.forget_deleted(item_id).await;
         log::trace!(
             target: "vfs::dir",
             "remove_file: parent_id={:?} parent_ino={} name={}",
@@ -360,6 +577,13 @@ impl Vfs {
     }
 
     pub async fn write_file(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<()> {
+        // Only the growth of the file consumes quota.
+        let old_size = self
+            .inode_pool
+            .get_attr(&self.id_pool.get_item_id(ino)?)?
+            .size;
+        self.statfs
+            .check_space((offset + data.len() as u64).saturating_sub(old_size))?;
         let updated = self.file_pool.write(fh, offset, data).await?;
         self.inode_pool
             .update_attr(&updated.item_id, |attr| InodeAttr {
@@ -379,10 +603,12 @@ impl Vfs {
     pub async fn set_attr(
         &self,
         ino: u64,
+        fh: Option<u64>,
         size: Option<u64>,
         mtime: Option<SystemTime>,
     ) -> Result<(InodeAttr, Duration)> {
         let item_id = self.id_pool.get_item_id(ino)?;
+        self.check_writable(&item_id)?;
         let old_attr = self.inode_pool.get_attr(&item_id)?;
         if size.is_some() && old_attr.is_directory {
             return Err(Error::IsADirectory);
@@ -391,10 +617,22 @@ impl Vfs {
         let new_attr = match (size, mtime) {
             // Truncate.
             (Some(new_size), _) if old_attr.size != new_size => {
+                self.statfs
+                    .check_space(new_size.saturating_sub(old_attr.size))?;
                 let mtime = mtime.unwrap_or_else(SystemTime::now);
-                self.file_pool
-                    .truncate_file(&item_id, new_size, mtime)
-                    .await?;
+                let streaming = match fh {
+                    Some(fh) if old_attr.size == 0 => {
+                        self.file_pool
+                            .try_start_stream_upload(fh, new_size, mtime)
+                            .await?
+                    }
+                    _ => false,
+                };
+                if !streaming {
+                    self.file_pool
+                        .truncate_file(&item_id, new_size, mtime)
+                        .await?;
+                }
                 self.inode_pool.update_attr(&item_id, |attr| InodeAttr {
                     dirty: true,
                     size: new_size,
@@ -404,9 +642,15 @@ impl Vfs {
             }
             // Touch mtime
             (_, Some(mtime)) => {
-                self.inode_pool
-                    .set_time(&item_id, mtime, &*self.onedrive().await)
-                    .await?
+                // A pending upload would overwrite the mtime set in remote side.
+                if old_attr.dirty && self.file_pool.set_pending_mtime(&item_id, mtime).await {
+                    self.inode_pool
+                        .update_attr(&item_id, |attr| InodeAttr { mtime, ..attr })
+                } else {
+                    self.inode_pool
+                        .set_time(&item_id, mtime, &*self.onedrive().await?)
+                        .await?
+                }
             }
             // Do nothing.
             (_, None) => self.inode_pool.get_attr(&item_id)?,
@@ -420,16 +664,22 @@ impl Vfs {
         Ok((new_attr, self.ttl()))
     }
 
-    pub async fn sync_file(&self, ino: u64) -> Result<()> {
+    /// Upload all pending changes before exiting.
+    pub async fn flush_all(&self) {
+        if !self.readonly {
+            self.file_pool.flush_all().await;
+        }
+    }
+
+    pub async fn sync_file(&self, ino: u64, fh: u64) -> Result<()> {
         if self.readonly {
             return Ok(());
         }
-        let item_id = self.id_pool.get_item_id(ino)?;
-        self.file_pool.flush_file(&item_id).await?;
+        self.file_pool.fsync(fh).await?;
         log::trace!(
             target: "vfs::file",
-            "sync_file: ino={} id={:?}",
-            ino, item_id,
+            "sync_file: ino={} fh={}",
+            ino, fh,
         );
         Ok(())
     }
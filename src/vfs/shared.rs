@@ -0,0 +1,493 @@
+//! Virtual `.shared` directory exposing items shared with the user, which live in other drives.
+//!
+//! Shared items are not tracked by the synchronization of the mounted drive. Directories are
+//! listed when opened, and files are refreshed when opened instead. They are addressed by local
+//! ids encoding the owning drive and item, see `local_id`.
+//!
+//! Shortcuts to shared items added in the mounted drive are resolved to them, see
+//! `resolve_shortcuts`.
+use crate::vfs::{
+    error::{Error, Result},
+    inode::{content_tag, suffixed_name, DirEntry, InodeAttr, InodePool, ItemKind},
+};
+use onedrive_api::{
+    option::{CollectionOption, ObjectOption},
+    resource::{DriveId, DriveItem, DriveItemField},
+    DriveLocation, FileName, ItemId, ItemLocation, OneDrive,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Mutex as SyncMutex};
+
+/// Name of the virtual directory under root.
+pub const DIR_NAME: &str = ".shared";
+
+/// Fields of `InodeAttr`, like `InodePool::SYNC_SELECT_FIELDS` selected along with `FilePool`'s.
+const TAG_FIELDS: &[DriveItemField] = &[DriveItemField::c_tag, DriveItemField::e_tag];
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    enable: bool,
+}
+
+pub struct SharedItems {
+    /// Item id of the virtual directory. Real item ids never start with `.`.
+    dir_id: Option<ItemId>,
+    inner: SyncMutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Local id -> Name and attribute of items listed.
+    items: HashMap<ItemId, (String, InodeAttr)>,
+    /// Local id -> Children names and local ids of directories listed.
+    children: HashMap<ItemId, Vec<(String, ItemId)>>,
+    /// Item id of a shortcut in the mounted drive -> Local id of the item it points to.
+    shortcuts: HashMap<ItemId, ItemId>,
+}
+
+/// The local id of an item in another drive, like `.shared:<drive id>:<item id>`.
+/// Neither drive ids nor item ids contain `:`.
+fn local_id(drive_id: &DriveId, item_id: &ItemId) -> ItemId {
+    ItemId(format!(
+        "{}:{}:{}",
+        DIR_NAME,
+        drive_id.as_str(),
+        item_id.as_str(),
+    ))
+}
+
+/// The owning drive and item id of a local id, or `None` if it's not a shared item.
+pub fn remote_of(item_id: &ItemId) -> Option<(DriveId, ItemId)> {
+    let rest = item_id.as_str().strip_prefix(DIR_NAME)?.strip_prefix(':')?;
+    let (drive_id, item_id) = rest.split_once(':')?;
+    Some((DriveId(drive_id.to_owned()), ItemId(item_id.to_owned())))
+}
+
+/// The owning drive id of a `remoteItem` facet.
+fn remote_drive_id(remote: &DriveItem) -> Option<DriveId> {
+    let drive_id = remote.parent_reference.as_ref()?.get("driveId")?.as_str()?;
+    Some(DriveId(drive_id.to_owned()))
+}
+
+/// Client of the drive owning a shared item, with the connection and access token of `onedrive`.
+pub fn remote_drive(onedrive: &OneDrive, drive_id: DriveId) -> OneDrive {
+    OneDrive::new_with_client(
+        onedrive.client().clone(),
+        onedrive.access_token().to_owned(),
+        DriveLocation::from_id(drive_id),
+    )
+}
+
+impl SharedItems {
+    pub fn new(config: Config) -> Self {
+        Self {
+            dir_id: config.enable.then(|| ItemId(DIR_NAME.to_owned())),
+            inner: Default::default(),
+        }
+    }
+
+    /// Item id of the virtual directory, or `None` if disabled.
+    pub fn dir_id(&self) -> Option<&ItemId> {
+        self.dir_id.as_ref()
+    }
+
+    /// Whether an item is the virtual directory or a shared item under it.
+    pub fn contains(&self, item_id: &ItemId) -> bool {
+        self.dir_id.is_some() && (self.dir_id() == Some(item_id) || remote_of(item_id).is_some())
+    }
+
+    pub fn get_attr(&self, item_id: &ItemId) -> Result<InodeAttr> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.items.get(item_id).ok_or(Error::NotFound)?.1.clone())
+    }
+
+    pub fn get_name(&self, item_id: &ItemId) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        Some(inner.items.get(item_id)?.0.clone())
+    }
+
+    /// Lookup a child of a directory, listing it first if it's never listed.
+    /// Items fetched are also returned, see `list`.
+    pub async fn lookup(
+        &self,
+        parent_id: &ItemId,
+        child_name: &FileName,
+        onedrive: &OneDrive,
+    ) -> Result<(ItemId, Vec<DriveItem>)> {
+        let listed = self.inner.lock().unwrap().children.contains_key(parent_id);
+        let fetched = if listed {
+            Vec::new()
+        } else {
+            self.list(parent_id, onedrive).await?
+        };
+        let inner = self.inner.lock().unwrap();
+        let id = inner
+            .children
+            .get(parent_id)
+            .ok_or(Error::NotFound)?
+            .iter()
+            .find(|(name, _)| name == child_name.as_str())
+            .ok_or(Error::NotFound)?
+            .1
+            .clone();
+        Ok((id, fetched))
+    }
+
+    /// Read entries of a directory listed before.
+    pub fn read_dir(&self, parent_id: &ItemId, offset: u64, count: usize) -> Result<Vec<DirEntry>> {
+        let inner = self.inner.lock().unwrap();
+        let children = inner.children.get(parent_id).ok_or(Error::NotFound)?;
+        let l = (offset as usize).min(children.len());
+        let r = (l + count).min(children.len());
+        Ok(children[l..r]
+            .iter()
+            .map(|(name, id)| DirEntry {
+                name: name.clone(),
+                attr: inner.items[id].1.clone(),
+            })
+            .collect())
+    }
+
+    /// List a directory, or items shared with the user for the virtual directory.
+    ///
+    /// Return the items with local ids, and mock deleted items for children gone since the last
+    /// listing, for `FilePool::sync_items` to invalidate outdated caches.
+    pub async fn list(&self, parent_id: &ItemId, onedrive: &OneDrive) -> Result<Vec<DriveItem>> {
+        let items = if self.dir_id() == Some(parent_id) {
+            Self::fetch_shared_with_me(onedrive).await?
+        } else {
+            let (drive_id, remote_id) = remote_of(parent_id).ok_or(Error::NotFound)?;
+            if !self.get_attr(parent_id)?.is_directory {
+                return Err(Error::NotADirectory);
+            }
+            let drive = remote_drive(onedrive, drive_id.clone());
+            let opt = CollectionOption::new()
+                .select(InodePool::SYNC_SELECT_FIELDS)
+                .select(TAG_FIELDS);
+            drive
+                .list_children_with_option(ItemLocation::from_id(&remote_id), opt)
+                .await?
+                .ok_or(Error::NotFound)?
+                .fetch_all(&drive)
+                .await?
+                .into_iter()
+                .map(|item| (drive_id.clone(), item))
+                .collect()
+        };
+
+        let mut ret = Vec::with_capacity(items.len());
+        let mut children = Vec::<(String, ItemId)>::with_capacity(items.len());
+        let mut inner = self.inner.lock().unwrap();
+        for (drive_id, mut item) in items {
+            let remote_id = match item.id.clone() {
+                Some(id) => id,
+                None => {
+                    log::warn!("Skip shared item without id: {:?}", item);
+                    continue;
+                }
+            };
+            let id = local_id(&drive_id, &remote_id);
+            item.id = Some(id.clone());
+            if ItemKind::of(&item).is_none() {
+                log::debug!("Skip unsupported shared item {:?}", id);
+                continue;
+            }
+            let attr = match InodeAttr::parse_item(&item) {
+                Ok(attr) => attr,
+                Err(err) => {
+                    log::warn!("Skip unsupported shared item {:?}: {:#}", id, err);
+                    continue;
+                }
+            };
+            let name = match item.name.clone() {
+                Some(name) => name,
+                None => {
+                    log::warn!("Skip shared item {:?} without name", id);
+                    continue;
+                }
+            };
+            // Items shared by different users may have the same name.
+            let child_name = if children.iter().any(|(other, _)| *other == name) {
+                suffixed_name(&name, &remote_id)
+            } else {
+                name.clone()
+            };
+            children.push((child_name, id.clone()));
+            inner.items.insert(id, (name, attr));
+            ret.push(item);
+        }
+        let old = inner.children.insert(parent_id.clone(), children);
+        for (_, old_id) in old.into_iter().flatten() {
+            if ret.iter().all(|item| item.id.as_ref() != Some(&old_id)) {
+                log::debug!("Shared item {:?} is gone", old_id);
+                let mut mock_item = DriveItem::default();
+                mock_item.id = Some(old_id);
+                mock_item.deleted = Some(Box::new(serde_json::Value::Null));
+                ret.push(mock_item);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Fetch the latest attribute of a shared item, returning it like `list`.
+    pub async fn refresh(&self, item_id: &ItemId, onedrive: &OneDrive) -> Result<DriveItem> {
+        let (drive_id, remote_id) = remote_of(item_id).ok_or(Error::NotFound)?;
+        let opt = ObjectOption::new()
+            .select(InodePool::SYNC_SELECT_FIELDS)
+            .select(TAG_FIELDS);
+        let mut item = remote_drive(onedrive, drive_id)
+            .get_item_with_option(ItemLocation::from_id(&remote_id), opt)
+            .await?
+            .ok_or(Error::NotFound)?;
+        item.id = Some(item_id.clone());
+        let (_, attr) = InodeAttr::parse_response(&item)?;
+        if let Some((_, old_attr)) = self.inner.lock().unwrap().items.get_mut(item_id) {
+            *old_attr = attr;
+        }
+        Ok(item)
+    }
+
+    /// Fetch items shared with the user, with their owning drive ids.
+    /// They are resolved to the `remoteItem` facet, which is the actual item in the owning drive.
+    async fn fetch_shared_with_me(onedrive: &OneDrive) -> Result<Vec<(DriveId, DriveItem)>> {
+        #[derive(Deserialize)]
+        struct Resp {
+            value: Vec<DriveItem>,
+            #[serde(rename = "@odata.nextLink")]
+            next_link: Option<String>,
+        }
+
+        let mut shared = Vec::new();
+        let mut url = "https://graph.microsoft.com/v1.0/me/drive/sharedWithMe".to_owned();
+        loop {
+            let resp: Resp = onedrive
+                .client()
+                .get(&url)
+                .bearer_auth(onedrive.access_token())
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            shared.extend(resp.value);
+            match resp.next_link {
+                Some(next_link) => url = next_link,
+                None => break,
+            }
+        }
+
+        let mut ret = Vec::with_capacity(shared.len());
+        for item in shared {
+            let mut remote: DriveItem = match item.remote_item {
+                Some(remote) => serde_json::from_value(*remote)?,
+                None => {
+                    log::debug!("Skip shared item {:?} without remoteItem", item.id);
+                    continue;
+                }
+            };
+            let drive_id = match remote_drive_id(&remote) {
+                Some(drive_id) => drive_id,
+                None => {
+                    log::warn!("Skip shared item {:?} without owning drive", item.id);
+                    continue;
+                }
+            };
+            // `remoteItem` may come without tags required by `InodeAttr` of files.
+            if remote.folder.is_none() && content_tag(&remote).is_none() {
+                let remote_id = match remote.id.clone() {
+                    Some(id) => id,
+                    None => {
+                        log::warn!("Skip shared item {:?} without remote id", item.id);
+                        continue;
+                    }
+                };
+                let opt = ObjectOption::new()
+                    .select(InodePool::SYNC_SELECT_FIELDS)
+                    .select(TAG_FIELDS);
+                let ret = remote_drive(onedrive, drive_id.clone())
+                    .get_item_with_option(ItemLocation::from_id(&remote_id), opt)
+                    .await;
+                remote = match ret {
+                    Ok(Some(remote)) => remote,
+                    // Not modified, which should not happen without `if_none_match`.
+                    Ok(None) => continue,
+                    // The share may be revoked, or the item is deleted by its owner.
+                    Err(err)
+                        if matches!(
+                            err.status_code(),
+                            Some(StatusCode::FORBIDDEN | StatusCode::NOT_FOUND)
+                        ) =>
+                    {
+                        log::warn!("Skip inaccessible shared item {:?}: {}", remote_id, err);
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+            }
+            if remote.name.is_none() {
+                remote.name = item.name;
+            }
+            ret.push((drive_id, remote));
+        }
+        Ok(ret)
+    }
+    /// Resolve shortcuts to shared items in changes of the mounted drive, which has only
+    /// `remoteItem` facet and is otherwise skipped. They are rewritten to the items they point to,
+    /// with local ids, and the names and parents of the shortcuts. Deletions of them are rewritten
+    /// to the local ids too.
+    ///
+    /// Their attributes are recorded, so that they're addressed like other shared items.
+    pub fn resolve_shortcuts(&self, items: &mut [DriveItem]) {
+        if self.dir_id.is_none() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        for item in items {
+            let id = match &item.id {
+                Some(id) => id,
+                None => continue,
+            };
+            if item.deleted.is_some() {
+                if let Some(local) = inner.shortcuts.remove(id) {
+                    let is_directory = inner
+                        .items
+                        .get(&local)
+                        .is_some_and(|(_, attr)| attr.is_directory);
+                    log::debug!("Shortcut {:?} to {:?} is deleted", id, local);
+                    let mut mock_item = DriveItem::default();
+                    mock_item.id = Some(local);
+                    mock_item.deleted = item.deleted.take();
+                    let facet = Some(Box::new(serde_json::json!({})));
+                    if is_directory {
+                        mock_item.folder = facet;
+                    } else {
+                        mock_item.file = facet;
+                    }
+                    *item = mock_item;
+                }
+                continue;
+            }
+            if ItemKind::of(item).is_some() {
+                continue;
+            }
+            let mut remote: DriveItem = match item
+                .remote_item
+                .as_ref()
+                .map(|remote| serde_json::from_value((**remote).clone()))
+            {
+                Some(Ok(remote)) => remote,
+                Some(Err(err)) => {
+                    log::warn!("Skip shortcut {:?} with invalid remoteItem: {}", id, err);
+                    continue;
+                }
+                None => continue,
+            };
+            let (drive_id, remote_id) = match (remote_drive_id(&remote), remote.id.clone()) {
+                (Some(drive_id), Some(remote_id)) => (drive_id, remote_id),
+                _ => {
+                    log::warn!("Skip shortcut {:?} without remote drive or id", id);
+                    continue;
+                }
+            };
+            let name = match &item.name {
+                Some(name) => name.clone(),
+                None => {
+                    log::warn!("Skip shortcut {:?} without name", id);
+                    continue;
+                }
+            };
+            let local = local_id(&drive_id, &remote_id);
+            remote.id = Some(local.clone());
+            remote.name = Some(name.clone());
+            remote.parent_reference = item.parent_reference.take();
+            if remote.file_system_info.is_none() {
+                remote.file_system_info = item.file_system_info.take();
+            }
+            let attr = match InodeAttr::parse_item(&remote) {
+                Ok(attr) => attr,
+                Err(err) => {
+                    log::warn!("Skip unsupported shortcut {:?}: {:#}", id, err);
+                    continue;
+                }
+            };
+            log::debug!("Resolve shortcut {:?} to {:?}", id, local);
+            inner.shortcuts.insert(id.clone(), local.clone());
+            inner.items.insert(local, (name, attr));
+            *item = remote;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn shared() -> SharedItems {
+        SharedItems::new(Config { enable: true })
+    }
+
+    fn shortcut() -> DriveItem {
+        serde_json::from_value(json!({
+            "id": "SHORTCUT",
+            "name": "Shortcut",
+            "parentReference": { "id": "ROOT" },
+            "fileSystemInfo": {
+                "createdDateTime": "2020-01-01T00:00:00Z",
+                "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+            },
+            "remoteItem": {
+                "id": "REMOTE",
+                "name": "Original",
+                "folder": { "childCount": 0 },
+                "size": 0,
+                "parentReference": { "driveId": "DRIVE" },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn resolve_shortcut() {
+        let shared = shared();
+        let local = local_id(&DriveId("DRIVE".to_owned()), &ItemId("REMOTE".to_owned()));
+
+        let mut items = [shortcut()];
+        shared.resolve_shortcuts(&mut items);
+        assert_eq!(items[0].id.as_ref(), Some(&local));
+        assert_eq!(items[0].name.as_deref(), Some("Shortcut"));
+        assert_eq!(
+            items[0].parent_reference.as_ref().unwrap()["id"],
+            json!("ROOT"),
+        );
+        assert!(shared.contains(&local));
+        assert!(shared.get_attr(&local).unwrap().is_directory);
+        assert_eq!(shared.get_name(&local).as_deref(), Some("Shortcut"));
+
+        let mut items = [serde_json::from_value(json!({
+            "id": "SHORTCUT",
+            "deleted": {},
+        }))
+        .unwrap()];
+        shared.resolve_shortcuts(&mut items);
+        assert_eq!(items[0].id.as_ref(), Some(&local));
+        assert!(items[0].deleted.is_some());
+        assert!(items[0].folder.is_some());
+    }
+
+    #[test]
+    fn skip_broken_shortcut() {
+        let shared = shared();
+        let mut item = shortcut();
+        item.remote_item.as_mut().unwrap()["parentReference"] = json!({});
+        let mut items = [item];
+        shared.resolve_shortcuts(&mut items);
+        assert_eq!(items[0].id.as_ref().unwrap().as_str(), "SHORTCUT");
+
+        let disabled = SharedItems::new(Config { enable: false });
+        let mut items = [shortcut()];
+        disabled.resolve_shortcuts(&mut items);
+        assert_eq!(items[0].id.as_ref().unwrap().as_str(), "SHORTCUT");
+    }
+}
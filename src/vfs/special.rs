@@ -0,0 +1,94 @@
+//! Virtual `.special` directory exposing OneDrive special folders by their well-known names.
+use crate::vfs::error::Result;
+use onedrive_api::{resource::DriveId, ItemId, OneDrive};
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+/// Name of the virtual directory under root.
+pub const DIR_NAME: &str = ".special";
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    enable: bool,
+    names: Vec<String>,
+}
+
+pub struct SpecialFolders {
+    /// Item id of the virtual directory. Real item ids never start with `.`.
+    dir_id: Option<ItemId>,
+    // Special folder name -> Item id.
+    folders: Vec<(String, ItemId)>,
+}
+
+impl SpecialFolders {
+    pub async fn new(onedrive: &OneDrive, drive_id: &DriveId, config: Config) -> Self {
+        if !config.enable {
+            return Self {
+                dir_id: None,
+                folders: Vec::new(),
+            };
+        }
+
+        let mut folders = Vec::with_capacity(config.names.len());
+        for name in config.names {
+            match Self::fetch_id(onedrive, drive_id, &name).await {
+                Ok(Some(id)) => {
+                    log::debug!("Special folder {:?}: {:?}", name, id);
+                    folders.push((name, id));
+                }
+                // Not all accounts have all special folders, eg. `cameraroll` on OneDrive for Business.
+                Ok(None) => log::info!("Special folder {:?} is not available", name),
+                Err(err) => log::warn!("Failed to fetch special folder {:?}: {}", name, err),
+            }
+        }
+        Self {
+            dir_id: Some(ItemId(DIR_NAME.to_owned())),
+            folders,
+        }
+    }
+
+    async fn fetch_id(
+        onedrive: &OneDrive,
+        drive_id: &DriveId,
+        name: &str,
+    ) -> Result<Option<ItemId>> {
+        #[derive(Deserialize)]
+        struct Resp {
+            id: ItemId,
+        }
+
+        let resp = onedrive
+            .client()
+            .get(format!(
+                "https://graph.microsoft.com/v1.0/drives/{}/special/{}",
+                drive_id.as_str(),
+                name,
+            ))
+            .query(&[("$select", "id")])
+            .bearer_auth(onedrive.access_token())
+            .send()
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp: Resp = resp.error_for_status()?.json().await?;
+        Ok(Some(resp.id))
+    }
+
+    /// Item id of the virtual directory, or `None` if disabled.
+    pub fn dir_id(&self) -> Option<&ItemId> {
+        self.dir_id.as_ref()
+    }
+
+    /// Resolve a special folder name to its item id.
+    pub fn resolve(&self, name: &str) -> Option<&ItemId> {
+        self.folders
+            .iter()
+            .find(|(folder_name, _)| folder_name == name)
+            .map(|(_, id)| id)
+    }
+
+    pub fn folders(&self) -> &[(String, ItemId)] {
+        &self.folders
+    }
+}
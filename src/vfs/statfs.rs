@@ -12,6 +12,7 @@ use std::{
 
 pub struct Statfs {
     cache: Arc<SyncMutex<StatfsData>>,
+    min_free_space: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,6 +20,7 @@ pub struct Config {
     enable_auto_refresh: bool,
     #[serde(deserialize_with = "de_duration_sec")]
     refresh_period: Duration,
+    min_free_space: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -29,7 +31,7 @@ pub struct StatfsData {
 
 impl Statfs {
     pub async fn new(onedrive: ManagedOnedrive, config: Config) -> Result<Self> {
-        let data = Self::statfs_raw(&*onedrive.get().await).await?;
+        let data = Self::statfs_raw(&*onedrive.get().await?).await?;
         let cache = Arc::new(SyncMutex::new(data));
         if config.enable_auto_refresh {
             tokio::spawn(Self::refresh_thread(
@@ -38,7 +40,10 @@ impl Statfs {
                 onedrive,
             ));
         }
-        Ok(Self { cache })
+        Ok(Self {
+            cache,
+            min_free_space: config.min_free_space,
+        })
     }
 
     async fn refresh_thread(
@@ -54,7 +59,11 @@ impl Statfs {
                 Some(arc) => arc,
                 None => return,
             };
-            let data = match Self::statfs_raw(&*onedrive.get().await).await {
+            let ret = match onedrive.get().await {
+                Ok(onedrive) => Self::statfs_raw(&onedrive).await,
+                Err(err) => Err(err.into()),
+            };
+            let data = match ret {
                 Ok(data) => data,
                 Err(err) => {
                     log::error!("Failed to query quota: {}", err);
@@ -70,6 +79,18 @@ impl Statfs {
         *self.cache.lock().unwrap()
     }
 
+    /// Reject writing `len` more bytes if the free space would drop below `min_free_space`,
+    /// according to the cached quota.
+    pub fn check_space(&self, len: u64) -> Result<()> {
+        if self.min_free_space != 0
+            && len != 0
+            && self.statfs().free < self.min_free_space.saturating_add(len)
+        {
+            return Err(Error::NoSpace);
+        }
+        Ok(())
+    }
+
     async fn statfs_raw(onedrive: &OneDrive) -> Result<StatfsData> {
         use onedrive_api::{option::ObjectOption, resource::DriveField};
 
@@ -91,3 +112,27 @@ impl Statfs {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_space_keeps_min_free_space() {
+        let statfs = |free, min_free_space| Statfs {
+            cache: Arc::new(SyncMutex::new(StatfsData { total: 100, free })),
+            min_free_space,
+        };
+
+        let full = statfs(30, 20);
+        full.check_space(10).unwrap();
+        assert!(matches!(full.check_space(11), Err(Error::NoSpace)));
+        assert!(matches!(full.check_space(u64::MAX), Err(Error::NoSpace)));
+        // Nothing to write.
+        full.check_space(0).unwrap();
+        statfs(10, 20).check_space(0).unwrap();
+        assert!(matches!(statfs(10, 20).check_space(1), Err(Error::NoSpace)));
+        // Disabled.
+        statfs(0, 0).check_space(u64::MAX).unwrap();
+    }
+}
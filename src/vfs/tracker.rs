@@ -1,8 +1,11 @@
-use crate::{config::de_duration_sec, login::ManagedOnedrive, vfs::UpdateEvent};
+use crate::{
+    config::de_duration_sec,
+    login::{ManagedOnedrive, TokenExpired},
+    vfs::UpdateEvent,
+};
 use onedrive_api::{
     option::CollectionOption,
     resource::{DriveItem, DriveItemField},
-    OneDrive,
 };
 use serde::Deserialize;
 use std::{
@@ -11,7 +14,7 @@ use std::{
     sync::{Arc, Mutex as SyncMutex, Weak},
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -19,10 +22,24 @@ pub struct Config {
     #[serde(deserialize_with = "de_duration_sec")]
     period: Duration,
     fetch_page_size: NonZeroUsize,
+    page_max_retry: usize,
+    offline_detection: bool,
+}
+
+/// Delay before retrying to fetch a page of changes.
+const PAGE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+enum FetchError {
+    #[error(transparent)]
+    Api(#[from] onedrive_api::Error),
+    #[error(transparent)]
+    TokenExpired(#[from] TokenExpired),
 }
 
 pub struct Tracker {
     last_sync_time: Option<Arc<SyncMutex<Instant>>>,
+    offline_rx: watch::Receiver<bool>,
     config: Config,
 }
 
@@ -41,21 +58,30 @@ impl Tracker {
             }
         };
 
+        let (offline_tx, offline_rx) = watch::channel(false);
         tokio::spawn(tracking_thread(
             None,
             event_tx,
             select_fields,
             onedrive,
             weak,
+            offline_tx,
             config.clone(),
         ));
 
         Ok(Self {
             last_sync_time,
+            offline_rx,
             config,
         })
     }
 
+    /// Whether the network is found unreachable by the last fetch of changes.
+    /// It's always `false` if `offline_detection` is disabled.
+    pub fn subscribe_offline(&self) -> watch::Receiver<bool> {
+        self.offline_rx.clone()
+    }
+
     pub fn time_to_next_sync(&self) -> Option<Duration> {
         let passed = self.last_sync_time.as_ref()?.lock().unwrap().elapsed();
         // Zero if time exceeded.
@@ -69,17 +95,30 @@ async fn tracking_thread(
     select_fields: Vec<DriveItemField>,
     onedrive: ManagedOnedrive,
     last_sync_time: Weak<SyncMutex<Instant>>,
+    offline_tx: watch::Sender<bool>,
     config: Config,
 ) {
     log::debug!("Tracking thread started");
 
+    let set_offline = |offline: bool| {
+        if offline_tx.send_replace(offline) != offline {
+            match offline {
+                true => log::warn!("Network is unreachable, serving cached files only"),
+                false => log::info!("Network is reachable again"),
+            }
+        }
+    };
+
     loop {
         // Do the first fetch immediately.
         let start_time = Instant::now();
 
-        let onedrive = onedrive.get().await;
-
-        match fetch_changes(&mut delta_url, &select_fields, &onedrive, &config).await {
+        let ret = fetch_changes(&mut delta_url, &select_fields, &onedrive, &config).await;
+        if config.offline_detection {
+            // Errors without a status code are failed requests, rather than error responses.
+            set_offline(matches!(&ret, Err(FetchError::Api(err)) if err.status_code().is_none()));
+        }
+        match ret {
             Ok(Some(changes)) => {
                 if event_tx
                     .send(UpdateEvent::BatchUpdate(changes))
@@ -93,6 +132,7 @@ async fn tracking_thread(
             Ok(None) => continue,
             Err(err) => {
                 log::error!("Failed to fetch changes: {}", err);
+                tokio::time::sleep(config.period).await;
                 continue;
             }
         }
@@ -108,37 +148,41 @@ async fn tracking_thread(
 }
 
 /// Fetch initial or delta changes with optional progress.
+/// The client is locked only during each request, so that relogin is not blocked by retries.
 ///
 /// Returns `Some(changes)` or `None` when delta url is gone.
 async fn fetch_changes(
     delta_url: &mut Option<String>,
     select_fields: &[DriveItemField],
-    onedrive: &OneDrive,
+    onedrive: &ManagedOnedrive,
     config: &Config,
-) -> onedrive_api::Result<Option<Vec<DriveItem>>> {
-    let mut fetcher = match delta_url {
-        // First fetch.
-        None => {
-            log::info!("Fetching metadata of the whole tree...");
-            let opt = CollectionOption::new()
-                .page_size(config.fetch_page_size.into())
-                .select(&[DriveItemField::id])
-                .select(select_fields);
-            onedrive
-                .track_root_changes_from_initial_with_option(opt)
-                .await?
-        }
-        // Delta fetch.
-        Some(url) => {
-            log::debug!("Checking remote changes");
-            match onedrive.track_root_changes_from_delta_url(url).await {
-                Ok(fetcher) => fetcher,
-                Err(err) if err.status_code().map_or(false, |st| st.is_client_error()) => {
-                    log::info!("Re-sync required. Delta URL is gone: {}", err);
-                    *delta_url = None;
-                    return Ok(None);
+) -> Result<Option<Vec<DriveItem>>, FetchError> {
+    let mut fetcher = {
+        let onedrive = onedrive.get().await?;
+        match delta_url {
+            // First fetch.
+            None => {
+                log::info!("Fetching metadata of the whole tree...");
+                let opt = CollectionOption::new()
+                    .page_size(config.fetch_page_size.into())
+                    .select(&[DriveItemField::id])
+                    .select(select_fields);
+                onedrive
+                    .track_root_changes_from_initial_with_option(opt)
+                    .await?
+            }
+            // Delta fetch.
+            Some(url) => {
+                log::debug!("Checking remote changes");
+                match onedrive.track_root_changes_from_delta_url(url).await {
+                    Ok(fetcher) => fetcher,
+                    Err(err) if err.status_code().is_some_and(|st| st.is_client_error()) => {
+                        log::info!("Re-sync required. Delta URL is gone: {}", err);
+                        *delta_url = None;
+                        return Ok(None);
+                    }
+                    Err(err) => return Err(err.into()),
                 }
-                Err(err) => return Err(err),
             }
         }
     };
@@ -147,7 +191,30 @@ async fn fetch_changes(
     let mut total_changes = 0usize;
     let mut ret = Vec::new();
     let mut seen_ids = HashSet::new();
-    while let Some(changes) = fetcher.fetch_next_page(onedrive).await? {
+    // Everything is applied only after all pages are fetched, since a partial delta may miss
+    // parents or moves of already fetched items. A failed page is retried without losing progress.
+    let mut tries = 0;
+    loop {
+        // Not locked during the retry delay.
+        let page_ret = fetcher.fetch_next_page(&*onedrive.get().await?).await;
+        let changes = match page_ret {
+            Ok(Some(changes)) => changes,
+            Ok(None) => break,
+            Err(err) if tries < config.page_max_retry => {
+                tries += 1;
+                log::error!(
+                    "Failed to fetch page {} of changes (try {}/{}): {}",
+                    page + 1,
+                    tries,
+                    config.page_max_retry,
+                    err,
+                );
+                tokio::time::sleep(PAGE_RETRY_DELAY).await;
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        tries = 0;
         total_changes += changes.len();
         page += 1;
 